@@ -0,0 +1,194 @@
+//! Conversation and prompt-prefix affinity.
+//!
+//! Agentic clients send dozens of sequential chat requests that each extend the same conversation,
+//! and code completion plugins send long prompts that share big prefixes.
+//! Ollama keeps the previous prompt in the KV cache, so sending the next request to the same server
+//! skips re-ingesting a prompt that may have taken a minute to build.
+//! We remember the last completed prompt of every server and prefer the server that has
+//! the longest beginning of the incoming one in its KV cache.
+//! Conversations are compared in Ollama's native chat shape (see `normalize`), so a conversation
+//! started through one API and continued through another still finds its server.
+
+use crate::normalize::{self, ReplyCollector};
+
+/// Cached prefix must be at least this many messages to count as a meaningful cache hit
+const MIN_SHARED_MESSAGES: usize = 3;
+/// Cached prefix must cover at least this percentage of the new request's messages
+const MIN_SHARED_PERCENT: usize = 40;
+
+/// What a request leaves in a server's KV cache.
+#[derive(Debug, Clone)]
+pub enum Prompt {
+    /// `/api/chat`, `/v1/chat/completions`, `/v1/messages`
+    Chat(Conversation),
+    /// `/api/generate`, `/v1/completions`
+    Completion(Completion),
+}
+
+/// Everything about a chat that must be equal for a server's KV cache to be reusable.
+#[derive(Debug, Clone)]
+pub struct Conversation {
+    model: String,
+    messages: Vec<serde_json::Value>,
+    tools: serde_json::Value,
+    num_ctx: serde_json::Value,
+}
+
+/// A raw completion prompt, and after the reply: prompt + response text.
+#[derive(Debug, Clone)]
+pub struct Completion {
+    model: String,
+    system: String,
+    raw: bool,
+    num_ctx: serde_json::Value,
+    text: String,
+    /// `/api/generate` requests may continue from the `context` returned by a previous one
+    context: serde_json::Value,
+}
+
+/// Parses what the request will put into the KV cache, whichever API it was sent through.
+pub fn parse_prompt(path: &str, json: &serde_json::Value) -> Option<Prompt> {
+    let model = crate::inventory::normalize_model_name(json.get("model")?.as_str()?);
+    let num_ctx = json.get("options").and_then(|options| options.get("num_ctx")).cloned().unwrap_or_default();
+    let text_field = |field: &str| json.get(field).and_then(|value| value.as_str()).unwrap_or_default().to_string();
+    match path {
+        "/api/generate" => Some(Prompt::Completion(Completion {
+            model,
+            system: text_field("system"),
+            raw: json.get("raw").and_then(|raw| raw.as_bool()).unwrap_or_default(),
+            num_ctx,
+            text: text_field("prompt"),
+            context: json.get("context").cloned().unwrap_or_default(),
+        })),
+        "/v1/completions" => {
+            let prompt = match json.get("prompt")? {
+                serde_json::Value::String(prompt) => prompt.clone(),
+                // A batch of prompts isn't one KV cache
+                serde_json::Value::Array(prompts) if prompts.len() == 1 => prompts[0].as_str()?.to_string(),
+                _ => return None,
+            };
+            Some(Prompt::Completion(Completion {
+                model,
+                system: String::new(),
+                raw: false,
+                num_ctx,
+                text: prompt,
+                context: serde_json::Value::Null,
+            }))
+        }
+        _ => {
+            let chat = normalize::normalize_request(path, json)?;
+            Some(Prompt::Chat(Conversation {
+                model,
+                messages: chat.messages,
+                tools: chat.tools,
+                num_ctx: chat.num_ctx,
+            }))
+        }
+    }
+}
+
+impl Prompt {
+    /// The model whose KV cache the prompt lands in
+    pub fn model(&self) -> &str {
+        match self {
+            Prompt::Chat(conversation) => &conversation.model,
+            Prompt::Completion(completion) => &completion.model,
+        }
+    }
+}
+
+/// How much of `incoming` a server that last served `cached` already has in its KV cache-
+/// messages for chats, characters for completions. Only comparable between servers for the same `incoming`.
+///
+/// 0 unless the shared part passes the meaningful cache hit threshold.
+pub fn shared_prefix(cached: &Prompt, incoming: &Prompt, min_shared_chars: usize) -> usize {
+    match (cached, incoming) {
+        (Prompt::Chat(cached), Prompt::Chat(incoming)) => shared_messages(cached, incoming),
+        (Prompt::Completion(cached), Prompt::Completion(incoming)) => {
+            let shared = shared_chars(cached, incoming);
+            if shared >= min_shared_chars { shared } else { 0 }
+        }
+        _ => 0,
+    }
+}
+
+/// Describes the result of `shared_prefix` for the logs
+pub fn describe_shared(prompt: &Prompt, shared: usize) -> String {
+    match prompt {
+        Prompt::Chat(_) => format!("{} message(s) of the conversation already cached", shared),
+        Prompt::Completion(_) => format!("{} character(s) of the prompt already cached", shared),
+    }
+}
+
+/// Number of messages, if `cached` is a strict prefix of `incoming`
+fn shared_messages(cached: &Conversation, incoming: &Conversation) -> usize {
+    let shared = cached.messages.len();
+    let is_strict_prefix = cached.model == incoming.model
+        && cached.tools == incoming.tools
+        && cached.num_ctx == incoming.num_ctx
+        && shared < incoming.messages.len()
+        && cached.messages.iter().zip(&incoming.messages).all(|(a, b)| a == b);
+    if is_strict_prefix && shared >= MIN_SHARED_MESSAGES && shared * 100 >= incoming.messages.len() * MIN_SHARED_PERCENT {
+        shared
+    } else {
+        0
+    }
+}
+
+/// Number of leading characters the two prompts have in common
+fn shared_chars(cached: &Completion, incoming: &Completion) -> usize {
+    if cached.model != incoming.model || cached.system != incoming.system || cached.raw != incoming.raw || cached.num_ctx != incoming.num_ctx {
+        return 0;
+    }
+    // Continuing from the context the server returned means continuing exactly what it has cached
+    if !incoming.context.is_null() && incoming.context == cached.context {
+        return cached.text.chars().count();
+    }
+    cached.text.chars().zip(incoming.text.chars())
+        .take_while(|(a, b)| a == b)
+        .count()
+}
+
+/// Collects the reply as the response streams by, so that the prompt can be remembered
+/// together with the reply once the reply is done.
+pub struct PendingPrompt {
+    request: Prompt,
+    reply: ReplyCollector,
+}
+
+impl PendingPrompt {
+    pub fn new(path: &str, request: Prompt, content_length: Option<u64>) -> Option<Self> {
+        Some(Self { request, reply: ReplyCollector::new(path, content_length)? })
+    }
+
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.reply.feed(bytes);
+    }
+
+    /// The response body ended
+    pub fn end(&mut self) {
+        self.reply.end();
+    }
+
+    /// Whether the final chunk of the reply has arrived
+    pub fn is_done(&self) -> bool {
+        self.reply.is_done()
+    }
+
+    /// The prompt as it now sits in the server's KV cache- the request plus the reply
+    pub fn finish(self) -> Prompt {
+        match self.request {
+            Prompt::Chat(mut conversation) => {
+                conversation.messages.push(self.reply.into_message());
+                Prompt::Chat(conversation)
+            }
+            Prompt::Completion(mut completion) => {
+                let (response, context) = self.reply.into_completion();
+                completion.text.push_str(&response);
+                completion.context = context;
+                Prompt::Completion(completion)
+            }
+        }
+    }
+}
@@ -0,0 +1,220 @@
+//! Keeping `ollama create` on the server that has its blobs.
+//!
+//! `ollama create` from a Modelfile first asks `HEAD /api/blobs/sha256:...` whether the server has each file,
+//! uploads the missing ones with `POST /api/blobs/sha256:...`, then sends `POST /api/create` referring to them by digest.
+//! All of these must reach the same server. The server a digest went to is remembered, and later requests
+//! naming that digest go to the same server- the create waiting for it if it's busy. A digest that went nowhere yet
+//! follows the client's earlier blobs, so that the files of one model don't end up spread over several servers.
+//!
+//! The blob requests themselves don't run a model, so like metadata requests they're sent without taking a slot
+//! and without the inference timeouts- uploading a model file of several gigabytes takes as long as it takes.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use hyper::{Body, Method, Request, Response, StatusCode};
+
+use crate::{proxy, retry, FailureRecord, SharedServerList};
+
+/// How long a digest stays pinned to its server after the last request naming it
+const PIN_TTL_SECS: u64 = 60 * 60;
+
+/// How long a client's new blobs follow its earlier ones after its last blob request.
+/// `ollama create` checks and uploads the files one after the other, then creates right away.
+const SESSION_TTL_SECS: u64 = 10 * 60;
+
+pub type SharedBlobPins = Arc<Mutex<BlobPins>>;
+
+struct Pin {
+    address: String,
+    last_used: Instant,
+}
+
+/// The server each recently uploaded blob went to, keyed by digest
+pub struct BlobPins {
+    pins: HashMap<String, Pin>,
+    /// The server each client's blobs went to lately, keyed by the client's address
+    sessions: HashMap<IpAddr, Pin>,
+}
+
+impl BlobPins {
+    pub fn new() -> Self {
+        BlobPins { pins: HashMap::new(), sessions: HashMap::new() }
+    }
+
+    /// The server the pinned digests went to, else the one the `client`'s blobs went to lately.
+    ///
+    /// Digests pinned to different servers are an error- no server has all of the blobs. They're forgotten,
+    /// so that running `ollama create` again uploads the missing ones next to the others.
+    pub fn lookup(&mut self, digests: &[String], client: IpAddr) -> Result<Option<String>, String> {
+        if digests.is_empty() {
+            return Ok(None);
+        }
+        self.forget_expired();
+        let now = Instant::now();
+        let mut addresses: Vec<String> = Vec::new();
+        for digest in digests {
+            if let Some(pin) = self.pins.get_mut(digest) {
+                pin.last_used = now;
+                if !addresses.contains(&pin.address) {
+                    addresses.push(pin.address.clone());
+                }
+            }
+        }
+        if addresses.len() > 1 {
+            let spread: Vec<String> = digests.iter()
+                .filter_map(|digest| self.pins.remove(digest).map(|pin| format!("{} on {}", digest, pin.address)))
+                .collect();
+            return Err(format!("blobs are spread over several servers: {}", spread.join(", ")));
+        }
+        if let Some(address) = addresses.pop() {
+            return Ok(Some(address));
+        }
+        Ok(self.sessions.get_mut(&client).map(|session| {
+            session.last_used = now;
+            session.address.clone()
+        }))
+    }
+
+    /// Remembers that the digests, and the `client`'s blobs for now, went to the server at `address`
+    pub fn pin(&mut self, digests: &[String], address: &str, client: IpAddr) {
+        if digests.is_empty() {
+            return;
+        }
+        self.forget_expired();
+        for digest in digests {
+            self.pins.insert(digest.clone(), Pin { address: address.to_string(), last_used: Instant::now() });
+        }
+        self.sessions.insert(client, Pin { address: address.to_string(), last_used: Instant::now() });
+    }
+
+    fn forget_expired(&mut self) {
+        self.pins.retain(|_, pin| pin.last_used.elapsed() < Duration::from_secs(PIN_TTL_SECS));
+        self.sessions.retain(|_, session| session.last_used.elapsed() < Duration::from_secs(SESSION_TTL_SECS));
+    }
+}
+
+/// `409 Conflict` for a request whose blobs are spread over several servers
+pub fn spread_response(remote_addr: SocketAddr, method: &Method, path: &str, error: &str) -> Response<Body> {
+    println!("🧩 Client {} sends {} {}, but its {}", remote_addr, method, path, error);
+    let error = serde_json::json!({ "error": error });
+    Response::builder()
+        .status(StatusCode::CONFLICT)
+        .header("Content-Type", "application/json; charset=utf-8")
+        .body(Body::from(error.to_string()))
+        .unwrap()
+}
+
+pub fn is_blob_request(method: &Method, path: &str) -> bool {
+    matches!(method, &Method::HEAD | &Method::POST) && path.starts_with("/api/blobs/")
+}
+
+/// Proxies a blob request to the server its digest is pinned to, or the client's other blobs went to.
+/// Otherwise it goes to the servers one by one until one of them answers, reliable and less busy ones first,
+/// and the digest gets pinned to it.
+pub async fn forward(
+    req: Request<Body>,
+    method: reqwest::Method,
+    servers: &SharedServerList,
+    blob_pins: &SharedBlobPins,
+    remote_addr: SocketAddr,
+    retry_body_limit: usize,
+) -> Response<Body> {
+    let (parts, body) = req.into_parts();
+    let mut request_body = match retry::read_body(body, retry_body_limit).await {
+        Ok(request_body) => request_body,
+        Err(e) => {
+            return Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::from(format!("Failed to read request body: {}", e)))
+                .unwrap();
+        }
+    };
+    let digests: Vec<String> = parts.uri.path().strip_prefix("/api/blobs/").and_then(parse_digest).into_iter().collect();
+    let pinned = match blob_pins.lock().unwrap().lookup(&digests, remote_addr.ip()) {
+        Ok(pinned) => pinned,
+        Err(e) => return spread_response(remote_addr, &parts.method, parts.uri.path(), &e),
+    };
+
+    let candidates: Vec<(String, String, reqwest::Client)> = {
+        let servers_lock = servers.lock().unwrap();
+        let mut candidates: Vec<_> = servers_lock.iter()
+            .filter(|(key, server)| match &pinned {
+                Some(pinned) => pinned == *key,
+                None => !server.state.reconfiguring,
+            })
+            .collect();
+        candidates.sort_by_key(|(_, server)| (!matches!(server.state.failure_record, FailureRecord::Reliable), server.state.busy_slots));
+        candidates.into_iter().map(|(key, server)| (key.clone(), server.name.clone(), server.client.clone())).collect()
+    };
+    if let Some(pinned) = &pinned {
+        println!("📌 Client {} sends {} {} to server {}, which has its blobs", remote_addr, parts.method, parts.uri.path(), pinned);
+    }
+
+    let upstream_headers = proxy::request_headers(&parts.headers, remote_addr);
+
+    let mut last_error = String::from("no server to send it to");
+    for (key, name, client) in candidates {
+        let mut request_builder = client.request(method.clone(), proxy::upstream_url(&key, &parts.uri))
+            .headers(upstream_headers.clone());
+        if let Some(reqwest_body) = request_body.next_attempt() {
+            request_builder = request_builder.body(reqwest_body);
+        }
+        match request_builder.send().await {
+            Ok(response) => {
+                blob_pins.lock().unwrap().pin(&digests, &key, remote_addr.ip());
+                let resp_builder = proxy::response_headers(Response::builder().status(u16::from(response.status())), response.headers());
+                return resp_builder.body(Body::wrap_stream(response.bytes_stream())).unwrap();
+            }
+            Err(e) if request_body.upload_failed() => {
+                println!("💔 Client {}'s request body broke off on its way to server {} ({}). Error: {}", remote_addr, key, name, e);
+                return Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .body(Body::from(format!("Failed to read request body: {}", e)))
+                    .unwrap();
+            }
+            Err(e) => {
+                println!("📭 Server {} ({}) didn't answer {} {} for client {}. Error: {}", key, name, parts.method, parts.uri.path(), remote_addr, e);
+                last_error = e.to_string();
+                // The body is gone with the failed attempt
+                if !request_body.can_retry() {
+                    break;
+                }
+            }
+        }
+    }
+
+    Response::builder()
+        .status(StatusCode::BAD_GATEWAY)
+        .body(Body::from(format!("Error connecting to Ollama server: {}", last_error)))
+        .unwrap()
+}
+
+/// The blob digests a `POST /api/create` body refers to (in `files`, `adapters` or an older `modelfile`).
+pub fn digests(method: &Method, path: &str, body: &retry::RequestBody) -> Vec<String> {
+    match (method, path, body) {
+        (&Method::POST, "/api/create", retry::RequestBody::Buffered(bytes)) => {
+            let text = String::from_utf8_lossy(bytes);
+            let mut digests: Vec<String> = Vec::new();
+            for (index, _) in text.match_indices("sha256") {
+                if let Some(digest) = parse_digest(&text[index..]) {
+                    if !digests.contains(&digest) {
+                        digests.push(digest);
+                    }
+                }
+            }
+            digests
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// The `sha256:HEX` digest at the start of `text`, also accepting the `sha256-HEX` form of blob file names
+fn parse_digest(text: &str) -> Option<String> {
+    let hex = text.strip_prefix("sha256:").or_else(|| text.strip_prefix("sha256-"))?;
+    let hex = hex.get(..64)?;
+    hex.bytes().all(|byte| byte.is_ascii_hexdigit())
+        .then(|| format!("sha256:{}", hex.to_ascii_lowercase()))
+}
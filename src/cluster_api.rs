@@ -0,0 +1,150 @@
+//! Endpoints that the load balancer answers by itself from the polled cluster state.
+//!
+//! Proxying these to a single server would only show that server's view of the cluster,
+//! and would needlessly mark it busy.
+
+use std::collections::HashSet;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hyper::{Body, Method, Response, StatusCode};
+use ordermap::OrderMap;
+
+use crate::inventory::normalize_model_name;
+use crate::{OllamaServer, SharedServerList};
+
+/// Returns the response for requests the load balancer serves itself, `None` for anything that must be proxied.
+pub fn respond(method: &Method, path: &str, servers: &SharedServerList) -> Option<Response<Body>> {
+    match (method, path) {
+        (&Method::GET, "/api/tags") | (&Method::HEAD, "/api/tags") => {
+            let servers_lock = servers.lock().unwrap();
+            Some(json_response(StatusCode::OK, tags(&servers_lock)))
+        }
+        (&Method::GET, "/api/ps") => {
+            let servers_lock = servers.lock().unwrap();
+            Some(json_response(StatusCode::OK, ps(&servers_lock)))
+        }
+        (&Method::GET, "/v1/models") => {
+            let servers_lock = servers.lock().unwrap();
+            Some(json_response(StatusCode::OK, v1_models(&servers_lock)))
+        }
+        (&Method::GET, path) if path.starts_with("/v1/models/") => {
+            let id = percent_decode(&path["/v1/models/".len()..]);
+            let servers_lock = servers.lock().unwrap();
+            Some(v1_model(&servers_lock, &id))
+        }
+        _ => None,
+    }
+}
+
+/// `GET /api/tags`: Union of the models installed on all servers, in CLI order.
+/// The same model (name and digest) installed on several servers is listed once.
+fn tags(servers: &OrderMap<String, OllamaServer>) -> serde_json::Value {
+    let mut seen = HashSet::new();
+    let models: Vec<serde_json::Value> = servers.values()
+        .filter_map(|server| server.installed_models.as_ref())
+        .flatten()
+        .filter(|model| seen.insert((model.name.as_str(), model.digest.as_str())))
+        .map(|model| model.entry.clone())
+        .collect();
+    serde_json::json!({ "models": models })
+}
+
+/// `GET /api/ps`: Models loaded on every server, in CLI order.
+/// Each entry gets an extra `server` field naming the server it's loaded on.
+fn ps(servers: &OrderMap<String, OllamaServer>) -> serde_json::Value {
+    let models: Vec<serde_json::Value> = servers.values()
+        .flat_map(|server| server.loaded_models.iter().flatten().map(move |model| {
+            let mut entry = model.entry.clone();
+            if let Some(fields) = entry.as_object_mut() {
+                fields.insert("server".to_string(), serde_json::Value::String(server.name.clone()));
+            }
+            entry
+        }))
+        .collect();
+    serde_json::json!({ "models": models })
+}
+
+/// `GET /v1/models`: OpenAI-style list of every model installed anywhere in the cluster.
+fn v1_models(servers: &OrderMap<String, OllamaServer>) -> serde_json::Value {
+    let created = unix_now();
+    let data: Vec<serde_json::Value> = hosting_servers(servers)
+        .iter()
+        .map(|(id, names)| v1_model_object(id, names, created))
+        .collect();
+    serde_json::json!({ "object": "list", "data": data })
+}
+
+/// `GET /v1/models/{id}`: One model, or an OpenAI-style 404 if no server has it.
+fn v1_model(servers: &OrderMap<String, OllamaServer>, id: &str) -> Response<Body> {
+    let wanted = normalize_model_name(id);
+    match hosting_servers(servers).get(&wanted) {
+        Some(names) => json_response(StatusCode::OK, v1_model_object(&wanted, names, unix_now())),
+        None => json_response(StatusCode::NOT_FOUND, serde_json::json!({
+            "error": {
+                "message": format!("The model '{}' does not exist", id),
+                "type": "invalid_request_error",
+                "param": null,
+                "code": "model_not_found"
+            }
+        })),
+    }
+}
+
+/// `owned_by` lists the servers hosting the model, so clients can tell where it's available.
+fn v1_model_object(id: &str, server_names: &[&str], created: u64) -> serde_json::Value {
+    serde_json::json!({
+        "id": id,
+        "object": "model",
+        "created": created,
+        "owned_by": server_names.join(", ")
+    })
+}
+
+/// Maps each installed model name to the names of the servers that have it, both in CLI order.
+fn hosting_servers(servers: &OrderMap<String, OllamaServer>) -> OrderMap<String, Vec<&str>> {
+    let mut hosts: OrderMap<String, Vec<&str>> = OrderMap::new();
+    for server in servers.values() {
+        for model in server.installed_models.iter().flatten() {
+            let names = hosts.entry(normalize_model_name(&model.name)).or_default();
+            if !names.contains(&server.name.as_str()) {
+                names.push(&server.name);
+            }
+        }
+    }
+    hosts
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Model names contain `:` and sometimes `/`, which clients may percent-encode in the URL path.
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn json_response(status: StatusCode, json: serde_json::Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json; charset=utf-8")
+        .body(Body::from(json.to_string()))
+        .unwrap()
+}
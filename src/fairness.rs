@@ -0,0 +1,48 @@
+//! Fair sharing of the servers between clients.
+//!
+//! An agent running in a loop can keep every server busy and lock everybody else out.
+//! With `--fairness`, each client may only have `--max-per-client` requests served at once- the rest
+//! of its requests wait in line- and freed servers go round-robin to the clients that have requests waiting.
+
+use std::fmt;
+use std::net::IpAddr;
+
+/// How to tell clients apart
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum ClientKey {
+    /// The address the request came from
+    RemoteAddress,
+    /// The first address in the `X-Forwarded-For` header, for clients behind a reverse proxy
+    ForwardedAddress,
+    /// The API key sent as `Authorization: Bearer` or `x-api-key`
+    ApiKey,
+}
+
+impl fmt::Display for ClientKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientKey::RemoteAddress => write!(f, "remote address"),
+            ClientKey::ForwardedAddress => write!(f, "forwarded address"),
+            ClientKey::ApiKey => write!(f, "API key"),
+        }
+    }
+}
+
+/// Identifies the client of a request. Requests without a forwarded address or API key
+/// are told apart by the address they came from.
+pub fn client_id(key: ClientKey, headers: &hyper::HeaderMap, remote_addr: IpAddr) -> String {
+    let by_address = || remote_addr.to_canonical().to_string();
+    match key {
+        ClientKey::RemoteAddress => by_address(),
+        ClientKey::ForwardedAddress => headers.get("x-forwarded-for")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(',').next())
+            .map(str::trim)
+            .filter(|address| !address.is_empty())
+            .map(String::from)
+            .unwrap_or_else(by_address),
+        ClientKey::ApiKey => crate::priority::api_key(headers)
+            .map(|key| format!("key {}", key))
+            .unwrap_or_else(by_address),
+    }
+}
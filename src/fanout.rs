@@ -0,0 +1,186 @@
+//! Model management on several servers at once.
+//!
+//! Keeping the servers' model sets in sync used to mean running `ollama pull` on every box.
+//! `POST /api/pull`, `DELETE /api/delete`, `POST /api/copy` and `POST /api/create` that opt in- with an
+//! `X-LB-Servers: all` or `X-LB-Servers: NAME,NAME` header, or by carrying the `--admin-key`- run on all
+//! the selected servers in parallel. Their progress streams are merged into one NDJSON stream where every line
+//! is tagged with the server's name, followed by a summary of which servers succeeded.
+//! These don't take an inference slot, Ollama handles them alongside generation.
+
+use futures_util::stream::{self, StreamExt};
+use hyper::body::Bytes;
+use hyper::{Body, HeaderMap, Method, Request, Response, StatusCode};
+use tokio::sync::mpsc;
+
+use crate::{priority, proxy, SharedServerList};
+
+/// Request header that picks the servers to run a model management request on: `all` or a list of server names
+pub const SERVERS_HEADER: &str = "x-lb-servers";
+
+/// A server to run a model management request on
+pub struct Target {
+    address: String,
+    name: String,
+    client: reqwest::Client,
+}
+
+pub fn is_management_request(method: &Method, path: &str) -> bool {
+    matches!(
+        (method, path),
+        (&Method::POST, "/api/pull") | (&Method::DELETE, "/api/delete") | (&Method::POST, "/api/copy") | (&Method::POST, "/api/create")
+    )
+}
+
+/// The servers to run a model management request on.
+/// `Ok(None)` if the request didn't opt in, and goes to a single server like any other request.
+/// `Err` with the status and message to reject the request with.
+pub fn targets(headers: &HeaderMap, servers: &SharedServerList, admin_key: Option<&str>) -> Result<Option<Vec<Target>>, (StatusCode, String)> {
+    let selection = headers.get(SERVERS_HEADER).map(|value| value.to_str().unwrap_or_default().trim());
+    let is_admin = admin_key.is_some_and(|admin_key| priority::api_key(headers) == Some(admin_key));
+    if selection.is_none() && !is_admin {
+        return Ok(None);
+    }
+    // With an admin key set, only admins may change the models of several servers
+    if admin_key.is_some() && !is_admin {
+        return Err((StatusCode::FORBIDDEN, format!("Running on several servers ({} header) takes the admin key", SERVERS_HEADER)));
+    }
+
+    let servers_lock = servers.lock().unwrap();
+    let all = || servers_lock.iter().map(|(address, server)| Target { address: address.clone(), name: server.name.clone(), client: server.client.clone() }).collect::<Vec<_>>();
+    match selection {
+        None => Ok(Some(all())),
+        Some(selection) if selection.eq_ignore_ascii_case("all") => Ok(Some(all())),
+        Some(selection) => {
+            let mut targets = Vec::new();
+            for name in selection.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+                match servers_lock.iter().find(|(_, server)| server.name == name) {
+                    Some((address, server)) => {
+                        if !targets.iter().any(|target: &Target| &target.address == address) {
+                            targets.push(Target { address: address.clone(), name: server.name.clone(), client: server.client.clone() });
+                        }
+                    }
+                    None => {
+                        let known: Vec<&str> = servers_lock.values().map(|server| server.name.as_str()).collect();
+                        return Err((StatusCode::BAD_REQUEST, format!("Unknown server \"{}\" in {} header. Servers: {}", name, SERVERS_HEADER, known.join(", "))));
+                    }
+                }
+            }
+            if targets.is_empty() {
+                return Err((StatusCode::BAD_REQUEST, format!("No servers given in {} header. Use all or NAME,NAME", SERVERS_HEADER)));
+            }
+            Ok(Some(targets))
+        }
+    }
+}
+
+/// Runs the request on every target in parallel, streaming their progress as one NDJSON stream
+pub async fn forward(req: Request<Body>, method: reqwest::Method, targets: Vec<Target>, remote_addr: std::net::SocketAddr) -> Response<Body> {
+    let (parts, body) = req.into_parts();
+    let body = match hyper::body::to_bytes(body).await {
+        Ok(body) => body,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, format!("Failed to read request body: {}", e)),
+    };
+    let names: Vec<&str> = targets.iter().map(|target| target.name.as_str()).collect();
+    println!("📦 Client {} runs {} {} on {} server(s): {}", remote_addr, parts.method, parts.uri.path(), targets.len(), names.join(", "));
+
+    let path = parts.uri.path().to_string();
+    let headers = proxy::request_headers(&parts.headers, remote_addr);
+
+    // Spawned so that the servers finish what they were told even if the client disconnects
+    let (sender, receiver) = mpsc::unbounded_channel::<serde_json::Value>();
+    tokio::spawn(async move {
+        let runs = targets.iter().map(|target| {
+            // Pulls can take hours, so unlike inference there's no wait limit
+            let request_builder = target.client.request(method.clone(), proxy::upstream_url(&target.address, &parts.uri))
+                .headers(headers.clone());
+            run_on_server(request_builder.body(body.clone()), &target.name, sender.clone())
+        });
+        let outcomes = futures_util::future::join_all(runs).await;
+
+        let mut summary = Vec::new();
+        for (Target { address, name, .. }, outcome) in targets.iter().zip(outcomes) {
+            match outcome {
+                Ok(()) => {
+                    println!("📦✅ {} {} succeeded on server {} ({})", parts.method, path, address, name);
+                    summary.push(serde_json::json!({ "server": name, "success": true }));
+                }
+                Err(e) => {
+                    println!("📦⛔ {} {} failed on server {} ({}). Error: {}", parts.method, path, address, name, e);
+                    summary.push(serde_json::json!({ "server": name, "success": false, "error": e }));
+                }
+            }
+        }
+        let _ = sender.send(serde_json::json!({ "status": "summary", "servers": summary }));
+    });
+
+    let lines = stream::unfold(receiver, |mut receiver| async move {
+        let line = receiver.recv().await?;
+        Some((Ok::<_, std::io::Error>(Bytes::from(format!("{}\n", line))), receiver))
+    });
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/x-ndjson")
+        .body(Body::wrap_stream(lines))
+        .unwrap()
+}
+
+/// Sends the request to one server and passes on its progress lines, tagged with the server's name.
+/// Fails if the server can't be reached, answers with an error status or reports an error line.
+async fn run_on_server(request_builder: reqwest::RequestBuilder, name: &str, sender: mpsc::UnboundedSender<serde_json::Value>) -> Result<(), String> {
+    let tagged = |line: &[u8]| {
+        let mut json = match serde_json::from_slice::<serde_json::Value>(line) {
+            Ok(json @ serde_json::Value::Object(_)) => json,
+            _ => serde_json::json!({ "status": String::from_utf8_lossy(line).trim() }),
+        };
+        json["server"] = serde_json::Value::String(name.to_string());
+        json
+    };
+
+    let response = request_builder.send().await.map_err(|e| e.to_string())?;
+    let status = response.status();
+    let mut chunks = response.bytes_stream();
+    let mut pending = Vec::new();
+    let mut error = None;
+    let mut forwarded_any = false;
+    let mut forward_line = |line: &[u8]| {
+        if line.iter().all(u8::is_ascii_whitespace) {
+            return;
+        }
+        forwarded_any = true;
+        let json = tagged(line);
+        if let Some(message) = json.get("error").and_then(|message| message.as_str()) {
+            error = Some(message.to_string());
+        }
+        let _ = sender.send(json);
+    };
+    while let Some(chunk) = chunks.next().await {
+        let chunk = chunk.map_err(|e| e.to_string())?;
+        pending.extend_from_slice(&chunk);
+        while let Some(end) = pending.iter().position(|&byte| byte == b'\n') {
+            let line: Vec<u8> = pending.drain(..=end).collect();
+            forward_line(&line);
+        }
+    }
+    forward_line(&pending);
+
+    if let Some(error) = error {
+        return Err(error);
+    }
+    if !status.is_success() {
+        return Err(format!("status {}", status));
+    }
+    // `DELETE /api/delete` and `POST /api/copy` answer with an empty body
+    if !forwarded_any {
+        let _ = sender.send(serde_json::json!({ "status": "success", "server": name }));
+    }
+    Ok(())
+}
+
+/// An Ollama-style JSON error
+pub fn error_response(status: StatusCode, message: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json; charset=utf-8")
+        .body(Body::from(serde_json::json!({ "error": message }).to_string()))
+        .unwrap()
+}
@@ -0,0 +1,55 @@
+//! Recognizing inference requests and reading which model they ask for.
+
+use hyper::body::{Body, Bytes, HttpBody};
+use hyper::Method;
+
+/// Endpoints whose JSON body names the model (`"model": "..."`) that must serve the request.
+const INFERENCE_PATHS: &[&str] = &[
+    "/api/chat",
+    "/api/generate",
+    "/api/embed",
+    "/api/embeddings",
+    "/v1/chat/completions",
+    "/v1/completions",
+    "/v1/embeddings",
+    "/v1/messages",
+    "/v1/responses",
+];
+
+pub fn is_inference_request(method: &Method, path: &str) -> bool {
+    method == Method::POST && INFERENCE_PATHS.contains(&path)
+}
+
+/// Returns the `model` field of a JSON request body.
+///
+/// `None` if the body has no model- we then let the Ollama server
+/// produce the appropriate error instead of guessing.
+pub fn extract_model(json: &serde_json::Value) -> Option<String> {
+    json.get("model")?.as_str().map(|model| model.to_string())
+}
+
+/// Reads the whole body into memory, `None` if it's larger than `limit` bytes.
+///
+/// The rest of a body that's too large is still read and thrown away- a client that sends
+/// its whole body before reading the response would otherwise see the connection reset instead of our error.
+pub async fn read_body(mut body: Body, limit: usize) -> Result<Option<Bytes>, hyper::Error> {
+    let mut chunks: Vec<Bytes> = Vec::new();
+    let mut length = 0;
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        length += chunk.len();
+        if length > limit {
+            chunks.clear();
+            continue;
+        }
+        chunks.push(chunk);
+    }
+    if length > limit {
+        return Ok(None);
+    }
+    // A body that arrived in one chunk is kept as it is
+    match chunks.len() {
+        1 => Ok(Some(chunks.remove(0))),
+        _ => Ok(Some(chunks.concat().into())),
+    }
+}
@@ -0,0 +1,207 @@
+//! Cluster-wide model inventory.
+//!
+//! A background task polls `GET /api/tags` (installed models), `GET /api/ps` (models loaded in memory)
+//! and llm_server_windows `GET :11435/health` (KV cache type) on every configured server and keeps the result in the shared server list, so that server selection can stay a quick
+//! in-memory decision inside the critical section.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::{kv_cache, placement};
+use crate::{OllamaServer, SharedServerList};
+
+/// One entry of a server's `GET /api/tags` response.
+#[derive(Debug, Clone)]
+pub struct InstalledModel {
+    pub name: String,
+    pub digest: String,
+    /// The entry exactly as the server reported it, so it can be served back to clients verbatim
+    pub entry: serde_json::Value,
+}
+
+impl InstalledModel {
+    fn from_entry(entry: serde_json::Value) -> Option<Self> {
+        let name = entry.get("name")?.as_str()?.to_string();
+        let digest = entry.get("digest").and_then(|digest| digest.as_str()).unwrap_or_default().to_string();
+        Some(Self { name, digest, entry })
+    }
+}
+
+/// One entry of a server's `GET /api/ps` response- a model currently loaded in memory.
+#[derive(Debug, Clone)]
+pub struct LoadedModel {
+    pub name: String,
+    /// The entry exactly as the server reported it
+    pub entry: serde_json::Value,
+}
+
+impl LoadedModel {
+    fn from_entry(entry: serde_json::Value) -> Option<Self> {
+        let name = entry.get("name")?.as_str()?.to_string();
+        Some(Self { name, entry })
+    }
+}
+
+/// Max seconds to wait for a server to answer an inventory poll.
+/// A hung server must not stall the inventory of the others for long.
+pub const POLL_TIMEOUT_SECS: u64 = 5;
+
+/// Ollama treats a model name without a tag as `:latest`,
+/// so `llama3` and `llama3:latest` refer to the same model.
+pub fn normalize_model_name(name: &str) -> String {
+    let last_segment = name.rsplit('/').next().unwrap_or(name);
+    if last_segment.contains(':') {
+        name.to_string()
+    } else {
+        format!("{}:latest", name)
+    }
+}
+
+/// Whether the server may be able to serve `model`.
+///
+/// A server whose inventory was never fetched (still starting up, or unreachable since we started)
+/// is given the benefit of the doubt- runtime failure tracking remains the authoritative signal.
+pub fn server_has_model(server: &OllamaServer, model: &str) -> bool {
+    match &server.installed_models {
+        None => true,
+        Some(models) => {
+            let wanted = normalize_model_name(model);
+            models.iter().any(|installed| normalize_model_name(&installed.name) == wanted)
+        }
+    }
+}
+
+/// Whether the last `GET /api/ps` poll saw `model` loaded in the server's memory.
+pub fn server_has_model_loaded(server: &OllamaServer, model: &str) -> bool {
+    let wanted = normalize_model_name(model);
+    server.loaded_models.iter().flatten().any(|loaded| normalize_model_name(&loaded.name) == wanted)
+}
+
+/// Whether at least one server, busy or not, may be able to serve `model`.
+pub fn cluster_has_model(servers: &SharedServerList, model: &str) -> bool {
+    let servers_lock = servers.lock().unwrap();
+    servers_lock.values().any(|server| server_has_model(server, model))
+}
+
+/// Runs forever, refreshing the installed and loaded models and the KV cache type of every server each `interval_secs`.
+/// After every poll, the installed models are checked against the model placements.
+pub async fn poll_inventory(servers: SharedServerList, interval_secs: u32, mut reconciler: placement::Reconciler) {
+    let client = match reqwest::Client::builder()
+        .connect_timeout(Duration::from_secs(1))
+        .timeout(Duration::from_secs(POLL_TIMEOUT_SECS))
+        .build()
+    {
+        Ok(client) => client,
+        Err(e) => {
+            println!("⛔ Model inventory polling disabled, failed to build HTTP client: {}", e);
+            return;
+        }
+    };
+
+    // Only log a failing poll once, not on every round. Keyed by URL.
+    let mut failing: HashMap<String, bool> = HashMap::new();
+
+    loop {
+        let placement_settled = reconciler.is_idle();
+        let addresses: Vec<(String, u16)> = servers.lock().unwrap().iter()
+            .map(|(address, server)| (address.clone(), server.control_port))
+            .collect();
+        let results = futures_util::future::join_all(addresses.iter().map(|(address, control_port)| async {
+            tokio::join!(
+                fetch_model_list(&client, address, "/api/tags"),
+                fetch_model_list(&client, address, "/api/ps"),
+                kv_cache::fetch_kv_cache_type(&client, address, *control_port),
+            )
+        })).await;
+
+        {
+            let mut servers_lock = servers.lock().unwrap();
+            for ((address, _), (tags_result, ps_result, kv_cache_result)) in addresses.iter().zip(results) {
+                let Some(server) = servers_lock.get_mut(address) else {
+                    continue;
+                };
+                match tags_result {
+                    Ok(entries) => {
+                        let models: Vec<InstalledModel> = entries.into_iter().filter_map(InstalledModel::from_entry).collect();
+                        let changed = match &server.installed_models {
+                            // Entries also carry fields such as `modified_at`, only name and digest identify a model
+                            Some(known) => !known.iter().map(|model| (&model.name, &model.digest))
+                                .eq(models.iter().map(|model| (&model.name, &model.digest))),
+                            None => true,
+                        };
+                        if changed {
+                            let names: Vec<&str> = models.iter().map(|model| model.name.as_str()).collect();
+                            println!("📚 Server {} ({}) has {} model(s) installed: {}", address, server.name, models.len(), names.join(", "));
+                        }
+                        server.installed_models = Some(models);
+                        failing.insert(format!("{}/api/tags", address), false);
+                    }
+                    Err(e) => {
+                        if failing.insert(format!("{}/api/tags", address), true) != Some(true) {
+                            println!("📭 Failed to list models of server {} ({}), keeping last known inventory. Error: {}", address, server.name, e);
+                        }
+                    }
+                }
+                match ps_result {
+                    Ok(entries) => {
+                        let models: Vec<LoadedModel> = entries.into_iter().filter_map(LoadedModel::from_entry).collect();
+                        let changed = match &server.loaded_models {
+                            Some(known) => !known.iter().map(|model| &model.name).eq(models.iter().map(|model| &model.name)),
+                            None => true,
+                        };
+                        if changed {
+                            let names: Vec<&str> = models.iter().map(|model| model.name.as_str()).collect();
+                            println!("🔥 Server {} ({}) has {} model(s) loaded: {}", address, server.name, models.len(), names.join(", "));
+                        }
+                        server.loaded_models = Some(models);
+                        failing.insert(format!("{}/api/ps", address), false);
+                    }
+                    Err(e) => {
+                        if failing.insert(format!("{}/api/ps", address), true) != Some(true) {
+                            println!("📭 Failed to list loaded models of server {} ({}), keeping last known state. Error: {}", address, server.name, e);
+                        }
+                    }
+                }
+                match kv_cache_result {
+                    // Restarting, `kv_cache::reconfigure` keeps track of it until it's back
+                    _ if server.state.reconfiguring => {}
+                    Ok(kv_cache_type) => {
+                        let was_reporting = failing.insert(format!("{}/health", address), false) == Some(false);
+                        if server.kv_cache_type != kv_cache_type || !was_reporting {
+                            println!("🧮 Server {} ({}) runs with {} KV cache", address, server.name, kv_cache_type);
+                        }
+                        server.kv_cache_type = kv_cache_type;
+                        server.kv_cache_controllable = true;
+                    }
+                    Err(e) => {
+                        // Not every server runs the newest llm_server_windows
+                        server.kv_cache_type = kv_cache::KvCacheType::Q8_0;
+                        if failing.insert(format!("{}/health", address), true) != Some(true) {
+                            println!("⚠️  Server {} ({}) didn't report its KV cache type, assuming {}. Error: {}", address, server.name, server.kv_cache_type, e);
+                        }
+                        server.kv_cache_controllable = false;
+                    }
+                }
+            }
+        }
+        if placement_settled {
+            reconciler.check(&servers);
+        }
+
+        tokio::time::sleep(Duration::from_secs(interval_secs.into())).await;
+    }
+}
+
+/// Fetches the `models` array that both `GET /api/tags` and `GET /api/ps` respond with.
+pub async fn fetch_model_list(client: &reqwest::Client, address: &str, path: &str) -> Result<Vec<serde_json::Value>, Box<dyn std::error::Error + Send + Sync>> {
+    let response = client.get(format!("{}{}", address, path)).send().await?;
+    if !response.status().is_success() {
+        return Err(format!("GET {} returned status {}", path, response.status()).into());
+    }
+    let body = response.bytes().await?;
+    let mut json: serde_json::Value = serde_json::from_slice(&body)?;
+    match json.get_mut("models").map(serde_json::Value::take) {
+        Some(serde_json::Value::Array(models)) => Ok(models),
+        _ => Err(format!("GET {} response has no \"models\" array", path).into()),
+    }
+}
@@ -0,0 +1,139 @@
+//! KV cache quantization awareness.
+//!
+//! Ollama's `OLLAMA_KV_CACHE_TYPE` is global to the server process, and some models need q8_0 to fit
+//! their context in VRAM while others lose quality with it. Servers deployed with
+//! [llm_server_windows](https://github.com/BigBIueWhale/llm_server_windows) report their current
+//! KV cache type via `GET :11435/health`, and restart Ollama with another one on `POST :11435/set-kv-cache`.
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::queue::{self, SharedQueue};
+use crate::SharedServerList;
+
+/// Port of the llm_server_windows control API, on the same host as Ollama
+pub const DEFAULT_CONTROL_PORT: u16 = 11435;
+
+/// Max seconds to wait for Ollama to come back with the new KV cache type.
+/// llm_server_windows usually restarts it within 5-15 seconds.
+const RECONFIGURE_TIMEOUT_SECS: u64 = 60;
+
+/// How often to ask `GET /health` whether the restart is done
+const RESTART_POLL_INTERVAL_MS: u64 = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KvCacheType {
+    Q8_0,
+    Q16,
+}
+
+impl fmt::Display for KvCacheType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KvCacheType::Q8_0 => write!(f, "q8_0"),
+            KvCacheType::Q16 => write!(f, "q16"),
+        }
+    }
+}
+
+/// Models given with `--kv-q8` need q8_0, all others q16.
+pub fn required_kv_cache_type(model: &str, kv_q8_models: &[String]) -> KvCacheType {
+    let model = crate::inventory::normalize_model_name(model);
+    if kv_q8_models.contains(&model) {
+        KvCacheType::Q8_0
+    } else {
+        KvCacheType::Q16
+    }
+}
+
+/// The llm_server_windows control API URL for `path`, on the host of the Ollama server at `address`
+pub fn control_url(address: &str, control_port: u16, path: &str) -> Result<String, String> {
+    let mut url = reqwest::Url::parse(address).map_err(|e| format!("Invalid server address {}: {}", address, e))?;
+    url.set_port(Some(control_port)).map_err(|_| format!("Server address {} can't have a port", address))?;
+    url.set_path(path);
+    Ok(url.to_string())
+}
+
+/// Asks llm_server_windows which KV cache type Ollama currently runs with.
+pub async fn fetch_kv_cache_type(client: &reqwest::Client, address: &str, control_port: u16) -> Result<KvCacheType, Box<dyn std::error::Error + Send + Sync>> {
+    let response = client.get(control_url(address, control_port, "/health")?).send().await?;
+    if !response.status().is_success() {
+        return Err(format!("GET /health returned status {}", response.status()).into());
+    }
+    let body = response.bytes().await?;
+    let json: serde_json::Value = serde_json::from_slice(&body)?;
+    match json.get("kv_cache_type").and_then(|kv_cache_type| kv_cache_type.as_str()) {
+        Some("q8_0") => Ok(KvCacheType::Q8_0),
+        Some("q16") | Some("f16") => Ok(KvCacheType::Q16),
+        Some(other) => Err(format!("Unknown kv_cache_type \"{}\"", other).into()),
+        None => Err("GET /health response has no \"kv_cache_type\"".into()),
+    }
+}
+
+/// Restarts the server at `address` with `kv_cache_type` and waits until it reports that type.
+///
+/// The server must have been marked `reconfiguring` when it was selected- that's what keeps other requests
+/// away from it until we're done. Meant to be spawned, so that a client disconnecting mid-restart
+/// can't leave the server marked `reconfiguring` forever.
+pub async fn reconfigure(servers: SharedServerList, queue: SharedQueue, address: String, kv_cache_type: KvCacheType) -> Result<(), String> {
+    let Some((name, control_port)) = servers.lock().unwrap().get(&address).map(|server| (server.name.clone(), server.control_port)) else {
+        return Err(format!("Server {} is no longer configured", address));
+    };
+    println!("🔧 Asking server {} ({}) to restart Ollama with {} KV cache", address, name, kv_cache_type);
+    let started = Instant::now();
+    let result = restart_with(&address, control_port, kv_cache_type).await;
+
+    {
+        let mut servers_lock = servers.lock().unwrap();
+        if let Some(server) = servers_lock.get_mut(&address) {
+            server.state.reconfiguring = false;
+            match &result {
+                Ok(()) => {
+                    println!("🔧✅ Server {} ({}) is back with {} KV cache after {:.1}s", address, server.name, kv_cache_type, started.elapsed().as_secs_f32());
+                    server.kv_cache_type = kv_cache_type;
+                    // The restart unloaded every model and emptied the KV cache
+                    server.loaded_models = Some(Vec::new());
+                    server.cached_prompt = None;
+                }
+                Err(e) => {
+                    println!("🔧⛔ Server {} ({}) failed to switch to {} KV cache. Error: {}", address, server.name, kv_cache_type, e);
+                }
+            }
+        }
+    }
+    // The server's other slots are free again
+    queue::dispatch(&servers, &queue);
+    result
+}
+
+/// `POST /set-kv-cache`, then polls `GET /health` until the new type is reported.
+async fn restart_with(address: &str, control_port: u16, kv_cache_type: KvCacheType) -> Result<(), String> {
+    let client = reqwest::Client::builder()
+        .connect_timeout(Duration::from_secs(1))
+        .timeout(Duration::from_secs(5))
+        .build()
+        .map_err(|e| e.to_string())?;
+    let body = serde_json::json!({ "type": kv_cache_type.to_string() });
+    let response = client.post(control_url(address, control_port, "/set-kv-cache")?)
+        .header("Content-Type", "application/json")
+        .body(body.to_string())
+        .send().await
+        .map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("POST /set-kv-cache returned status {}", response.status()));
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(RECONFIGURE_TIMEOUT_SECS);
+    loop {
+        tokio::time::sleep(Duration::from_millis(RESTART_POLL_INTERVAL_MS)).await;
+        // Errors are expected while Ollama is down
+        if let Ok(reported) = fetch_kv_cache_type(&client, address, control_port).await {
+            if reported == kv_cache_type {
+                return Ok(());
+            }
+        }
+        if Instant::now() >= deadline {
+            return Err(format!("Still not running with {} KV cache after {} seconds", kv_cache_type, RECONFIGURE_TIMEOUT_SECS));
+        }
+    }
+}
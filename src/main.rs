@@ -2,55 +2,286 @@ use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Request, Response, Server, StatusCode, server::conn::AddrStream};
 use std::convert::Infallible;
 use std::sync::{Arc, Mutex};
-use futures_util::stream::StreamExt;
 use futures_util::Stream;
+use std::future::Future;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 use clap::Parser;
 use ordermap::OrderMap;
 
-/// Struct to hold the user-supplied server address and its human-readable name.
-/// Format on the command line should be:  ip:port=Name
+mod affinity;
+mod blobs;
+mod cluster_api;
+mod fairness;
+mod fanout;
+mod inference;
+mod inventory;
+mod kv_cache;
+mod metadata;
+mod normalize;
+mod placement;
+mod priority;
+mod proxy;
+mod queue;
+mod retry;
+mod timeouts;
+
+/// Struct to hold the user-supplied server address, its human-readable name and optional annotations.
+/// Format on the command line should be:  ip:port=Name  or  ip:port=Name[capability=10,speed=100]
 #[derive(Debug, Clone)]
 struct ServerConfig {
     address: String,
     name: String,
+    /// 0-100, lower capability servers are preferred for models they have, saving the big ones for models that need them
+    capability: u8,
+    /// 0-100, tiebreaker between servers of the same capability- faster is preferred
+    speed: u8,
+    /// Port of the llm_server_windows control API on the server's host
+    control_port: u16,
+    /// Requests the server may handle at once- its `OLLAMA_NUM_PARALLEL`
+    slots: u32,
+    /// Group the server belongs to, for `--placement`
+    group: Option<String>,
 }
 
 impl std::str::FromStr for ServerConfig {
     type Err = String;
 
     /// We expect the user to provide something like "127.0.0.1:11433=LocalOllama"
+    /// or "http://127.0.0.1:11433=LocalOllama[capability=10,speed=100]"
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let parts: Vec<&str> = s.splitn(2, '=').collect();
         if parts.len() != 2 {
-            return Err("Invalid server format. Use ip:port=Name".to_string());
+            return Err("Invalid server format. Use ip:port=Name or ip:port=Name[capability=0-100,speed=0-100]".to_string());
         }
-        Ok(ServerConfig {
+        let mut config = ServerConfig {
             address: parts[0].trim().to_string(),
             name: parts[1].trim().to_string(),
-        })
+            capability: 0,
+            speed: 0,
+            control_port: kv_cache::DEFAULT_CONTROL_PORT,
+            slots: 1,
+            group: None,
+        };
+
+        if let Some(without_bracket) = config.name.strip_suffix(']') {
+            let (name, annotations) = without_bracket.split_once('[')
+                .ok_or_else(|| format!("Missing '[' before annotations in server name \"{}\"", config.name))?;
+            let name = name.trim().to_string();
+            let mut seen_keys = Vec::new();
+            for annotation in annotations.split(',').map(str::trim).filter(|annotation| !annotation.is_empty()) {
+                let (key, value) = annotation.split_once('=')
+                    .ok_or_else(|| format!("Invalid annotation \"{}\" for server {}. Use key=value", annotation, name))?;
+                let (key, value) = (key.trim(), value.trim());
+                if seen_keys.contains(&key) {
+                    return Err(format!("Annotation \"{}\" given twice for server {}", key, name));
+                }
+                seen_keys.push(key);
+                match key {
+                    "capability" => config.capability = parse_percentage(key, value, &name)?,
+                    "speed" => config.speed = parse_percentage(key, value, &name)?,
+                    "control_port" => config.control_port = value.parse()
+                        .map_err(|_| format!("Invalid control_port \"{}\" for server {}. Must be a port number", value, name))?,
+                    "slots" => config.slots = match value.parse::<u32>() {
+                        Ok(slots) if slots >= 1 => slots,
+                        _ => return Err(format!("Invalid slots \"{}\" for server {}. Must be a positive integer", value, name)),
+                    },
+                    "group" if !value.is_empty() => config.group = Some(value.to_string()),
+                    "group" => return Err(format!("Empty group for server {}", name)),
+                    _ => return Err(format!("Unknown annotation \"{}\" for server {}. Supported: capability, speed, control_port, slots, group", key, name)),
+                }
+            }
+            config.name = name;
+        }
+
+        Ok(config)
+    }
+}
+
+/// Parses an annotation value that must be an integer in the range 0-100 (inclusive)
+fn parse_percentage(key: &str, value: &str, server_name: &str) -> Result<u8, String> {
+    match value.parse::<u8>() {
+        Ok(number) if number <= 100 => Ok(number),
+        _ => Err(format!("Invalid {} \"{}\" for server {}. Must be an integer from 0 to 100", key, value, server_name)),
     }
 }
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
-    /// Syntax is --server IP:PORT=NAME --server IP:PORT=NAME ...
+    /// Syntax is --server IP:PORT=NAME --server IP:PORT=NAME[capability=0-100,speed=0-100] ...
     ///
     /// This is a required argument. It specifies the addresses of the Ollama servers
     /// that the load balancer will distribute requests to, plus a friendly name.
+    /// The optional annotations default to 0. Among servers that have the requested model, the lowest
+    /// capability is preferred, then the highest speed, then the order of the --server arguments.
+    /// Also accepted: control_port=PORT, where llm_server_windows listens (default 11435),
+    /// slots=N, the number of requests the server handles at once (its OLLAMA_NUM_PARALLEL, default 1),
+    /// and group=NAME, a group --placement can refer to.
     #[arg(short, long, required = true)]
     server: Vec<ServerConfig>,
 
-    /// Max seconds to allow Ollama server to pause.
+    /// Max seconds to allow Ollama server to pause in the middle of a response.
     ///
-    /// Don't set this too low because if the delay is too great at the beginning of response generation that will cause failure.
+    /// The wait for the beginning of the response is limited by --first-byte-timeout instead.
     /// Pass 0 to disable timeout.
     /// 
-    /// This is an optional argument. It specifies the maximum number of seconds to wait for a response from the Ollama server before considering it unavailable
+    /// This is an optional argument. It specifies the maximum number of seconds of silence between two chunks of a response before considering the Ollama server unavailable
     #[arg(short, long, default_value_t = 30)]
     timeout: u32,
+
+    /// Max seconds to wait for the response headers and its first chunk.
+    ///
+    /// Loading a model and ingesting a long prompt can take minutes before the first token.
+    /// Non-streamed responses arrive in one piece, so for them this limits the whole generation.
+    /// Pass 0 to disable timeout.
+    #[arg(long, default_value_t = 120)]
+    first_byte_timeout: u32,
+
+    /// Syntax is --timeout-rule "path=PATTERN,model=PATTERN[timeout=SECONDS,first_byte_timeout=SECONDS]" ...
+    ///
+    /// Overrides --timeout and --first-byte-timeout for requests whose path and requested model match the patterns,
+    /// e.g. "path=/v1/images/*[timeout=600]" or "model=qwen3-vl*[first_byte_timeout=900]". Either matcher may be left out,
+    /// `*` stands for any run of characters. The first matching rule wins.
+    #[arg(long = "timeout-rule", value_name = "RULE")]
+    timeout_rule: Vec<timeouts::TimeoutRule>,
+
+    /// Key that allows running `POST /api/pull`, `DELETE /api/delete`, `POST /api/copy` and `POST /api/create`
+    /// on several servers at once, sent as Authorization: Bearer or x-api-key.
+    ///
+    /// Such requests go to every server, or to the servers named in an X-LB-Servers: NAME,NAME header.
+    /// Without an admin key, any request with an X-LB-Servers: all|NAME,NAME header may do so.
+    #[arg(long)]
+    admin_key: Option<String>,
+
+    /// Seconds between polls of each server's installed models (`GET /api/tags`) and loaded models (`GET /api/ps`).
+    ///
+    /// Inference requests are only routed to servers that have the requested model installed.
+    #[arg(long, default_value_t = 30, value_parser = clap::value_parser!(u32).range(1..))]
+    poll_interval: u32,
+
+    /// Min characters a new `/api/generate` or `/v1/completions` prompt must share with the start of
+    /// a server's last prompt + response for that server to be preferred (its KV cache holds them).
+    #[arg(long, default_value_t = 1000)]
+    min_shared_prefix: u32,
+
+    /// Syntax is --kv-q8 MODEL --kv-q8 MODEL ...
+    ///
+    /// Models that need 8-bit KV cache quantization. All other models are preferably served by servers
+    /// running with q16 KV cache, as reported by llm_server_windows `GET :11435/health`.
+    #[arg(long = "kv-q8", value_name = "MODEL")]
+    kv_q8: Vec<String>,
+
+    /// Seconds a request waits for a busy server that runs the KV cache type its model wants, before a free server
+    /// of the other type is restarted with the right one (or, without llm_server_windows, used as is).
+    ///
+    /// Restarting Ollama takes 5-15 seconds and unloads every model, so a server about to free up is often sooner.
+    #[arg(long, default_value_t = 10)]
+    reconfigure_wait: u32,
+
+    /// Max requests waiting for a server while all suitable servers are busy.
+    ///
+    /// Once that many wait, further requests get 503 right away. Pass 0 to never wait.
+    #[arg(long, default_value_t = 100)]
+    max_queue_length: usize,
+
+    /// Max seconds a request waits for a server before getting 503.
+    #[arg(long, default_value_t = 30, value_parser = clap::value_parser!(u32).range(1..))]
+    max_queue_wait: u32,
+
+    /// Syntax is --priority CLASS:RULE --priority CLASS:RULE ...
+    ///
+    /// Requests matching RULE get priority CLASS (high, normal or low) while waiting for a server.
+    /// RULE is ip=ADDRESS[/PREFIX], key=API_KEY (sent as Authorization: Bearer or x-api-key)
+    /// or user-agent=TEXT (contained in the User-Agent header). The first matching rule wins.
+    /// An X-LB-Priority: high|normal|low request header overrides the rules. Everything else is normal.
+    #[arg(long = "priority", value_name = "CLASS:RULE")]
+    priority: Vec<priority::PriorityRule>,
+
+    /// Seconds of waiting that raise a waiting request by one priority class, so low priority requests aren't starved.
+    #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u32).range(1..))]
+    priority_aging: u32,
+
+    /// Share the servers fairly between clients, telling them apart by their address, their forwarded address
+    /// (first X-Forwarded-For entry) or their API key (Authorization: Bearer or x-api-key).
+    ///
+    /// Each client may have at most --max-per-client requests served at once, and freed servers go round-robin
+    /// to the clients with requests waiting. Off by default.
+    #[arg(long, value_enum)]
+    fairness: Option<fairness::ClientKey>,
+
+    /// With --fairness, max requests of a single client served at once. Its other requests wait in line.
+    #[arg(long, default_value_t = 2, value_parser = clap::value_parser!(u32).range(1..))]
+    max_per_client: u32,
+
+    /// Max servers to try a request on. When a server can't be reached, the request goes to the next best one,
+    /// and the client only gets 502 once every attempt failed.
+    #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u32).range(1..))]
+    max_attempts: u32,
+
+    /// Max bytes of a request body kept in memory to retry the request on another server.
+    ///
+    /// Larger bodies are streamed through and the request is only tried once.
+    /// Inference requests are always kept in memory, their model is needed to choose a server.
+    #[arg(long, default_value_t = 16 * 1024 * 1024)]
+    retry_body_limit: usize,
+
+    /// Max bytes of an inference request body, such as a chat with its images. Larger ones are rejected with 413.
+    #[arg(long, default_value_t = 64 * 1024 * 1024)]
+    inference_body_limit: usize,
+
+    /// Syntax is --placement MODEL=all --placement MODEL=NAME,NAME --placement MODEL=none ...
+    ///
+    /// The servers that must have MODEL installed, by server name or group (the group=NAME annotation).
+    /// A placed model is pulled where it's missing and deleted from all other servers. Models without a placement are left alone.
+    /// Drift is reported after every --poll-interval, and fixed with --fix-drift or the reconcile subcommand.
+    #[arg(long = "placement", value_name = "MODEL=SERVERS")]
+    placement: Vec<placement::PlacementRule>,
+
+    /// Pull and delete models to fix drift from --placement, instead of only reporting it.
+    #[arg(long)]
+    fix_drift: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Print the pulls and deletes that place the models as --placement says, then take them. Doesn't start the load balancer.
+    Reconcile {
+        /// Only print the pulls and deletes
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+/// How requests are forwarded to the servers, shared by all requests
+#[derive(Debug)]
+struct ForwardingConfig {
+    /// Max silence between chunks, 0 for none
+    timeout_secs: u32,
+    /// Max wait for the response headers and its first chunk, 0 for none
+    first_byte_timeout_secs: u32,
+    /// Override the timeouts for matching requests
+    timeout_rules: Vec<timeouts::TimeoutRule>,
+    /// Allows model management on several servers at once
+    admin_key: Option<String>,
+    priority_rules: Vec<priority::PriorityRule>,
+    fairness: Option<fairness::ClientKey>,
+    max_attempts: u32,
+    retry_body_limit: usize,
+    inference_body_limit: usize,
+}
+
+/// Settings that server selection needs, shared by all requests
+#[derive(Debug)]
+struct SelectionConfig {
+    min_shared_prefix: u32,
+    /// Normalized names of the models given with --kv-q8
+    kv_q8_models: Vec<String>,
+    /// How long a request holds out for a server of the right KV cache type
+    reconfigure_wait: std::time::Duration,
 }
 
 #[derive(Clone, Debug)]
@@ -62,21 +293,54 @@ enum FailureRecord {
 
 #[derive(Debug)]
 struct ServerState {
-    busy: bool,
+    /// Requests the server may handle at once
+    slots: u32,
+    /// Requests the server is handling right now
+    busy_slots: u32,
+    /// Restarting with another KV cache type. Set by the request that asked for the restart,
+    /// so no other request gets the server until it's back.
+    reconfiguring: bool,
     failure_record: FailureRecord,
 }
 
+impl ServerState {
+    fn is_available(&self) -> bool {
+        self.busy_slots < self.slots && !self.reconfiguring
+    }
+}
+
 #[derive(Debug)]
 struct OllamaServer {
     state: ServerState,
     name: String,
+    capability: u8,
+    speed: u8,
+    control_port: u16,
+    /// From the last successful `GET :11435/health` poll.
+    /// q8_0 is assumed for servers without llm_server_windows- that's how our servers ran before it.
+    kv_cache_type: kv_cache::KvCacheType,
+    /// Whether the last `GET :11435/health` poll succeeded- only then can we ask for another KV cache type
+    kv_cache_controllable: bool,
+    /// Models from the last successful `GET /api/tags` poll, `None` until the first one succeeds
+    installed_models: Option<Vec<inventory::InstalledModel>>,
+    /// Models from the last successful `GET /api/ps` poll, `None` until the first one succeeds
+    loaded_models: Option<Vec<inventory::LoadedModel>>,
+    /// The last prompt this server completed, presumably still in its KV cache
+    cached_prompt: Option<affinity::Prompt>,
+    /// Shared by all requests to the server, so that connections are kept alive and reused
+    client: reqwest::Client,
 }
 
 type SharedServerList = Arc<Mutex<OrderMap<String, OllamaServer>>>;
 
+/// Seconds an unused kept-alive connection to a server stays open. Firewalls and VM network stacks
+/// may forget quiet connections, and a connection that died unnoticed costs a failed attempt.
+const POOL_IDLE_TIMEOUT_SECS: u64 = 30;
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
+    let placements = placement::resolve(&args.placement, &args.server)?;
 
     let mut servers_map = OrderMap::new();
     for config in args.server {
@@ -88,31 +352,110 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
         servers_map.insert(address.clone(), OllamaServer {
             state: ServerState {
-                busy: false,
+                slots: config.slots,
+                busy_slots: 0,
+                reconfiguring: false,
                 failure_record: FailureRecord::Reliable,
             },
             name,
+            capability: config.capability,
+            speed: config.speed,
+            control_port: config.control_port,
+            kv_cache_type: kv_cache::KvCacheType::Q8_0,
+            kv_cache_controllable: false,
+            installed_models: None,
+            loaded_models: None,
+            cached_prompt: None,
+            client: build_server_client()?,
         });
     }
 
-    println!("");
+    if let Some(Command::Reconcile { dry_run }) = args.command {
+        return Ok(placement::reconcile(&placements, &servers_map, dry_run).await?);
+    }
+
+    println!();
     println!("📒 Ollama servers list:");
     for (index, (addr, srv)) in servers_map.iter().enumerate() {
-        println!("{}. {} ({})", index + 1, addr, srv.name);
+        println!("{}. {} ({}) capability {}, speed {}, {} slot(s)", index + 1, addr, srv.name, srv.capability, srv.speed, srv.state.slots);
     }
-    println!("");
+    println!();
     println!("⚙️  Timeout setting: Will abandon Ollama server after {} seconds of silence", args.timeout);
-    println!("");
+    println!("⚙️  First byte timeout setting: Will abandon Ollama server after {} seconds without a response", args.first_byte_timeout);
+    if !args.timeout_rule.is_empty() {
+        println!("⚙️  {} timeout rule(s) override these for matching requests", args.timeout_rule.len());
+    }
+    println!();
+
+    let selection = Arc::new(SelectionConfig {
+        min_shared_prefix: args.min_shared_prefix,
+        kv_q8_models: args.kv_q8.iter().map(|model| inventory::normalize_model_name(model)).collect(),
+        reconfigure_wait: std::time::Duration::from_secs(args.reconfigure_wait.into()),
+    });
+    if !selection.kv_q8_models.is_empty() {
+        println!("⚙️  Models that need q8_0 KV cache: {}", selection.kv_q8_models.join(", "));
+        println!();
+    }
 
     let servers = Arc::new(Mutex::new(servers_map));
+    let queue = Arc::new(Mutex::new(queue::WaitQueue::new(
+        args.max_queue_length,
+        args.max_queue_wait,
+        args.priority_aging,
+        args.fairness.map(|_| args.max_per_client as usize),
+        selection,
+    )));
+    if args.max_queue_length > 0 {
+        println!("⚙️  Waiting line: up to {} requests wait up to {} seconds for a server", args.max_queue_length, args.max_queue_wait);
+        println!();
+    }
+    if let Some(fairness) = args.fairness {
+        println!("⚙️  Fair sharing: clients told apart by {}, at most {} request(s) of each served at once", fairness, args.max_per_client);
+        println!();
+    }
+    if !args.priority.is_empty() {
+        println!("⚙️  {} priority rule(s), waiting raises a request by one priority class every {} seconds", args.priority.len(), args.priority_aging);
+        println!();
+    }
+    if args.max_attempts > 1 {
+        println!("⚙️  Retries: requests go to up to {} servers until one can be reached (bodies up to {} bytes)", args.max_attempts, args.retry_body_limit);
+        println!();
+    }
+    let forwarding = Arc::new(ForwardingConfig {
+        timeout_secs: args.timeout,
+        first_byte_timeout_secs: args.first_byte_timeout,
+        timeout_rules: args.timeout_rule,
+        admin_key: args.admin_key,
+        priority_rules: args.priority,
+        fairness: args.fairness,
+        max_attempts: args.max_attempts,
+        retry_body_limit: args.retry_body_limit,
+        inference_body_limit: args.inference_body_limit,
+    });
+
+    if !placements.is_empty() {
+        let fix = if args.fix_drift { "fixed" } else { "reported" };
+        println!("⚙️  Model placement: {} model(s) placed, drift is {} after every poll", placements.len(), fix);
+        println!();
+    }
+    let reconciler = placement::Reconciler::new(placements, args.fix_drift);
+    tokio::spawn(inventory::poll_inventory(servers.clone(), args.poll_interval, reconciler));
+
+    let blob_pins = Arc::new(Mutex::new(blobs::BlobPins::new()));
 
     let make_svc = make_service_fn(|conn: &AddrStream| {
         let remote_addr = conn.remote_addr();
         let servers = servers.clone();
+        let queue = queue.clone();
+        let blob_pins = blob_pins.clone();
+        let forwarding = forwarding.clone();
         async move {
             Ok::<_, Infallible>(service_fn(move |req| {
                 let servers = servers.clone();
-                handle_request(req, servers, remote_addr, args.timeout)
+                let queue = queue.clone();
+                let blob_pins = blob_pins.clone();
+                let forwarding = forwarding.clone();
+                handle_request(req, servers, queue, blob_pins, forwarding, remote_addr)
             }))
         }
     });
@@ -125,7 +468,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let graceful = server.with_graceful_shutdown(shutdown_signal());
 
     println!("👂 Ollama Load Balancer listening on http://{}", addr);
-    println!("");
+    println!();
 
     if let Err(e) = graceful.await {
         return Err(e.into());
@@ -148,9 +491,16 @@ async fn shutdown_signal() {
 async fn handle_request(
     req: Request<Body>,
     servers: SharedServerList,
+    queue: queue::SharedQueue,
+    blob_pins: blobs::SharedBlobPins,
+    config: Arc<ForwardingConfig>,
     remote_addr: std::net::SocketAddr,
-    timeout_secs: u32,
 ) -> Result<Response<Body>, Infallible> {
+    // Cluster-wide views are answered from memory, without occupying any server
+    if let Some(response) = cluster_api::respond(req.method(), req.uri().path(), &servers) {
+        return Ok(response);
+    }
+
     let reqwest_method = match hyper_method_to_reqwest_method(req.method().clone()) {
         Ok(method) => method,
         Err(e) => {
@@ -161,73 +511,210 @@ async fn handle_request(
         }
     };
 
-    // Get the path
-    let path = req.uri().path();
+    // Ollama answers these right away even while generating, no need to take a slot
+    if metadata::is_metadata_request(req.method(), req.uri().path()) {
+        return Ok(metadata::forward(req, reqwest_method, &servers, remote_addr).await);
+    }
 
-    // Select an available server
-    let server_key = select_available_server(&servers, &remote_addr).await;
+    // Blob checks and uploads don't run a model either, and the pinned server must get them even while it's busy
+    if blobs::is_blob_request(req.method(), req.uri().path()) {
+        return Ok(blobs::forward(req, reqwest_method, &servers, &blob_pins, remote_addr, config.retry_body_limit).await);
+    }
 
-    if let Some(key) = server_key {
-        // As long as guard object is alive, the server will be marked as "in use"
-        let _guard = ServerGuard {
-            servers: servers.clone(),
-            key: key.clone(),
-        };
+    // Pulls, deletes and such may be asked to run on several servers at once
+    if fanout::is_management_request(req.method(), req.uri().path()) {
+        match fanout::targets(req.headers(), &servers, config.admin_key.as_deref()) {
+            Ok(Some(targets)) => return Ok(fanout::forward(req, reqwest_method, targets, remote_addr).await),
+            Ok(None) => {}
+            Err((status, message)) => return Ok(fanout::error_response(status, message)),
+        }
+    }
+
+    // Get the path
+    let path = req.uri().path().to_string();
+    let (parts, body) = req.into_parts();
 
-        // Build the request to the Ollama server
-        let uri = format!("{}{}", key, path);
-
-        let mut builder = reqwest::Client::builder();
-        // Low value for connect timeout, to get an immediate error
-        // if the Ollama server isn't even running.
-        // Even if the Ollama server takes its time, it should still be
-        // able to immediately facilitate a TCP connection with us.
-        builder = builder.connect_timeout(std::time::Duration::from_secs(1));
-        if timeout_secs == 0 {
-            builder = builder.pool_idle_timeout(None);
+    // Inference requests name the model they need, so the body must be read
+    // before a server can be chosen. Any other request is kept in memory too if small enough,
+    // so it can be sent to another server when the first can't be reached.
+    let is_inference = inference::is_inference_request(&parts.method, &path);
+    let body_result = if is_inference {
+        inference::read_body(body, config.inference_body_limit).await.map(|bytes| bytes.map(retry::RequestBody::Buffered))
+    } else {
+        retry::read_body(body, config.retry_body_limit).await.map(Some)
+    };
+    let mut request_body = match body_result {
+        Ok(Some(request_body)) => request_body,
+        Ok(None) => {
+            println!("🐘 Client {}'s {} request body is larger than {} bytes, rejecting it", remote_addr, path, config.inference_body_limit);
+            let error = serde_json::json!({ "error": format!("request body is larger than {} bytes (--inference-body-limit)", config.inference_body_limit) });
+            return Ok(Response::builder()
+                .status(StatusCode::PAYLOAD_TOO_LARGE)
+                .header("Content-Type", "application/json; charset=utf-8")
+                .body(Body::from(error.to_string()))
+                .unwrap());
         }
-        else {
-            let timeout = std::time::Duration::from_secs(timeout_secs.into());
-            builder = builder.read_timeout(timeout).pool_idle_timeout(timeout);
+        Err(e) => {
+            return Ok(Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::from(format!("Failed to read request body: {}", e)))
+                .unwrap());
         }
-        let client = builder.build().unwrap();
-        let mut request_builder = client.request(reqwest_method, &uri);
+    };
+    let (requested_model, prompt) = match &request_body {
+        // Parsed only once, images attached to a chat can make the body megabytes large
+        retry::RequestBody::Buffered(bytes) if is_inference => match serde_json::from_slice::<serde_json::Value>(bytes) {
+            Ok(json) => (inference::extract_model(&json), affinity::parse_prompt(&path, &json)),
+            // Not JSON- the Ollama server produces the appropriate error
+            Err(_) => (None, None),
+        },
+        _ => (None, None),
+    };
 
-        // Copy headers
-        for (key_h, value) in req.headers() {
-            request_builder = request_builder.header(key_h.as_str(), value.as_bytes());
+    // The create must reach the server its blobs were uploaded to
+    let digests = blobs::digests(&parts.method, &path, &request_body);
+    let pinned = match blob_pins.lock().unwrap().lookup(&digests, remote_addr.ip()) {
+        Ok(pinned) => pinned,
+        Err(e) => return Ok(blobs::spread_response(remote_addr, &parts.method, &path, &e)),
+    };
+    if let Some(pinned) = &pinned {
+        println!("📌 Client {} sends {} {} to server {}, which has its blobs", remote_addr, parts.method, path, pinned);
+    }
+
+    if let Some(model) = &requested_model {
+        if !inventory::cluster_has_model(&servers, model) {
+            println!("🚫 No server has model {} installed, rejecting client {}", model, remote_addr);
+            let error = serde_json::json!({ "error": format!("model '{}' not found", model) });
+            return Ok(Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .header("Content-Type", "application/json; charset=utf-8")
+                .body(Body::from(error.to_string()))
+                .unwrap());
         }
+    }
 
-        // Set up streaming body
-        let body_stream = req.into_body().map(|chunk_result| match chunk_result {
-            Ok(chunk) => Ok(chunk.to_vec()),
-            Err(e) => Err(std::io::Error::new(std::io::ErrorKind::Other, e)),
-        });
+    // Waiting for the response is timed by us, the wait for the first byte and the silence
+    // between chunks have separate limits
+    let (timeout_secs, first_byte_timeout_secs) = timeouts::resolve(
+        &config.timeout_rules,
+        &path,
+        requested_model.as_deref(),
+        config.timeout_secs,
+        config.first_byte_timeout_secs,
+    );
+    let first_byte_timeout = timeout_from_secs(first_byte_timeout_secs);
+    let chunk_timeout = timeout_from_secs(timeout_secs);
 
-        let reqwest_body = reqwest::Body::wrap_stream(body_stream);
+    let mut queued_request = queue::QueuedRequest {
+        remote_addr,
+        model: requested_model,
+        prompt: prompt.clone(),
+        priority: priority::classify(&config.priority_rules, &parts.headers, remote_addr.ip()),
+        client: config.fairness.map(|key| fairness::client_id(key, &parts.headers, remote_addr.ip())),
+        excluded: Vec::new(),
+        pinned,
+    };
+    let upstream_headers = proxy::request_headers(&parts.headers, remote_addr);
+    // Why the last attempt failed, `None` while no server was tried
+    let mut last_error = None;
+
+    for attempt in 1..=config.max_attempts {
+        // Select an available server, waiting in line if they're all busy
+        let Ok(mut guard) = queue::wait_for_server(&servers, &queue, queued_request.clone()).await else {
+            // Already logged while giving up on waiting
+            break;
+        };
+        // As long as guard object is alive, the server slot will be marked as "in use"
+        let key = guard.key.clone();
+
+        if let Some(kv_cache_type) = guard.reconfigure_to.take() {
+            // Spawned so that the restart is seen through even if this client disconnects
+            let reconfiguration = tokio::spawn(kv_cache::reconfigure(servers.clone(), queue.clone(), key.clone(), kv_cache_type));
+            if let Err(e) = reconfiguration.await.unwrap_or_else(|e| Err(e.to_string())) {
+                demote(&servers, &queue, &key, &format!("didn't come back with {} KV cache", kv_cache_type), None);
+                last_error = Some(format!("Error switching Ollama server to {} KV cache: {}", kv_cache_type, e));
+                queued_request.excluded.push(key);
+                continue;
+            }
+        }
 
-        request_builder = request_builder.body(reqwest_body);
+        let (client, name) = {
+            let servers_lock = servers.lock().unwrap();
+            (servers_lock[&key].client.clone(), servers_lock[&key].name.clone())
+        };
+        let uri = proxy::upstream_url(&key, &parts.uri);
+        let sent_at = tokio::time::Instant::now();
+        let uploaded = request_body.uploaded(sent_at);
+        let send = async {
+            let mut stale_connection_retried = false;
+            loop {
+                // Build the request to the Ollama server
+                let mut request_builder = client.request(reqwest_method.clone(), &uri)
+                    .headers(upstream_headers.clone());
+                if let Some(reqwest_body) = request_body.next_attempt() {
+                    request_builder = request_builder.body(reqwest_body);
+                }
+                match request_builder.send().await {
+                    // The server closed a kept-alive connection just as we reused it- that says nothing about the server
+                    Err(e) if !stale_connection_retried && request_body.can_retry() && retry::is_stale_connection(&e) => {
+                        println!("♻️  Server {} ({}) closed a kept-alive connection, sending client {}'s request again on a new one. Error: {}", key, name, remote_addr, e);
+                        stale_connection_retried = true;
+                    }
+                    sent => return sent.map_err(|e| e.to_string()),
+                }
+            }
+        };
 
-        // Send the request and handle the response
-        match request_builder.send().await {
+        // Send the request and handle the response, `None` if no response came in time
+        let sent = match first_byte_timeout {
+            // The wait for the first byte starts once the whole body went out- a slow upload is up to the client
+            Some(limit) => tokio::select! {
+                sent = send => Some(sent),
+                _ = async { tokio::time::sleep_until(uploaded.await + limit).await } => None,
+            },
+            None => Some(send.await),
+        };
+        let Some(sent) = sent else {
+            // The server may only be slow on a huge prompt- another server would have to start over, so no retry
+            let what = Stall::FirstByte.describe(first_byte_timeout.unwrap_or_default());
+            demote(&servers, &queue, &key, &what, None);
+            return Ok(Response::builder()
+                .status(StatusCode::GATEWAY_TIMEOUT)
+                .body(Body::from(format!("Ollama server {}", what)))
+                .unwrap());
+        };
+        match sent {
             Ok(response) => {
+                if !digests.is_empty() {
+                    blob_pins.lock().unwrap().pin(&digests, &key, remote_addr.ip());
+                }
                 let status = response.status();
-                let mut resp_builder = Response::builder().status(u16::from(status));
+                let resp_builder = proxy::response_headers(Response::builder().status(u16::from(status)), response.headers());
 
-                // Copy headers
-                for (key_h, value) in response.headers() {
-                    resp_builder = resp_builder.header(key_h.to_string(), value.to_str().unwrap());
-                }
+                // Only a successful reply ends up in the server's KV cache
+                let content_length = response.content_length();
+                let pending_prompt = prompt.filter(|_| status.is_success())
+                    .and_then(|prompt| affinity::PendingPrompt::new(&path, prompt, content_length));
 
                 // Wrap the response body stream with our custom stream.
                 // The purpose of our custom stream as opposed to directly using response.bytes_stream()
                 // is so we can keep track of the stream lifetime- to mark the server as available once again.
                 let resp_body = ResponseBodyWithGuard {
                     stream: response.bytes_stream(),
-                    _guard,
+                    guard,
                     servers: servers.clone(),
                     key: key.clone(),
                     had_error: false,
+                    pending_prompt,
+                    // What's left of the wait for the first byte
+                    silence: first_byte_timeout.map(|limit| {
+                        let uploaded_at = request_body.uploaded_at(sent_at).unwrap_or_else(tokio::time::Instant::now);
+                        Box::pin(tokio::time::sleep_until(uploaded_at + limit))
+                    }),
+                    stall: Stall::FirstByte,
+                    first_byte_timeout,
+                    chunk_timeout,
+                    timed_out: false,
                 };
 
                 // Convert our custom stream to hyper::Body
@@ -235,69 +722,165 @@ async fn handle_request(
 
                 let response = resp_builder.body(hyper_body).unwrap();
 
-                Ok(response)
+                return Ok(response);
+            }
+            Err(e) if request_body.upload_failed() => {
+                println!("💔 Client {}'s request body broke off on its way to server {} ({}). Error: {}", remote_addr, key, name, e);
+                return Ok(Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .body(Body::from(format!("Failed to read request body: {}", e)))
+                    .unwrap());
             }
             Err(e) => {
-                {
-                    let mut servers_lock = servers.lock().unwrap();
-                    if let Some(server) = servers_lock.get_mut(&key) {
-                        if matches!(server.state.failure_record, FailureRecord::Reliable) {
-                            server.state.failure_record = FailureRecord::Unreliable;
-                            println!("⛔😱 Server {} ({}) didn't respond, now marked Unreliable. Error: {}", key, server.name, e);
-                        }
-                        else {
-                            server.state.failure_record = FailureRecord::SecondChanceGiven;
-                            println!("⛔😞 Unreliable server {} ({}) didn't respond. Error: {}", key, server.name, e);
-                        }
-                        print_server_statuses(&servers_lock);
-                    }
-                }
-
-                // Return an error to the client
-                let response = Response::builder()
-                    .status(StatusCode::BAD_GATEWAY)
-                    .body(Body::from(format!("Error connecting to Ollama server: {}", e)))
-                    .unwrap();
-                Ok(response)
+                demote(&servers, &queue, &key, "didn't respond", Some(&e));
+                last_error = Some(format!("Error connecting to Ollama server: {}", e));
             }
         }
-    } else {
-        println!("🤷 No available servers to serve client {}", remote_addr);
-        {
-            // Print server statuses after failure to find a server
-            let servers_lock = servers.lock().unwrap();
-            print_server_statuses(&servers_lock);
+
+        // The body is gone with the failed attempt
+        if !request_body.can_retry() {
+            break;
         }
-        let response = Response::builder()
+        queued_request.excluded.push(key);
+        if attempt < config.max_attempts {
+            if !can_retry_elsewhere(&servers, &queued_request) {
+                println!("🤷 No other server to retry client {} on", remote_addr);
+                break;
+            }
+            println!("🔁 Retrying client {} on another server (attempt {} of {})", remote_addr, attempt + 1, config.max_attempts);
+        }
+    }
+
+    let response = match last_error {
+        // Return an error to the client
+        Some(error) => Response::builder()
+            .status(StatusCode::BAD_GATEWAY)
+            .body(Body::from(error))
+            .unwrap(),
+        None => Response::builder()
             .status(StatusCode::SERVICE_UNAVAILABLE)
             .body(Body::from("No available servers"))
-            .unwrap();
-        Ok(response)
-    }
+            .unwrap(),
+    };
+    Ok(response)
 }
 
-async fn select_available_server(servers: &SharedServerList, remote_addr: &std::net::SocketAddr) -> Option<String> {
-    let mut servers_lock = servers.lock().unwrap();
+/// The client for all requests to one server.
+///
+/// Low value for connect timeout, to get an immediate error if the Ollama server isn't even running.
+/// Even if the Ollama server takes its time, it should still be able to immediately facilitate a TCP connection with us.
+/// Waiting for the response is timed per request, see `--timeout`, `--first-byte-timeout` and `--timeout-rule`.
+fn build_server_client() -> reqwest::Result<reqwest::Client> {
+    reqwest::Client::builder()
+        .connect_timeout(std::time::Duration::from_secs(1))
+        .pool_idle_timeout(std::time::Duration::from_secs(POOL_IDLE_TIMEOUT_SECS))
+        .build()
+}
+
+/// Whether a server that wasn't tried yet, busy or not, may be able to serve the request
+fn can_retry_elsewhere(servers: &SharedServerList, request: &queue::QueuedRequest) -> bool {
+    let servers_lock = servers.lock().unwrap();
+    servers_lock.iter()
+        .filter(|(key, _)| !request.excluded.contains(key) && request.pinned.as_ref().is_none_or(|pinned| pinned == *key))
+        .any(|(_, server)| request.model.as_deref().is_none_or(|model| inventory::server_has_model(server, model)))
+}
+
+/// Takes a slot of the chosen server and returns its key, `None` if no suitable server has a free slot.
+/// When the request has a model, only servers that have that model installed are considered.
+/// Servers the request already failed on are skipped.
+/// Servers whose KV cache type suits the model are preferred. If none does, a server whose KV cache type
+/// we can change is chosen and marked `reconfiguring`, and the type to switch it to is returned along with its key.
+/// When the request has a prompt, a server that already has its beginning in the KV cache is preferred.
+fn select_server(
+    servers_lock: &mut OrderMap<String, OllamaServer>,
+    request: &queue::QueuedRequest,
+    waited: std::time::Duration,
+    selection: &SelectionConfig,
+) -> Option<(String, Option<kv_cache::KvCacheType>)> {
+    let remote_addr = &request.remote_addr;
+    let model = request.model.as_deref();
+    let prompt = request.prompt.as_ref();
+
+    let has_model = |key: &String, server: &OllamaServer| !request.excluded.contains(key) && request.pinned.as_ref().is_none_or(|pinned| pinned == key) && match model {
+        Some(model) => inventory::server_has_model(server, model),
+        None => true,
+    };
+
+    let wanted_kv_cache_type = model.map(|model| kv_cache::required_kv_cache_type(model, &selection.kv_q8_models));
+    let kv_cache_matches = |server: &OllamaServer| wanted_kv_cache_type.is_none_or(|wanted| server.kv_cache_type == wanted);
+    // A server of the other KV cache type would have to be restarted or serve the model with the wrong type,
+    // so while one of the right type is only busy, wait for it a while (`--reconfigure-wait`)
+    let hold_out = waited < selection.reconfigure_wait
+        && servers_lock.iter().any(|(key, server)| has_model(key, server) && kv_cache_matches(server) && !server.state.reconfiguring);
+    let can_serve = |key: &String, server: &OllamaServer| has_model(key, server) && (!hold_out || kv_cache_matches(server));
+    // Restarting Ollama would kill the requests it's handling
+    let can_reconfigure = |server: &OllamaServer| server.kv_cache_controllable && server.state.busy_slots == 0;
+    // The KV cache type to switch the server to before it can serve the request
+    let reconfigure_to = |server: &OllamaServer| wanted_kv_cache_type.filter(|&wanted| server.kv_cache_type != wanted && can_reconfigure(server));
+
+    // Loading a large model into VRAM can take up to a minute, so among equally reliable servers
+    // of the same capability tier prefer the ones that already have the model loaded.
+    let is_hot = |server: &OllamaServer| match model {
+        Some(model) => inventory::server_has_model_loaded(server, model),
+        None => false,
+    };
+    let shared_prefix = |server: &OllamaServer| match (prompt, &server.cached_prompt) {
+        (Some(incoming), Some(cached)) => affinity::shared_prefix(cached, incoming, selection.min_shared_prefix as usize),
+        _ => 0,
+    };
+    let pick_details = |server: &OllamaServer| {
+        let temperature = match (model, is_hot(server)) {
+            (None, _) => return String::new(),
+            (Some(_), true) => "hot, model already loaded",
+            (Some(_), false) => "cold, model not loaded",
+        };
+        let mut details = vec![temperature.to_string()];
+        if let Some(wanted) = reconfigure_to(server) {
+            details.push(format!("KV cache is {}, switching it to {} for the model", server.kv_cache_type, wanted));
+        }
+        else if let Some(wanted) = wanted_kv_cache_type.filter(|&wanted| server.kv_cache_type != wanted) {
+            details.push(format!("KV cache is {} but the model wants {}", server.kv_cache_type, wanted));
+        }
+        if let (Some(prompt), shared) = (prompt, shared_prefix(server)) {
+            if shared > 0 {
+                details.push(affinity::describe_shared(prompt, shared));
+            }
+        }
+        format!(" ({})", details.join(", "))
+    };
+    // Takes a slot of the chosen server. A new prompt for the same model is about to replace its KV cache.
+    // Also marks it reconfiguring if its KV cache type has to be switched first.
+    let occupy = |server: &mut OllamaServer| {
+        let kv_cache_type = reconfigure_to(server);
+        server.state.busy_slots += 1;
+        if prompt.is_some_and(|prompt| server.cached_prompt.as_ref().is_some_and(|cached| cached.model() == prompt.model())) {
+            server.cached_prompt = None;
+        }
+        server.state.reconfiguring = kv_cache_type.is_some();
+        kv_cache_type
+    };
 
     // Define the closure to encapsulate server selection logic
     let mut select_server = || {
         // 1st choice: Find an available reliable server
-        for (key, server) in servers_lock.iter_mut() {
-            if matches!(server.state.failure_record, FailureRecord::Reliable) && !server.state.busy {
-                server.state.busy = true;
-                println!("🤖🦸 Chose reliable server: {} ({}) to serve client {}", key, server.name, remote_addr);
-                return Some(key.clone());
-            }
+        let reliable = |key: &String, server: &OllamaServer| matches!(server.state.failure_record, FailureRecord::Reliable) && server.state.is_available() && can_serve(key, server);
+        if let Some(key) = choose_server(servers_lock, reliable, kv_cache_matches, can_reconfigure, is_hot, shared_prefix) {
+            let server = servers_lock.get_mut(&key).unwrap();
+            let details = pick_details(server);
+            let reconfigure = occupy(server);
+            println!("🤖🦸 Chose reliable server: {} ({}) to serve client {}{}", key, server.name, remote_addr, details);
+            return Some((key, reconfigure));
         }
 
         // 2nd choice: If no reliable servers are available, select an untrusted available server that has
         // only failed once in a row.
-        for (key, server) in servers_lock.iter_mut() {
-            if matches!(server.state.failure_record, FailureRecord::Unreliable) && !server.state.busy {
-                server.state.busy = true;
-                println!("🤖😇 Giving server {} ({}) another chance with client {}", key, server.name, remote_addr);
-                return Some(key.clone());
-            }
+        let unreliable = |key: &String, server: &OllamaServer| matches!(server.state.failure_record, FailureRecord::Unreliable) && server.state.is_available() && can_serve(key, server);
+        if let Some(key) = choose_server(servers_lock, unreliable, kv_cache_matches, can_reconfigure, is_hot, shared_prefix) {
+            let server = servers_lock.get_mut(&key).unwrap();
+            let details = pick_details(server);
+            let reconfigure = occupy(server);
+            println!("🤖😇 Giving server {} ({}) another chance with client {}{}", key, server.name, remote_addr, details);
+            return Some((key, reconfigure));
         }
 
         // If all untrusted available servers have been given a second chance,
@@ -305,49 +888,142 @@ async fn select_available_server(servers: &SharedServerList, remote_addr: &std::
         // This ensures that we cycle equally through all untrusted servers- give everyone
         // their chance
         for server in servers_lock.values_mut() {
-            if matches!(server.state.failure_record, FailureRecord::SecondChanceGiven) && !server.state.busy {
+            if matches!(server.state.failure_record, FailureRecord::SecondChanceGiven) && server.state.is_available() {
                 server.state.failure_record = FailureRecord::Unreliable;
             }
         }
 
         // 3rd choice: Select any untrusted server, because we're out of options at this point
-        for (key, server) in servers_lock.iter_mut() {
-            if matches!(server.state.failure_record, FailureRecord::Unreliable) && !server.state.busy {
-                server.state.busy = true;
-                println!("🤖😇 Giving server {} ({}) a 3rd+ chance with client {}", key, server.name, remote_addr);
-                return Some(key.clone());
-            }
+        if let Some(key) = choose_server(servers_lock, unreliable, kv_cache_matches, can_reconfigure, is_hot, shared_prefix) {
+            let server = servers_lock.get_mut(&key).unwrap();
+            let details = pick_details(server);
+            let reconfigure = occupy(server);
+            println!("🤖😇 Giving server {} ({}) a 3rd+ chance with client {}{}", key, server.name, remote_addr, details);
+            return Some((key, reconfigure));
         }
 
         // No servers available
         None
     };
 
-    // Capture the result of the closure
-    let selected_server = select_server();
+    select_server()
+}
+
+/// Picks among the `eligible` servers, narrowing down step by step:
+/// suitable KV cache type (`kv_cache_matches`) → longest `shared_prefix` of the prompt already in the KV cache
+/// → lowest capability tier → model already loaded (`is_hot`) → fewest busy slots → highest speed → first in CLI order.
+///
+/// If no candidate has a suitable KV cache type, the fastest one of the lowest tier whose type can be switched
+/// (`can_reconfigure`) is picked.
+fn choose_server(
+    servers: &OrderMap<String, OllamaServer>,
+    eligible: impl Fn(&String, &OllamaServer) -> bool,
+    kv_cache_matches: impl Fn(&OllamaServer) -> bool,
+    can_reconfigure: impl Fn(&OllamaServer) -> bool,
+    is_hot: impl Fn(&OllamaServer) -> bool,
+    shared_prefix: impl Fn(&OllamaServer) -> usize,
+) -> Option<String> {
+    let mut candidates: Vec<(&String, &OllamaServer)> = servers.iter().filter(|(key, server)| eligible(key, server)).collect();
+
+    // Any server that's ready for the model beats restarting one, whatever its tier
+    if candidates.iter().any(|(_, server)| kv_cache_matches(server)) {
+        candidates.retain(|(_, server)| kv_cache_matches(server));
+    }
+    else if candidates.iter().any(|(_, server)| can_reconfigure(server)) {
+        // The restart unloads every model and empties the KV cache, so hot models and affinity don't matter
+        candidates.retain(|(_, server)| can_reconfigure(server));
+        retain_lowest_capability(&mut candidates);
+        return fastest(candidates);
+    }
+
+    // A server holding part of the conversation has the model loaded by definition, even if the last poll
+    // of `/api/ps` didn't see it, and reusing its KV cache beats saving it for bigger models
+    let most_shared = candidates.iter().map(|(_, server)| shared_prefix(server)).max()?;
+    candidates.retain(|(_, server)| shared_prefix(server) == most_shared);
+
+    retain_lowest_capability(&mut candidates);
 
-    print_server_statuses(&servers_lock);
+    if candidates.iter().any(|(_, server)| is_hot(server)) {
+        candidates.retain(|(_, server)| is_hot(server));
+    }
+
+    // A server splitting its GPU between fewer requests answers sooner
+    let fewest_busy = candidates.iter().map(|(_, server)| server.state.busy_slots).min()?;
+    candidates.retain(|(_, server)| server.state.busy_slots == fewest_busy);
+
+    fastest(candidates)
+}
+
+/// Save the high capability servers for the models that only they have
+fn retain_lowest_capability(candidates: &mut Vec<(&String, &OllamaServer)>) {
+    if let Some(lowest_capability) = candidates.iter().map(|(_, server)| server.capability).min() {
+        candidates.retain(|(_, server)| server.capability == lowest_capability);
+    }
+}
 
-    selected_server
+/// The fastest of the candidates, the first in CLI order among equally fast ones
+fn fastest(candidates: Vec<(&String, &OllamaServer)>) -> Option<String> {
+    let highest_speed = candidates.iter().map(|(_, server)| server.speed).max()?;
+    candidates.into_iter()
+        .find(|(_, server)| server.speed == highest_speed)
+        .map(|(key, _)| key.clone())
 }
 
+/// A server slot taken for a request
 struct ServerGuard {
     servers: SharedServerList,
+    queue: queue::SharedQueue,
     key: String,
+    /// Who the slot was taken for, with `--fairness` only
+    client: Option<String>,
+    /// The KV cache type to switch the server to before sending it the request, taken when the switch starts.
+    /// The server was marked `reconfiguring` for it, a guard dropped before that has to clear the mark.
+    reconfigure_to: Option<kv_cache::KvCacheType>,
 }
 
 impl Drop for ServerGuard {
     fn drop(&mut self) {
         let mut servers_lock = self.servers.lock().unwrap();
+        let queued = queue::finished(&self.queue, &self.client);
         if let Some(server) = servers_lock.get_mut(&self.key) {
-            server.state.busy = false;
+            server.state.busy_slots = server.state.busy_slots.saturating_sub(1);
+            if let Some(kv_cache_type) = self.reconfigure_to {
+                server.state.reconfiguring = false;
+                println!("🔧 Server {} ({}) won't be switched to {} KV cache, its request is gone", self.key, server.name, kv_cache_type);
+            }
             if matches!(server.state.failure_record, FailureRecord::Reliable) {
                 println!("🟢 Server {} ({}) now available", self.key, server.name);
             }
             else {
                 println!("⚠️  Connection closed with Unreliable Server {} ({})", self.key, server.name);
             }
-            print_server_statuses(&servers_lock);
+            print_server_statuses(&servers_lock, queued);
+        }
+        drop(servers_lock);
+        // The freed slot goes to the request whose turn it is
+        queue::dispatch(&self.servers, &self.queue);
+    }
+}
+
+/// `None` for 0, which disables the timeout
+fn timeout_from_secs(secs: u32) -> Option<std::time::Duration> {
+    (secs > 0).then(|| std::time::Duration::from_secs(secs.into()))
+}
+
+/// What a server was doing when it stopped sending
+#[derive(Clone, Copy, Debug)]
+enum Stall {
+    /// Sent the headers but not the first chunk- still loading the model or ingesting the prompt
+    FirstByte,
+    /// Went silent in the middle of the response- frozen VM, crashed GPU driver
+    BetweenChunks,
+}
+
+impl Stall {
+    fn describe(self, limit: std::time::Duration) -> String {
+        match self {
+            Stall::FirstByte => format!("sent no response within {} seconds", limit.as_secs()),
+            Stall::BetweenChunks => format!("went silent for {} seconds mid-response", limit.as_secs()),
         }
     }
 }
@@ -355,10 +1031,33 @@ impl Drop for ServerGuard {
 // Custom stream that holds the guard
 struct ResponseBodyWithGuard<S> {
     stream: S,
-    _guard: ServerGuard,
+    guard: ServerGuard,
     servers: SharedServerList,
     key: String,
     had_error: bool,
+    /// Set for chat and completion requests, to remember the prompt once the reply is done
+    pending_prompt: Option<affinity::PendingPrompt>,
+    /// Fires when the server took too long to send the next chunk, `None` without a timeout
+    silence: Option<Pin<Box<tokio::time::Sleep>>>,
+    /// Which timeout `silence` is counting down
+    stall: Stall,
+    first_byte_timeout: Option<std::time::Duration>,
+    chunk_timeout: Option<std::time::Duration>,
+    timed_out: bool,
+}
+
+impl<S> ResponseBodyWithGuard<S> {
+    /// Remembers the prompt as what's in the server's KV cache, once the whole reply is in
+    fn cache_prompt_if_done(&mut self) {
+        if !self.pending_prompt.as_ref().is_some_and(affinity::PendingPrompt::is_done) {
+            return;
+        }
+        let cached_prompt = self.pending_prompt.take().map(affinity::PendingPrompt::finish);
+        let mut servers_lock = self.servers.lock().unwrap();
+        if let Some(server) = servers_lock.get_mut(&self.key) {
+            server.cached_prompt = cached_prompt;
+        }
+    }
 }
 
 impl<S> Stream for ResponseBodyWithGuard<S>
@@ -371,30 +1070,35 @@ where
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<Option<Self::Item>> {
+        // Already gave up on the server
+        if self.timed_out {
+            return Poll::Ready(None);
+        }
         let stream = Pin::new(&mut self.stream);
         match stream.poll_next(cx) {
-            Poll::Ready(Some(Ok(bytes))) => Poll::Ready(Some(Ok(bytes))),
+            Poll::Ready(Some(Ok(bytes))) => {
+                self.stall = Stall::BetweenChunks;
+                self.silence = self.chunk_timeout.map(|limit| Box::pin(tokio::time::sleep(limit)));
+                if let Some(pending_prompt) = &mut self.pending_prompt {
+                    pending_prompt.feed(&bytes);
+                }
+                // Don't wait for the end of the stream- when the response has a Content-Length,
+                // hyper stops polling once it has written that many bytes.
+                self.cache_prompt_if_done();
+                Poll::Ready(Some(Ok(bytes)))
+            },
             Poll::Ready(Some(Err(e))) => {
                 // An error occurred during streaming
                 self.had_error = true; // Mark that an error has occurred
-                {
-                    let mut servers_lock = self.servers.lock().unwrap();
-                    if let Some(server) = servers_lock.get_mut(&self.key) {
-                        if matches!(server.state.failure_record, FailureRecord::Reliable) {
-                            server.state.failure_record = FailureRecord::Unreliable;
-                            println!("⛔😱 Server {} ({}) failed during streaming, now marked Unreliable. Error: {}", self.key, server.name, e);
-                        }
-                        else {
-                            server.state.failure_record = FailureRecord::SecondChanceGiven;
-                            println!("⛔😞 Unreliable server {} ({}) failed during streaming. Error: {}", self.key, server.name, e);
-                        }
-                        print_server_statuses(&servers_lock);
-                    }
-                }
+                demote(&self.servers, &self.guard.queue, &self.key, "failed during streaming", Some(&e.to_string()));
                 // Return the error to the client
-                Poll::Ready(Some(Err(std::io::Error::new(std::io::ErrorKind::Other, e))))
+                Poll::Ready(Some(Err(std::io::Error::other(e))))
             },
             Poll::Ready(None) => {
+                if let Some(pending_prompt) = &mut self.pending_prompt {
+                    pending_prompt.end();
+                }
+                self.cache_prompt_if_done();
                 if !self.had_error {
                     // Streaming ended successfully
                     // Mark the server as Reliable
@@ -403,13 +1107,30 @@ where
                         if !matches!(server.state.failure_record, FailureRecord::Reliable) {
                             server.state.failure_record = FailureRecord::Reliable;
                             println!("🙏⚕️  Server {} ({}) has completed streaming successfully and is now marked Reliable", self.key, server.name);
-                            print_server_statuses(&servers_lock);
+                            print_server_statuses(&servers_lock, queue::queued(&self.guard.queue));
                         }
                     }
                 }
                 Poll::Ready(None)
             },
-            Poll::Pending => Poll::Pending,
+            Poll::Pending => {
+                let Some(silence) = &mut self.silence else {
+                    return Poll::Pending;
+                };
+                if silence.as_mut().poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+                // The server took too long, the client gets an error instead of waiting forever
+                self.had_error = true;
+                self.timed_out = true;
+                let limit = match self.stall {
+                    Stall::FirstByte => self.first_byte_timeout,
+                    Stall::BetweenChunks => self.chunk_timeout,
+                }.unwrap_or_default();
+                let what = self.stall.describe(limit);
+                demote(&self.servers, &self.guard.queue, &self.key, &what, None);
+                Poll::Ready(Some(Err(std::io::Error::new(std::io::ErrorKind::TimedOut, format!("Ollama server {}", what)))))
+            },
         }
     }
 }
@@ -418,21 +1139,45 @@ where
 // reqwest is a new version, hyper is an old version and the new API is completely
 // different so for now I chose to stay with the old version of hyper.
 fn hyper_method_to_reqwest_method(method: hyper::Method) -> Result<reqwest::Method, Box<dyn std::error::Error>> {
-    return Ok(method.as_str().parse::<reqwest::Method>()?);
+    Ok(method.as_str().parse::<reqwest::Method>()?)
+}
+
+/// Marks a server that just failed Unreliable, or SecondChanceGiven if it already was.
+/// `reason` completes "Server ... ", e.g. "didn't respond".
+fn demote(servers: &SharedServerList, queue: &queue::SharedQueue, key: &str, reason: &str, error: Option<&str>) {
+    let mut servers_lock = servers.lock().unwrap();
+    let Some(server) = servers_lock.get_mut(key) else {
+        return;
+    };
+    let error = error.map(|e| format!(". Error: {}", e)).unwrap_or_default();
+    if matches!(server.state.failure_record, FailureRecord::Reliable) {
+        server.state.failure_record = FailureRecord::Unreliable;
+        println!("⛔😱 Server {} ({}) {}, now marked Unreliable{}", key, server.name, reason, error);
+    }
+    else {
+        server.state.failure_record = FailureRecord::SecondChanceGiven;
+        println!("⛔😞 Unreliable server {} ({}) {}{}", key, server.name, reason, error);
+    }
+    print_server_statuses(&servers_lock, queue::queued(queue));
 }
 
-/// Prints a nicely formatted list of the servers, their name, busy status, and reliability.
-fn print_server_statuses(servers: &OrderMap<String, OllamaServer>) {
+/// Prints a nicely formatted list of the servers, their name, busy slots, and reliability,
+/// followed by the number of requests waiting for a server.
+fn print_server_statuses(servers: &OrderMap<String, OllamaServer>, queued: usize) {
     println!("🗒  Current server statuses:");
     for (i, (address, srv)) in servers.iter().enumerate() {
-        let busy_status = if srv.state.busy { "Busy" } else { "Available" };
+        let busy_status = if srv.state.reconfiguring {
+            "Reconfiguring KV cache".to_string()
+        } else {
+            format!("{}/{} busy", srv.state.busy_slots, srv.state.slots)
+        };
         let reliability = match srv.state.failure_record {
             FailureRecord::Reliable => "Reliable",
             FailureRecord::Unreliable => "Unreliable",
             FailureRecord::SecondChanceGiven => "SecondChanceGiven",
         };
         println!(
-            "{}. Address: {} ({}), Busy: {}, Reliability: {}",
+            "{}. Address: {} ({}), {}, Reliability: {}",
             i + 1,
             address,
             srv.name,
@@ -440,5 +1185,8 @@ fn print_server_statuses(servers: &OrderMap<String, OllamaServer>) {
             reliability
         );
     }
-    println!("");
+    if queued > 0 {
+        println!("⏳ Waiting for a server: {} request(s)", queued);
+    }
+    println!();
 }
@@ -0,0 +1,80 @@
+//! Lightweight metadata requests that don't need an inference slot.
+//!
+//! `GET /`, `GET /api/version`, `POST /api/show` and CORS `OPTIONS` preflights are answered by Ollama
+//! in milliseconds, right alongside running generations. They're proxied to the first server that answers,
+//! without occupying a slot or waiting in line- and without a failure counting against the server's reliability.
+
+use std::time::Duration;
+
+use hyper::{Body, Method, Request, Response, StatusCode};
+
+use crate::{inference, inventory, proxy, FailureRecord, SharedServerList};
+
+/// Max seconds to wait for a server to answer a metadata request before trying the next one
+const METADATA_TIMEOUT_SECS: u64 = 10;
+
+pub fn is_metadata_request(method: &Method, path: &str) -> bool {
+    matches!(
+        (method, path),
+        (&Method::GET, "/") | (&Method::HEAD, "/")
+            | (&Method::GET, "/api/version") | (&Method::HEAD, "/api/version")
+            | (&Method::POST, "/api/show")
+            | (&Method::OPTIONS, _)
+    )
+}
+
+/// Proxies the request to the servers one by one until one of them answers.
+/// For `POST /api/show`, servers that have the model are asked first.
+pub async fn forward(req: Request<Body>, method: reqwest::Method, servers: &SharedServerList, remote_addr: std::net::SocketAddr) -> Response<Body> {
+    let (parts, body) = req.into_parts();
+    let body = match hyper::body::to_bytes(body).await {
+        Ok(body) => body,
+        Err(e) => {
+            return Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::from(format!("Failed to read request body: {}", e)))
+                .unwrap();
+        }
+    };
+    // Older clients send the model of `/api/show` as "name"
+    let model = serde_json::from_slice::<serde_json::Value>(&body).ok().and_then(|json| {
+        inference::extract_model(&json).or_else(|| json.get("name")?.as_str().map(String::from))
+    });
+
+    // Servers that have the model first, then reliable ones, otherwise in CLI order
+    let candidates: Vec<(String, String, reqwest::Client)> = {
+        let servers_lock = servers.lock().unwrap();
+        let mut candidates: Vec<_> = servers_lock.iter()
+            .filter(|(_, server)| !server.state.reconfiguring)
+            .collect();
+        candidates.sort_by_key(|(_, server)| (
+            !model.as_deref().is_none_or(|model| inventory::server_has_model(server, model)),
+            !matches!(server.state.failure_record, FailureRecord::Reliable),
+        ));
+        candidates.into_iter().map(|(key, server)| (key.clone(), server.name.clone(), server.client.clone())).collect()
+    };
+
+    let upstream_headers = proxy::request_headers(&parts.headers, remote_addr);
+
+    let mut last_error = String::from("no server to ask");
+    for (key, name, client) in candidates {
+        let request_builder = client.request(method.clone(), proxy::upstream_url(&key, &parts.uri))
+            .headers(upstream_headers.clone())
+            .timeout(Duration::from_secs(METADATA_TIMEOUT_SECS));
+        match request_builder.body(body.clone()).send().await {
+            Ok(response) => {
+                let resp_builder = proxy::response_headers(Response::builder().status(u16::from(response.status())), response.headers());
+                return resp_builder.body(Body::wrap_stream(response.bytes_stream())).unwrap();
+            }
+            Err(e) => {
+                println!("📭 Server {} ({}) didn't answer {} {} for client {}, trying the next one. Error: {}", key, name, parts.method, parts.uri.path(), remote_addr, e);
+                last_error = e.to_string();
+            }
+        }
+    }
+
+    Response::builder()
+        .status(StatusCode::BAD_GATEWAY)
+        .body(Body::from(format!("Error connecting to Ollama server: {}", last_error)))
+        .unwrap()
+}
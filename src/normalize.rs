@@ -0,0 +1,449 @@
+//! One internal representation for chats sent through any of the APIs that Ollama serves.
+//!
+//! Ollama converts OpenAI `/v1/chat/completions` and Anthropic `/v1/messages` requests to its native
+//! chat format before applying the prompt template, so the same conversation hits the same KV cache
+//! no matter which API carries it. We compare conversations in that native shape: messages made of
+//! `role`, `content`, `images`, `tool_calls` and `tool_call_id`, where tool call arguments
+//! are JSON objects and empty fields are left out.
+//! Thinking is left out too: clients rarely send it back, and the prompt templates drop it from earlier turns.
+
+use std::collections::BTreeMap;
+
+use serde_json::{json, Value};
+
+/// A chat request, in the native shape regardless of the API it came through.
+pub struct Chat {
+    pub messages: Vec<Value>,
+    pub tools: Value,
+    pub num_ctx: Value,
+}
+
+/// `None` for endpoints that aren't chats, or bodies that don't look like one.
+pub fn normalize_request(path: &str, json: &Value) -> Option<Chat> {
+    match path {
+        "/api/chat" => ollama_request(json),
+        "/v1/chat/completions" => openai_request(json),
+        "/v1/messages" => anthropic_request(json),
+        _ => None,
+    }
+}
+
+#[derive(Default)]
+struct Message {
+    role: String,
+    content: String,
+    images: Vec<Value>,
+    tool_calls: Vec<Value>,
+    tool_call_id: String,
+}
+
+impl Message {
+    fn new(role: &str) -> Self {
+        Self { role: role.to_string(), ..Default::default() }
+    }
+
+    fn into_json(self) -> Value {
+        let mut message = json!({ "role": self.role, "content": self.content });
+        if !self.images.is_empty() {
+            message["images"] = Value::Array(self.images);
+        }
+        if !self.tool_calls.is_empty() {
+            message["tool_calls"] = Value::Array(self.tool_calls);
+        }
+        if !self.tool_call_id.is_empty() {
+            message["tool_call_id"] = Value::String(self.tool_call_id);
+        }
+        message
+    }
+}
+
+fn str_field<'a>(json: &'a Value, field: &str) -> &'a str {
+    json.get(field).and_then(Value::as_str).unwrap_or_default()
+}
+
+fn array_field<'a>(json: &'a Value, field: &str) -> &'a [Value] {
+    json.get(field).and_then(Value::as_array).map(Vec::as_slice).unwrap_or_default()
+}
+
+/// Tool call in the native shape. OpenAI sends the arguments as a JSON string, the others as an object.
+fn tool_call(name: &str, arguments: &Value) -> Value {
+    let arguments = match arguments {
+        Value::String(text) if text.trim().is_empty() => json!({}),
+        Value::String(text) => serde_json::from_str(text).unwrap_or_else(|_| arguments.clone()),
+        Value::Null => json!({}),
+        _ => arguments.clone(),
+    };
+    json!({ "function": { "name": name, "arguments": arguments } })
+}
+
+/// `[]` and a missing `tools` field mean the same
+fn tools_or_null(tools: Vec<Value>) -> Value {
+    if tools.is_empty() { Value::Null } else { Value::Array(tools) }
+}
+
+/// Ollama wants raw base64 in `images`, the other APIs may wrap it in a data URL
+fn image(url_or_data: &str) -> Value {
+    match url_or_data.split_once(";base64,") {
+        Some((prefix, data)) if prefix.starts_with("data:") => Value::String(data.to_string()),
+        _ => Value::String(url_or_data.to_string()),
+    }
+}
+
+fn ollama_request(json: &Value) -> Option<Chat> {
+    let messages = json.get("messages")?.as_array()?.iter().map(|message| {
+        let function_call = |call: &Value| {
+            let function = call.get("function").unwrap_or(&Value::Null);
+            tool_call(str_field(function, "name"), function.get("arguments").unwrap_or(&Value::Null))
+        };
+        Message {
+            role: str_field(message, "role").to_string(),
+            content: str_field(message, "content").to_string(),
+            images: array_field(message, "images").iter().filter_map(Value::as_str).map(image).collect(),
+            tool_calls: array_field(message, "tool_calls").iter().map(function_call).collect(),
+            tool_call_id: str_field(message, "tool_call_id").to_string(),
+        }.into_json()
+    }).collect();
+    Some(Chat {
+        messages,
+        tools: tools_or_null(array_field(json, "tools").to_vec()),
+        num_ctx: json.get("options").and_then(|options| options.get("num_ctx")).cloned().unwrap_or_default(),
+    })
+}
+
+fn openai_request(json: &Value) -> Option<Chat> {
+    let messages = json.get("messages")?.as_array()?.iter().map(|message| {
+        let role = match str_field(message, "role") {
+            "developer" => "system",
+            role => role,
+        };
+        let mut normalized = Message::new(role);
+        match message.get("content") {
+            Some(Value::String(text)) => normalized.content = text.clone(),
+            Some(Value::Array(parts)) => {
+                let mut texts = Vec::new();
+                for part in parts {
+                    match str_field(part, "type") {
+                        "text" => texts.push(str_field(part, "text")),
+                        "image_url" => {
+                            let url = part.get("image_url").map(|image_url| match image_url {
+                                Value::String(url) => url.as_str(),
+                                _ => str_field(image_url, "url"),
+                            });
+                            normalized.images.push(image(url.unwrap_or_default()));
+                        }
+                        _ => {}
+                    }
+                }
+                normalized.content = texts.join("\n");
+            }
+            _ => {}
+        }
+        normalized.tool_calls = array_field(message, "tool_calls").iter().map(|call| {
+            let function = call.get("function").unwrap_or(&Value::Null);
+            tool_call(str_field(function, "name"), function.get("arguments").unwrap_or(&Value::Null))
+        }).collect();
+        normalized.tool_call_id = str_field(message, "tool_call_id").to_string();
+        normalized.into_json()
+    }).collect();
+    Some(Chat {
+        messages,
+        tools: tools_or_null(array_field(json, "tools").to_vec()),
+        num_ctx: Value::Null,
+    })
+}
+
+fn anthropic_request(json: &Value) -> Option<Chat> {
+    let mut messages = Vec::new();
+
+    let system = match json.get("system") {
+        Some(Value::String(text)) => text.clone(),
+        Some(Value::Array(blocks)) => blocks.iter().map(|block| str_field(block, "text")).collect::<Vec<_>>().join("\n"),
+        _ => String::new(),
+    };
+    if !system.is_empty() {
+        messages.push(Message { content: system, ..Message::new("system") }.into_json());
+    }
+
+    for message in json.get("messages")?.as_array()? {
+        let role = str_field(message, "role");
+        let blocks = match message.get("content") {
+            Some(Value::String(text)) => vec![json!({ "type": "text", "text": text })],
+            Some(Value::Array(blocks)) => blocks.clone(),
+            _ => Vec::new(),
+        };
+
+        // Tool results become "tool" messages of their own, ahead of the rest of the turn
+        let mut normalized = Message::new(role);
+        let mut texts = Vec::new();
+        for block in &blocks {
+            match str_field(block, "type") {
+                "text" => texts.push(str_field(block, "text")),
+                "image" => {
+                    let source = block.get("source").unwrap_or(&Value::Null);
+                    let data = match str_field(source, "type") {
+                        "url" => str_field(source, "url"),
+                        _ => str_field(source, "data"),
+                    };
+                    normalized.images.push(image(data));
+                }
+                "tool_use" => normalized.tool_calls.push(tool_call(str_field(block, "name"), block.get("input").unwrap_or(&Value::Null))),
+                "tool_result" => {
+                    let content = match block.get("content") {
+                        Some(Value::String(text)) => text.clone(),
+                        Some(Value::Array(parts)) => parts.iter().map(|part| str_field(part, "text")).collect::<Vec<_>>().join("\n"),
+                        _ => String::new(),
+                    };
+                    messages.push(Message {
+                        content,
+                        tool_call_id: str_field(block, "tool_use_id").to_string(),
+                        ..Message::new("tool")
+                    }.into_json());
+                }
+                _ => {}
+            }
+        }
+        normalized.content = texts.join("\n");
+        let only_tool_results = !blocks.is_empty() && blocks.iter().all(|block| str_field(block, "type") == "tool_result");
+        if !only_tool_results {
+            messages.push(normalized.into_json());
+        }
+    }
+
+    let tools = array_field(json, "tools").iter().map(|tool| json!({
+        "type": "function",
+        "function": {
+            "name": str_field(tool, "name"),
+            "description": str_field(tool, "description"),
+            "parameters": tool.get("input_schema").cloned().unwrap_or_default(),
+        }
+    })).collect();
+
+    Some(Chat {
+        messages,
+        tools: tools_or_null(tools),
+        num_ctx: Value::Null,
+    })
+}
+
+/// Response framing, decided by the endpoint the request was sent to
+#[derive(Clone, Copy)]
+enum ReplyFormat {
+    /// `/api/chat`, `/api/generate`: NDJSON chunks, or a single JSON object when not streaming
+    Ollama,
+    /// `/v1/chat/completions`, `/v1/completions`: SSE `data:` chunks, or a single JSON object when not streaming
+    OpenAi,
+    /// `/v1/messages`: SSE events, or a single JSON object when not streaming
+    Anthropic,
+}
+
+/// A reply with more than this many bytes without a newline isn't remembered- a non-streaming reply is
+/// a single JSON object, and copying a huge one costs more than the KV cache it would point to saves
+const MAX_PENDING_BYTES: usize = 4 * 1024 * 1024;
+
+/// Collects the assistant reply out of a response body as it streams by- in the native message shape for chats,
+/// as text for completions.
+pub struct ReplyCollector {
+    format: ReplyFormat,
+    /// Bytes of an incomplete line
+    pending: Vec<u8>,
+    /// Bytes of the body still to come, if the server sent a Content-Length
+    remaining: Option<u64>,
+    /// Set once `pending` outgrew `MAX_PENDING_BYTES`- the rest of the body is ignored
+    gave_up: bool,
+    done: bool,
+    content: String,
+    /// By the index the API streams them with: name, and arguments as (possibly still partial) JSON text
+    tool_calls: BTreeMap<u64, (String, String)>,
+    /// The token context `/api/generate` returns in its final chunk
+    context: Value,
+}
+
+impl ReplyCollector {
+    /// `None` for endpoints whose replies we can't read
+    pub fn new(path: &str, content_length: Option<u64>) -> Option<Self> {
+        let format = match path {
+            "/api/chat" | "/api/generate" => ReplyFormat::Ollama,
+            "/v1/chat/completions" | "/v1/completions" => ReplyFormat::OpenAi,
+            "/v1/messages" => ReplyFormat::Anthropic,
+            _ => return None,
+        };
+        Some(Self {
+            format,
+            pending: Vec::new(),
+            remaining: content_length,
+            gave_up: false,
+            done: false,
+            content: String::new(),
+            tool_calls: BTreeMap::new(),
+            context: Value::Null,
+        })
+    }
+
+    pub fn feed(&mut self, bytes: &[u8]) {
+        if self.gave_up {
+            return;
+        }
+        // The pending bytes were already searched for a newline, a long line would otherwise be searched once per chunk
+        let mut searched = self.pending.len();
+        self.pending.extend_from_slice(bytes);
+        while let Some(offset) = self.pending[searched..].iter().position(|&byte| byte == b'\n') {
+            let line: Vec<u8> = self.pending.drain(..=searched + offset).collect();
+            self.feed_line(&line);
+            searched = 0;
+        }
+        if self.pending.len() > MAX_PENDING_BYTES {
+            self.gave_up = true;
+            self.pending = Vec::new();
+            return;
+        }
+        // When the response has a Content-Length, the body may never be polled to its end
+        if let Some(remaining) = &mut self.remaining {
+            *remaining = remaining.saturating_sub(bytes.len() as u64);
+            if *remaining == 0 {
+                self.end();
+            }
+        }
+    }
+
+    /// The body is over- a non-streaming response is a single JSON object, usually without a trailing newline
+    pub fn end(&mut self) {
+        if !self.gave_up && !self.pending.is_empty() {
+            let line = std::mem::take(&mut self.pending);
+            self.feed_line(&line);
+        }
+    }
+
+    /// Whether the final chunk of the reply has arrived
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    fn feed_line(&mut self, line: &[u8]) {
+        let line = String::from_utf8_lossy(line);
+        let line = line.trim();
+        // SSE framing- only the `data:` lines carry the payload
+        let payload = match line.strip_prefix("data:") {
+            Some(data) => data.trim(),
+            None if line.starts_with('{') => line,
+            None => return,
+        };
+        if payload == "[DONE]" {
+            self.done = true;
+            return;
+        }
+        if let Ok(chunk) = serde_json::from_str::<Value>(payload) {
+            self.feed_chunk(&chunk);
+        }
+    }
+
+    fn feed_chunk(&mut self, chunk: &Value) {
+        match self.format {
+            ReplyFormat::Ollama => self.feed_ollama(chunk),
+            ReplyFormat::OpenAi => self.feed_openai(chunk),
+            ReplyFormat::Anthropic => self.feed_anthropic(chunk),
+        }
+    }
+
+    fn feed_ollama(&mut self, chunk: &Value) {
+        if chunk.get("done").and_then(Value::as_bool) == Some(true) {
+            self.done = true;
+        }
+        // `/api/generate`
+        self.content.push_str(str_field(chunk, "response"));
+        if let Some(context) = chunk.get("context") {
+            self.context = context.clone();
+        }
+        let Some(message) = chunk.get("message") else {
+            return;
+        };
+        self.content.push_str(str_field(message, "content"));
+        for call in array_field(message, "tool_calls") {
+            let function = call.get("function").unwrap_or(&Value::Null);
+            let arguments = function.get("arguments").map(Value::to_string).unwrap_or_default();
+            let index = self.tool_calls.len() as u64;
+            self.tool_calls.insert(index, (str_field(function, "name").to_string(), arguments));
+        }
+    }
+
+    fn feed_openai(&mut self, chunk: &Value) {
+        let Some(choice) = array_field(chunk, "choices").first() else {
+            return;
+        };
+        if choice.get("finish_reason").is_some_and(|reason| !reason.is_null()) {
+            self.done = true;
+        }
+        // `/v1/completions`
+        self.content.push_str(str_field(choice, "text"));
+        // `delta` when streaming, `message` otherwise
+        let Some(message) = choice.get("delta").or_else(|| choice.get("message")) else {
+            return;
+        };
+        self.content.push_str(str_field(message, "content"));
+        for (position, call) in array_field(message, "tool_calls").iter().enumerate() {
+            let index = call.get("index").and_then(Value::as_u64).unwrap_or(position as u64);
+            let function = call.get("function").unwrap_or(&Value::Null);
+            let (name, arguments) = self.tool_calls.entry(index).or_default();
+            name.push_str(str_field(function, "name"));
+            arguments.push_str(str_field(function, "arguments"));
+        }
+    }
+
+    fn feed_anthropic(&mut self, chunk: &Value) {
+        match str_field(chunk, "type") {
+            // Not streaming- the whole reply at once
+            "message" => {
+                for (index, block) in array_field(chunk, "content").iter().enumerate() {
+                    self.feed_anthropic_block(index as u64, block);
+                }
+                self.done = true;
+            }
+            "content_block_start" => {
+                let index = chunk.get("index").and_then(Value::as_u64).unwrap_or_default();
+                if let Some(block) = chunk.get("content_block") {
+                    self.feed_anthropic_block(index, block);
+                }
+            }
+            "content_block_delta" => {
+                let index = chunk.get("index").and_then(Value::as_u64).unwrap_or_default();
+                let delta = chunk.get("delta").unwrap_or(&Value::Null);
+                match str_field(delta, "type") {
+                    "text_delta" => self.content.push_str(str_field(delta, "text")),
+                    "input_json_delta" => self.tool_calls.entry(index).or_default().1.push_str(str_field(delta, "partial_json")),
+                    _ => {}
+                }
+            }
+            "message_stop" => self.done = true,
+            _ => {}
+        }
+    }
+
+    fn feed_anthropic_block(&mut self, index: u64, block: &Value) {
+        match str_field(block, "type") {
+            "text" => self.content.push_str(str_field(block, "text")),
+            "tool_use" => {
+                // When streaming, `input` starts out empty and arrives as `input_json_delta`s
+                let input = match block.get("input") {
+                    Some(Value::Object(input)) if input.is_empty() => String::new(),
+                    Some(input) => input.to_string(),
+                    None => String::new(),
+                };
+                self.tool_calls.insert(index, (str_field(block, "name").to_string(), input));
+            }
+            _ => {}
+        }
+    }
+
+    /// The assistant message, in the native shape
+    pub fn into_message(self) -> Value {
+        Message {
+            content: self.content,
+            tool_calls: self.tool_calls.into_values().map(|(name, arguments)| tool_call(&name, &Value::String(arguments))).collect(),
+            ..Message::new("assistant")
+        }.into_json()
+    }
+
+    /// The generated text, and the token context if the server returned one
+    pub fn into_completion(self) -> (String, Value) {
+        (self.content, self.context)
+    }
+}
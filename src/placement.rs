@@ -0,0 +1,378 @@
+//! Declarative model placement.
+//!
+//! `--placement MODEL=TARGETS` declares which servers must have a model installed- `all`, `none`, or a list of
+//! server names and `group=` annotations. A declared model must be on exactly those servers: missing means a pull,
+//! installed elsewhere means a delete. Models without a placement are left alone.
+//!
+//! After every inventory poll, the servers' `GET /api/tags` is compared against the placements and drift is reported.
+//! With `--fix-drift` the load balancer takes the steps itself, otherwise the `reconcile` subcommand does it once.
+//! A step that failed is retried less and less often, and only its first failure is logged.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use ordermap::OrderMap;
+
+use crate::{inventory, OllamaServer, ServerConfig, SharedServerList};
+
+/// Format on the command line should be:  MODEL=all  or  MODEL=none  or  MODEL=NAME,NAME
+/// where each NAME is a server name or a server group
+#[derive(Debug, Clone)]
+pub struct PlacementRule {
+    model: String,
+    targets: Targets,
+}
+
+#[derive(Debug, Clone)]
+enum Targets {
+    All,
+    None,
+    Named(Vec<String>),
+}
+
+impl std::str::FromStr for PlacementRule {
+    type Err = String;
+
+    /// We expect the user to provide something like "llama3:8b=all" or "qwen3:32b=james,gpu"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (model, targets) = s.split_once('=')
+            .ok_or_else(|| "Invalid placement format. Use MODEL=all, MODEL=none or MODEL=NAME,NAME".to_string())?;
+        let (model, targets) = (model.trim(), targets.trim());
+        if model.is_empty() {
+            return Err(format!("Placement \"{}\" names no model", s));
+        }
+        let targets = match targets {
+            "all" => Targets::All,
+            "none" => Targets::None,
+            _ => {
+                let names: Vec<String> = targets.split(',').map(str::trim).filter(|name| !name.is_empty()).map(String::from).collect();
+                if names.is_empty() {
+                    return Err(format!("Placement \"{}\" names no servers. Use all, none or NAME,NAME", s));
+                }
+                Targets::Named(names)
+            }
+        };
+        Ok(PlacementRule { model: inventory::normalize_model_name(model), targets })
+    }
+}
+
+/// A placement with its targets resolved to server addresses
+#[derive(Debug, Clone)]
+pub struct Placement {
+    model: String,
+    addresses: Vec<String>,
+}
+
+/// Resolves the server names and groups of the rules to addresses, failing on names that match no server.
+pub fn resolve(rules: &[PlacementRule], servers: &[ServerConfig]) -> Result<Vec<Placement>, String> {
+    let mut placements: Vec<Placement> = Vec::new();
+    for rule in rules {
+        if placements.iter().any(|placement| placement.model == rule.model) {
+            return Err(format!("Model {} given twice in --placement", rule.model));
+        }
+        let addresses = match &rule.targets {
+            Targets::All => servers.iter().map(|server| server.address.clone()).collect(),
+            Targets::None => Vec::new(),
+            Targets::Named(names) => {
+                let mut addresses = Vec::new();
+                for name in names {
+                    let matching: Vec<&ServerConfig> = servers.iter()
+                        .filter(|server| &server.name == name || server.group.as_ref() == Some(name))
+                        .collect();
+                    if matching.is_empty() {
+                        return Err(format!("Placement of {} names \"{}\", which is neither a server name nor a group", rule.model, name));
+                    }
+                    for server in matching {
+                        if !addresses.contains(&server.address) {
+                            addresses.push(server.address.clone());
+                        }
+                    }
+                }
+                addresses
+            }
+        };
+        placements.push(Placement { model: rule.model.clone(), addresses });
+    }
+    Ok(placements)
+}
+
+/// Seconds until a failed step is retried, doubling with every further failure
+const FIRST_RETRY_DELAY_SECS: u64 = 60;
+/// The longest wait between two tries of a failing step
+const MAX_RETRY_DELAY_SECS: u64 = 60 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Action {
+    Pull,
+    Delete,
+}
+
+/// One pull or delete that brings a server in line with the placements
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Step {
+    action: Action,
+    address: String,
+    server_name: String,
+    model: String,
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let action = match self.action {
+            Action::Pull => "pull",
+            Action::Delete => "delete",
+        };
+        write!(f, "{} {} on server {} ({})", action, self.model, self.address, self.server_name)
+    }
+}
+
+/// What a server has installed, `None` if that isn't known
+struct Inventory {
+    address: String,
+    name: String,
+    models: Option<Vec<String>>,
+}
+
+/// The steps that bring every server whose inventory is known in line with the placements
+fn plan(placements: &[Placement], inventories: &[Inventory]) -> Vec<Step> {
+    let mut steps = Vec::new();
+    for placement in placements {
+        for inventory in inventories {
+            let Some(models) = &inventory.models else {
+                continue;
+            };
+            let installed = models.iter().any(|model| inventory::normalize_model_name(model) == placement.model);
+            let wanted = placement.addresses.contains(&inventory.address);
+            let action = match (wanted, installed) {
+                (true, false) => Action::Pull,
+                (false, true) => Action::Delete,
+                _ => continue,
+            };
+            steps.push(Step { action, address: inventory.address.clone(), server_name: inventory.name.clone(), model: placement.model.clone() });
+        }
+    }
+    steps
+}
+
+/// Takes the steps, servers in parallel and the steps of each server one after another.
+/// The `quiet` ones failed before, their start and another failure aren't logged. Returns the steps that failed.
+async fn apply(steps: &[Step], quiet: &[Step]) -> Vec<Step> {
+    // Pulls can take hours, only connecting has to be quick
+    let client = reqwest::Client::builder()
+        .connect_timeout(Duration::from_secs(1))
+        .build()
+        .unwrap();
+    let mut addresses: Vec<&str> = Vec::new();
+    for step in steps {
+        if !addresses.contains(&step.address.as_str()) {
+            addresses.push(&step.address);
+        }
+    }
+
+    let failures = futures_util::future::join_all(addresses.into_iter().map(|address| {
+        let client = &client;
+        async move {
+            let mut failures = Vec::new();
+            for step in steps.iter().filter(|step| step.address == address) {
+                let is_quiet = quiet.contains(step);
+                if !is_quiet {
+                    println!("🧭 Starting to {}", step);
+                }
+                match take_step(client, step).await {
+                    Ok(()) => println!("🧭✅ Done: {}", step),
+                    Err(e) => {
+                        if !is_quiet {
+                            println!("🧭⛔ Failed to {}. Error: {}", step, e);
+                        }
+                        failures.push(step.clone());
+                    }
+                }
+            }
+            failures
+        }
+    })).await;
+    failures.into_iter().flatten().collect()
+}
+
+/// How long to wait before trying a step that failed `failures` times in a row once more
+fn retry_delay(failures: u32) -> Duration {
+    let doublings = failures.saturating_sub(1).min(16);
+    Duration::from_secs((FIRST_RETRY_DELAY_SECS << doublings).min(MAX_RETRY_DELAY_SECS))
+}
+
+async fn take_step(client: &reqwest::Client, step: &Step) -> Result<(), String> {
+    let (request_builder, body) = match step.action {
+        Action::Pull => (client.post(format!("{}/api/pull", step.address)), serde_json::json!({ "model": step.model, "stream": false })),
+        Action::Delete => (client.delete(format!("{}/api/delete", step.address)), serde_json::json!({ "model": step.model })),
+    };
+    let response = request_builder
+        .header("Content-Type", "application/json")
+        .body(body.to_string())
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    let status = response.status();
+    // Someone else deleted it in the meantime
+    if step.action == Action::Delete && status == reqwest::StatusCode::NOT_FOUND {
+        return Ok(());
+    }
+    let body = response.bytes().await.map_err(|e| e.to_string())?;
+    let error = serde_json::from_slice::<serde_json::Value>(&body).ok()
+        .and_then(|json| json.get("error").and_then(|error| error.as_str()).map(String::from));
+    match error {
+        Some(error) => Err(error),
+        None if !status.is_success() => Err(format!("status {}", status)),
+        None => Ok(()),
+    }
+}
+
+/// Compares the inventory against the placements after every poll
+pub struct Reconciler {
+    placements: Vec<Placement>,
+    /// Take the steps instead of only reporting them
+    fix_drift: bool,
+    /// The steps reported last, so that unchanged drift isn't reported every poll
+    last_plan: Option<Vec<Step>>,
+    /// Steps are being taken right now
+    fixing: Arc<AtomicBool>,
+    /// The steps that failed, until they succeed or aren't needed anymore
+    failures: Arc<Mutex<HashMap<Step, Failure>>>,
+}
+
+/// How often a step failed in a row, and when it's due again
+struct Failure {
+    count: u32,
+    retry_at: Instant,
+}
+
+impl Reconciler {
+    pub fn new(placements: Vec<Placement>, fix_drift: bool) -> Self {
+        Reconciler { placements, fix_drift, last_plan: None, fixing: Arc::new(AtomicBool::new(false)), failures: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    /// Whether no steps are being taken. A poll that started while steps were taken may not show their outcome.
+    pub fn is_idle(&self) -> bool {
+        !self.fixing.load(Ordering::SeqCst)
+    }
+
+    /// Reports drift from the placements, and with `--fix-drift` starts taking the steps.
+    pub fn check(&mut self, servers: &SharedServerList) {
+        if self.placements.is_empty() || !self.is_idle() {
+            return;
+        }
+        let inventories: Vec<Inventory> = servers.lock().unwrap().iter()
+            .map(|(address, server)| Inventory {
+                address: address.clone(),
+                name: server.name.clone(),
+                models: server.installed_models.as_ref().map(|models| models.iter().map(|model| model.name.clone()).collect()),
+            })
+            .collect();
+        let steps = plan(&self.placements, &inventories);
+
+        if self.last_plan.as_ref() != Some(&steps) {
+            if steps.is_empty() {
+                println!("🧭 Models are placed as configured");
+            } else {
+                println!("🧭 Models drifted from their placement, {} step(s) to fix:", steps.len());
+                for step in &steps {
+                    println!("   {}", step);
+                }
+                if !self.fix_drift {
+                    println!("   Run with --fix-drift or use the reconcile subcommand to take them");
+                }
+            }
+            self.last_plan = Some(steps.clone());
+        }
+
+        if !self.fix_drift {
+            return;
+        }
+        let (due, quiet) = {
+            let mut failures = self.failures.lock().unwrap();
+            // A step that's needed again later starts afresh
+            failures.retain(|step, _| steps.contains(step));
+            let now = Instant::now();
+            let due: Vec<Step> = steps.into_iter()
+                .filter(|step| failures.get(step).is_none_or(|failure| failure.retry_at <= now))
+                .collect();
+            let quiet: Vec<Step> = due.iter().filter(|step| failures.contains_key(*step)).cloned().collect();
+            (due, quiet)
+        };
+        if due.is_empty() {
+            return;
+        }
+        self.fixing.store(true, Ordering::SeqCst);
+        let fixing = self.fixing.clone();
+        let failures = self.failures.clone();
+        tokio::spawn(async move {
+            let failed = apply(&due, &quiet).await;
+            {
+                let mut failures = failures.lock().unwrap();
+                for step in due {
+                    if !failed.contains(&step) {
+                        failures.remove(&step);
+                        continue;
+                    }
+                    let failure = failures.entry(step.clone()).or_insert(Failure { count: 0, retry_at: Instant::now() });
+                    failure.count += 1;
+                    let delay = retry_delay(failure.count);
+                    failure.retry_at = Instant::now() + delay;
+                    if failure.count == 1 {
+                        println!("🧭 Will try to {} again in {} seconds, then less and less often. Further failures of it aren't logged", step, delay.as_secs());
+                    }
+                }
+            }
+            fixing.store(false, Ordering::SeqCst);
+        });
+    }
+}
+
+/// The `reconcile` subcommand: lists every server's models, prints the steps, and unless `dry_run` takes them.
+pub async fn reconcile(placements: &[Placement], servers: &OrderMap<String, OllamaServer>, dry_run: bool) -> Result<(), String> {
+    if placements.is_empty() {
+        return Err("Nothing to reconcile, give the models' placements with --placement".to_string());
+    }
+    let client = reqwest::Client::builder()
+        .connect_timeout(Duration::from_secs(1))
+        .timeout(Duration::from_secs(inventory::POLL_TIMEOUT_SECS))
+        .build()
+        .unwrap();
+
+    let results = futures_util::future::join_all(servers.keys().map(|address| inventory::fetch_model_list(&client, address, "/api/tags"))).await;
+    let mut unlisted = 0;
+    let inventories: Vec<Inventory> = servers.iter().zip(results)
+        .map(|((address, server), result)| {
+            let models = match result {
+                Ok(entries) => Some(entries.iter()
+                    .filter_map(|entry| entry.get("name").and_then(|name| name.as_str()).map(String::from))
+                    .collect()),
+                Err(e) => {
+                    println!("📭 Failed to list models of server {} ({}), leaving it out. Error: {}", address, server.name, e);
+                    unlisted += 1;
+                    None
+                }
+            };
+            Inventory { address: address.clone(), name: server.name.clone(), models }
+        })
+        .collect();
+
+    let steps = plan(placements, &inventories);
+    if steps.is_empty() {
+        println!("🧭 Models are placed as configured, nothing to do");
+    } else {
+        println!("🧭 {} step(s) to place the models as configured:", steps.len());
+        for (index, step) in steps.iter().enumerate() {
+            println!("{}. {}", index + 1, step);
+        }
+    }
+
+    let failures = if dry_run || steps.is_empty() { 0 } else { apply(&steps, &[]).await.len() };
+    match (failures, unlisted) {
+        (0, 0) => Ok(()),
+        (0, _) => Err(format!("{} server(s) couldn't be listed", unlisted)),
+        _ => Err(format!("{} of {} step(s) failed", failures, steps.len())),
+    }
+}
@@ -0,0 +1,157 @@
+//! Priority classes for requests waiting in line.
+//!
+//! Interactive chat shouldn't wait behind hundreds of batch embedding requests. Every request gets a class-
+//! from its `X-LB-Priority` header, else from the first matching `--priority` rule, else normal- and
+//! higher classes get freed servers first. Waiting raises a request's class over time
+//! (see `--priority-aging`), so low priority work still gets served eventually.
+
+use std::fmt;
+use std::net::IpAddr;
+use std::time::Duration;
+
+/// Request header that sets the priority class explicitly
+pub const PRIORITY_HEADER: &str = "x-lb-priority";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Low,
+    Normal,
+    High,
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Priority::Low => write!(f, "low"),
+            Priority::Normal => write!(f, "normal"),
+            Priority::High => write!(f, "high"),
+        }
+    }
+}
+
+impl std::str::FromStr for Priority {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "low" => Ok(Priority::Low),
+            "normal" => Ok(Priority::Normal),
+            "high" => Ok(Priority::High),
+            _ => Err(format!("Invalid priority class \"{}\". Use high, normal or low", s)),
+        }
+    }
+}
+
+impl Priority {
+    /// The class a request of this class counts as after waiting `waited`- one class higher per `aging`
+    pub fn aged(self, waited: Duration, aging: Duration) -> u64 {
+        let rank = match self {
+            Priority::Low => 0,
+            Priority::Normal => 1,
+            Priority::High => 2,
+        };
+        rank + (waited.as_millis() / aging.as_millis().max(1)) as u64
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Matcher {
+    /// Client address within `network`/`prefix_len`
+    Ip { network: IpAddr, prefix_len: u8 },
+    /// `Authorization: Bearer <key>` or `x-api-key: <key>`
+    ApiKey(String),
+    /// `User-Agent` contains this text
+    UserAgent(String),
+}
+
+/// Format on the command line should be:  CLASS:ip=ADDRESS[/PREFIX]  or  CLASS:key=API_KEY  or  CLASS:user-agent=TEXT
+#[derive(Debug, Clone)]
+pub struct PriorityRule {
+    priority: Priority,
+    matcher: Matcher,
+}
+
+impl std::str::FromStr for PriorityRule {
+    type Err = String;
+
+    /// We expect the user to provide something like "low:user-agent=python-requests" or "high:ip=192.168.1.0/24"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (priority, rule) = s.split_once(':')
+            .ok_or_else(|| "Invalid priority rule format. Use CLASS:ip=ADDRESS[/PREFIX], CLASS:key=API_KEY or CLASS:user-agent=TEXT".to_string())?;
+        let priority: Priority = priority.parse()?;
+        let (kind, value) = rule.split_once('=')
+            .ok_or_else(|| format!("Invalid priority rule \"{}\". Use ip=, key= or user-agent=", rule))?;
+        let value = value.trim();
+        if value.is_empty() {
+            return Err(format!("Empty value in priority rule \"{}\"", rule));
+        }
+        let matcher = match kind.trim() {
+            "ip" => {
+                let (address, prefix_len) = match value.split_once('/') {
+                    Some((address, prefix_len)) => (address, Some(prefix_len)),
+                    None => (value, None),
+                };
+                let network: IpAddr = address.parse()
+                    .map_err(|_| format!("Invalid IP address \"{}\" in priority rule", address))?;
+                let max_prefix_len = if network.is_ipv4() { 32 } else { 128 };
+                let prefix_len = match prefix_len {
+                    Some(prefix_len) => match prefix_len.parse::<u8>() {
+                        Ok(prefix_len) if prefix_len <= max_prefix_len => prefix_len,
+                        _ => return Err(format!("Invalid prefix length \"{}\" in priority rule. Must be 0 to {}", prefix_len, max_prefix_len)),
+                    },
+                    None => max_prefix_len,
+                };
+                Matcher::Ip { network, prefix_len }
+            }
+            "key" => Matcher::ApiKey(value.to_string()),
+            "user-agent" => Matcher::UserAgent(value.to_string()),
+            other => return Err(format!("Unknown priority rule kind \"{}\". Supported: ip, key, user-agent", other)),
+        };
+        Ok(PriorityRule { priority, matcher })
+    }
+}
+
+impl PriorityRule {
+    fn matches(&self, headers: &hyper::HeaderMap, remote_addr: IpAddr) -> bool {
+        let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+        match &self.matcher {
+            Matcher::Ip { network, prefix_len } => in_network(remote_addr.to_canonical(), *network, *prefix_len),
+            Matcher::ApiKey(key) => api_key(headers) == Some(key.as_str()),
+            Matcher::UserAgent(text) => header("user-agent").is_some_and(|user_agent| user_agent.contains(text.as_str())),
+        }
+    }
+}
+
+/// The API key of a request- OpenAI clients send `Authorization: Bearer <key>`, Anthropic clients `x-api-key: <key>`
+pub fn api_key(headers: &hyper::HeaderMap) -> Option<&str> {
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+    header("authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .or_else(|| header("x-api-key"))
+        .map(str::trim)
+        .filter(|key| !key.is_empty())
+}
+
+fn in_network(address: IpAddr, network: IpAddr, prefix_len: u8) -> bool {
+    match (address, network) {
+        (IpAddr::V4(address), IpAddr::V4(network)) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(prefix_len)).unwrap_or(0);
+            u32::from(address) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(address), IpAddr::V6(network)) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(prefix_len)).unwrap_or(0);
+            u128::from(address) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    }
+}
+
+/// The priority class of a request: its `X-LB-Priority` header, else the first matching rule, else normal.
+pub fn classify(rules: &[PriorityRule], headers: &hyper::HeaderMap, remote_addr: IpAddr) -> Priority {
+    let explicit = headers.get(PRIORITY_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok());
+    explicit
+        .or_else(|| rules.iter().find(|rule| rule.matches(headers, remote_addr)).map(|rule| rule.priority))
+        .unwrap_or(Priority::Normal)
+}
@@ -0,0 +1,91 @@
+//! Headers and URLs of proxied requests.
+//!
+//! End-to-end headers are passed through byte for byte, whether or not they're valid UTF-8.
+//! Hop-by-hop headers (RFC 9110 section 7.6.1) describe one connection only, so they're dropped both ways,
+//! and `Host` is set by reqwest for the server's address. The server is told who the client was with
+//! `X-Forwarded-For` and `X-Forwarded-Proto`, and our own `X-LB-*` headers aren't passed on.
+
+use std::net::SocketAddr;
+
+use hyper::http::response;
+use hyper::{HeaderMap, Uri};
+
+use crate::{fanout, priority};
+
+/// Headers that only concern a single connection
+const HOP_BY_HOP_HEADERS: [&str; 9] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// The URL of the request on the server at `address`, query string included
+pub fn upstream_url(address: &str, uri: &Uri) -> String {
+    let path_and_query = uri.path_and_query().map(|path_and_query| path_and_query.as_str()).unwrap_or("/");
+    format!("{}{}", address, path_and_query)
+}
+
+/// The client's headers as they should reach the server
+pub fn request_headers(headers: &HeaderMap, remote_addr: SocketAddr) -> reqwest::header::HeaderMap {
+    let connection_headers = connection_headers(headers.get_all("connection").iter().map(|value| value.as_bytes()));
+    let is_forwarded = |name: &str| !HOP_BY_HOP_HEADERS.contains(&name)
+        && !connection_headers.iter().any(|connection_header| connection_header == name)
+        && name != "host"
+        && name != priority::PRIORITY_HEADER
+        && name != fanout::SERVERS_HEADER;
+
+    let mut upstream = reqwest::header::HeaderMap::new();
+    for (name, value) in headers.iter().filter(|(name, _)| is_forwarded(name.as_str())) {
+        if let (Ok(name), Ok(value)) = (
+            reqwest::header::HeaderName::from_bytes(name.as_str().as_bytes()),
+            reqwest::header::HeaderValue::from_bytes(value.as_bytes()),
+        ) {
+            upstream.append(name, value);
+        }
+    }
+
+    // Proxies in front of us already listed the addresses before the client's
+    let forwarded_for = match upstream.remove("x-forwarded-for") {
+        Some(earlier) => {
+            let mut value = earlier.as_bytes().to_vec();
+            value.extend_from_slice(format!(", {}", remote_addr.ip()).as_bytes());
+            value
+        }
+        None => remote_addr.ip().to_string().into_bytes(),
+    };
+    if let Ok(value) = reqwest::header::HeaderValue::from_bytes(&forwarded_for) {
+        upstream.insert("x-forwarded-for", value);
+    }
+    // We only listen on plain HTTP, a TLS terminating proxy in front of us knows better
+    if !upstream.contains_key("x-forwarded-proto") {
+        upstream.insert("x-forwarded-proto", reqwest::header::HeaderValue::from_static("http"));
+    }
+    upstream
+}
+
+/// Adds the server's response headers to the client's response
+pub fn response_headers(mut resp_builder: response::Builder, headers: &reqwest::header::HeaderMap) -> response::Builder {
+    let connection_headers = connection_headers(headers.get_all("connection").iter().map(|value| value.as_bytes()));
+    for (name, value) in headers {
+        let name = name.as_str();
+        if HOP_BY_HOP_HEADERS.contains(&name) || connection_headers.iter().any(|connection_header| connection_header == name) {
+            continue;
+        }
+        resp_builder = resp_builder.header(name, value.as_bytes());
+    }
+    resp_builder
+}
+
+/// The headers a `Connection` header names as hop-by-hop, lowercase
+fn connection_headers<'a>(values: impl Iterator<Item = &'a [u8]>) -> Vec<String> {
+    values
+        .flat_map(|value| String::from_utf8_lossy(value).split(',').map(|name| name.trim().to_ascii_lowercase()).collect::<Vec<_>>())
+        .filter(|name| !name.is_empty())
+        .collect()
+}
@@ -0,0 +1,287 @@
+//! Waiting line for requests that arrive while every suitable server is busy.
+//!
+//! Instead of failing right away, a request waits (up to `--max-queue-wait` seconds, and only while
+//! fewer than `--max-queue-length` requests wait ahead of it) until a server slot frees up.
+//! Freed slots go to the waiting requests by priority class (see `priority`), first come first served within
+//! a class- a request only waits behind the ones that the freed server can serve too, so a request for a model
+//! that only a busy server has doesn't hold up everybody else. With `--fairness`, clients also take turns
+//! within a class and may only have so many requests served at once (see `fairness`).
+
+use std::cmp::Reverse;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::sync::oneshot;
+
+use crate::priority::Priority;
+use crate::{affinity, SelectionConfig, ServerGuard, SharedServerList};
+
+pub type SharedQueue = Arc<Mutex<WaitQueue>>;
+
+/// What server selection needs to know about a request
+#[derive(Clone)]
+pub struct QueuedRequest {
+    pub remote_addr: SocketAddr,
+    pub model: Option<String>,
+    pub prompt: Option<affinity::Prompt>,
+    pub priority: Priority,
+    /// Who sent the request, with `--fairness` only
+    pub client: Option<String>,
+    /// Servers that couldn't be reached by earlier attempts of the request
+    pub excluded: Vec<String>,
+    /// The only server the request may go to- the one that has its blobs
+    pub pinned: Option<String>,
+}
+
+struct Waiter {
+    id: u64,
+    since: Instant,
+    request: QueuedRequest,
+    sender: oneshot::Sender<ServerGuard>,
+}
+
+pub struct WaitQueue {
+    waiters: VecDeque<Waiter>,
+    next_id: u64,
+    max_length: usize,
+    max_wait: Duration,
+    /// Waiting this long raises a request by one priority class
+    aging: Duration,
+    selection: Arc<SelectionConfig>,
+    /// With `--fairness`, max requests each client may have served at once
+    max_per_client: Option<usize>,
+    /// Requests being served per client, with `--fairness` only
+    in_flight: HashMap<String, usize>,
+    /// When each client was last given a server, to take turns- lower goes first
+    last_turn: HashMap<String, u64>,
+    next_turn: u64,
+}
+
+/// Why a request didn't get a server
+pub enum Rejection {
+    QueueFull,
+    TimedOut,
+}
+
+impl WaitQueue {
+    pub fn new(max_length: usize, max_wait_secs: u32, aging_secs: u32, max_per_client: Option<usize>, selection: Arc<SelectionConfig>) -> Self {
+        Self {
+            waiters: VecDeque::new(),
+            next_id: 0,
+            max_length,
+            max_wait: Duration::from_secs(max_wait_secs.into()),
+            aging: Duration::from_secs(aging_secs.into()),
+            selection,
+            max_per_client,
+            in_flight: HashMap::new(),
+            last_turn: HashMap::new(),
+            next_turn: 1,
+        }
+    }
+
+    /// Whether the client already has as many requests served as it may
+    fn at_capacity(&self, client: &Option<String>) -> bool {
+        match (client, self.max_per_client) {
+            (Some(client), Some(max)) => self.in_flight.get(client).copied().unwrap_or_default() >= max,
+            _ => false,
+        }
+    }
+
+    /// 0 for clients that haven't been given a server lately
+    fn last_turn(&self, client: &Option<String>) -> u64 {
+        client.as_ref().and_then(|client| self.last_turn.get(client)).copied().unwrap_or_default()
+    }
+
+    fn start_serving(&mut self, client: &Option<String>) {
+        if let Some(client) = client {
+            *self.in_flight.entry(client.clone()).or_default() += 1;
+            self.last_turn.insert(client.clone(), self.next_turn);
+            self.next_turn += 1;
+        }
+    }
+
+    fn stop_serving(&mut self, client: &Option<String>) {
+        if let Some(count) = client.as_ref().and_then(|client| self.in_flight.get_mut(client)) {
+            *count -= 1;
+            if *count == 0 {
+                self.in_flight.remove(client.as_ref().unwrap());
+            }
+        }
+        self.forget_if_idle(client);
+    }
+
+    /// Only clients with requests served or waiting need their turn remembered
+    fn forget_if_idle(&mut self, client: &Option<String>) {
+        let Some(client) = client else {
+            return;
+        };
+        if !self.in_flight.contains_key(client) && !self.waiters.iter().any(|waiter| waiter.request.client.as_ref() == Some(client)) {
+            self.last_turn.remove(client);
+        }
+    }
+
+    fn remove(&mut self, id: u64) -> Option<Waiter> {
+        let position = self.waiters.iter().position(|waiter| waiter.id == id)?;
+        let waiter = self.waiters.remove(position)?;
+        self.forget_if_idle(&waiter.request.client);
+        Some(waiter)
+    }
+}
+
+/// Removes the request from the line when it stops waiting for any reason-
+/// most importantly when hyper drops the request because the client disconnected.
+struct Ticket {
+    queue: SharedQueue,
+    id: u64,
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        let mut queue_lock = self.queue.lock().unwrap();
+        if let Some(waiter) = queue_lock.remove(self.id) {
+            println!("🚪 Client {} left the waiting line ({} waiting)", waiter.request.remote_addr, queue_lock.waiters.len());
+        }
+    }
+}
+
+/// Takes a slot of the best server for the request, waiting in line if every suitable server is busy.
+pub async fn wait_for_server(servers: &SharedServerList, queue: &SharedQueue, request: QueuedRequest) -> Result<ServerGuard, Rejection> {
+    let remote_addr = request.remote_addr;
+    let priority = request.priority;
+    let (sender, mut receiver) = oneshot::channel();
+    let (id, max_wait, reconfigure_wait) = {
+        let mut queue_lock = queue.lock().unwrap();
+        let id = queue_lock.next_id;
+        queue_lock.next_id += 1;
+        queue_lock.waiters.push_back(Waiter { id, since: Instant::now(), request, sender });
+        (id, queue_lock.max_wait, queue_lock.selection.reconfigure_wait)
+    };
+    let _ticket = Ticket { queue: queue.clone(), id };
+
+    // Free slots go to whoever comes first, which is us only if nobody that fits the same servers is ahead
+    dispatch(servers, queue);
+    if let Ok(selected) = receiver.try_recv() {
+        return Ok(selected);
+    }
+
+    {
+        let servers_lock = servers.lock().unwrap();
+        let mut queue_lock = queue.lock().unwrap();
+        if queue_lock.waiters.len() > queue_lock.max_length && queue_lock.remove(id).is_some() {
+            println!("🤷 No available servers to serve client {} and the waiting line is full", remote_addr);
+            crate::print_server_statuses(&servers_lock, queue_lock.waiters.len());
+            return Err(Rejection::QueueFull);
+        }
+        println!("⏳ No available servers to serve client {}, waiting in line with {} priority ({} waiting)", remote_addr, priority, queue_lock.waiters.len());
+        crate::print_server_statuses(&servers_lock, queue_lock.waiters.len());
+    }
+
+    let waiting = async {
+        // Past `--reconfigure-wait`, the request may also take a server of the wrong KV cache type
+        if reconfigure_wait < max_wait {
+            if let Ok(selected) = tokio::time::timeout(reconfigure_wait, &mut receiver).await {
+                return selected;
+            }
+            dispatch(servers, queue);
+        }
+        (&mut receiver).await
+    };
+    match tokio::time::timeout(max_wait, waiting).await {
+        Ok(Ok(selected)) => Ok(selected),
+        _ => {
+            // Might have been handed a server right as time ran out
+            let removed = queue.lock().unwrap().remove(id).is_some();
+            match receiver.try_recv() {
+                Ok(selected) => Ok(selected),
+                Err(_) => {
+                    if removed {
+                        println!("⌛ Client {} waited {} seconds in line without getting a server", remote_addr, max_wait.as_secs());
+                    }
+                    Err(Rejection::TimedOut)
+                }
+            }
+        }
+    }
+}
+
+/// Hands free server slots to the waiting requests: highest (aged) priority class first, then the client
+/// whose turn it is, then longest waiting. Clients that already have `--max-per-client` requests served are skipped.
+/// Must be called without holding the server list or queue lock, whenever a slot may have freed up.
+pub fn dispatch(servers: &SharedServerList, queue: &SharedQueue) {
+    // Handed to requests that were gone by the time we got to them, released once the locks are
+    let mut undelivered = Vec::new();
+    {
+        let mut servers_lock = servers.lock().unwrap();
+        let mut queue_lock = queue.lock().unwrap();
+        let queue_lock = &mut *queue_lock;
+        let mut dispatched_any = false;
+
+        // One request at a time, since giving a client a server changes whose turn it is
+        loop {
+            let now = Instant::now();
+            let mut order: Vec<usize> = (0..queue_lock.waiters.len()).collect();
+            order.sort_by_key(|&index| {
+                let waiter = &queue_lock.waiters[index];
+                (
+                    Reverse(waiter.request.priority.aged(now - waiter.since, queue_lock.aging)),
+                    queue_lock.last_turn(&waiter.request.client),
+                    waiter.id,
+                )
+            });
+
+            // The next waiter to leave the line, with the server slot it gets- none if the client is gone
+            let mut leaving = None;
+            for index in order {
+                let waiter = &queue_lock.waiters[index];
+                if waiter.sender.is_closed() {
+                    leaving = Some((index, None));
+                    break;
+                }
+                if queue_lock.at_capacity(&waiter.request.client) {
+                    continue;
+                }
+                if let Some(selected) = crate::select_server(&mut servers_lock, &waiter.request, now - waiter.since, &queue_lock.selection) {
+                    leaving = Some((index, Some(selected)));
+                    break;
+                }
+            }
+            let Some((index, selected)) = leaving else {
+                break;
+            };
+
+            let waiter = queue_lock.waiters.remove(index).unwrap();
+            if let Some((key, reconfigure_to)) = selected {
+                dispatched_any = true;
+                queue_lock.start_serving(&waiter.request.client);
+                let guard = ServerGuard {
+                    servers: servers.clone(),
+                    queue: queue.clone(),
+                    key,
+                    client: waiter.request.client,
+                    reconfigure_to,
+                };
+                if let Err(guard) = waiter.sender.send(guard) {
+                    undelivered.push(guard);
+                }
+            }
+        }
+        if dispatched_any {
+            crate::print_server_statuses(&servers_lock, queue_lock.waiters.len());
+        }
+    }
+    drop(undelivered);
+}
+
+/// Counts the client's request as no longer served, returns the number of requests waiting for a server
+pub fn finished(queue: &SharedQueue, client: &Option<String>) -> usize {
+    let mut queue_lock = queue.lock().unwrap();
+    queue_lock.stop_serving(client);
+    queue_lock.waiters.len()
+}
+
+/// Number of requests waiting for a server
+pub fn queued(queue: &SharedQueue) -> usize {
+    queue.lock().unwrap().waiters.len()
+}
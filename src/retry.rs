@@ -0,0 +1,147 @@
+//! Retrying a request on another server when the chosen one can't be reached.
+//!
+//! Nothing has reached the client yet when connecting to a server fails, so the request can just as well go
+//! to the next best server. That takes the request body kept in memory- bodies larger than `--retry-body-limit`
+//! are streamed through as before and only get one attempt.
+//!
+//! A streamed body arrives only as fast as the client uploads it, so how far it got is tracked- the wait for the
+//! server's first byte only starts once all of it went out.
+//!
+//! A kept-alive connection the server closed just as it was reused fails the request without the server being at fault,
+//! so such a request is sent once more to the same server, on a new connection.
+
+use std::future::Future;
+
+use futures_util::stream::{self, StreamExt};
+use hyper::body::{Body, Bytes, HttpBody};
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+/// The body of a request on its way to the Ollama servers
+pub enum RequestBody {
+    /// Read completely, can be sent as many times as needed
+    Buffered(Bytes),
+    /// Too large to keep in memory, `None` once sent
+    Streaming(Option<reqwest::Body>, watch::Receiver<Upload>),
+}
+
+/// How far a streamed body got on its way to the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Upload {
+    Sending,
+    /// The last of it went out at this instant
+    Sent(Instant),
+    /// Reading it from the client failed
+    Failed,
+}
+
+impl RequestBody {
+    /// The body for the next attempt, `None` if it was streamed already
+    pub fn next_attempt(&mut self) -> Option<reqwest::Body> {
+        match self {
+            RequestBody::Buffered(bytes) => Some(reqwest::Body::from(bytes.clone())),
+            RequestBody::Streaming(body, _) => body.take(),
+        }
+    }
+
+    pub fn can_retry(&self) -> bool {
+        matches!(self, RequestBody::Buffered(_))
+    }
+
+    /// When the last of the body went out, if it did. A body kept in memory goes out
+    /// as fast as the network allows, so it counts as sent at `sent_at`, when the request was.
+    pub fn uploaded_at(&self, sent_at: Instant) -> Option<Instant> {
+        match self {
+            RequestBody::Buffered(_) => Some(sent_at),
+            RequestBody::Streaming(_, upload) => match *upload.borrow() {
+                Upload::Sent(at) => Some(at),
+                _ => None,
+            },
+        }
+    }
+
+    /// Waits until the last of the body went out, see `uploaded_at`. Never completes if the upload fails.
+    pub fn uploaded(&self, sent_at: Instant) -> impl Future<Output = Instant> {
+        let upload = match self {
+            RequestBody::Buffered(_) => None,
+            RequestBody::Streaming(_, upload) => Some(upload.clone()),
+        };
+        async move {
+            let Some(mut upload) = upload else {
+                return sent_at;
+            };
+            if let Ok(upload) = upload.wait_for(|upload| matches!(upload, Upload::Sent(_))).await {
+                if let Upload::Sent(at) = *upload {
+                    return at;
+                }
+            }
+            std::future::pending().await
+        }
+    }
+
+    /// Whether reading the streamed body from the client failed, the server isn't to blame then
+    pub fn upload_failed(&self) -> bool {
+        matches!(self, RequestBody::Streaming(_, upload) if *upload.borrow() == Upload::Failed)
+    }
+}
+
+/// Reads the body into memory, up to `limit` bytes. Beyond that, the part already read
+/// is streamed to the server followed by the rest.
+pub async fn read_body(mut body: Body, limit: usize) -> Result<RequestBody, hyper::Error> {
+    let mut chunks: Vec<Bytes> = Vec::new();
+    let mut length = 0;
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        length += chunk.len();
+        chunks.push(chunk);
+        if length > limit {
+            let (progress, upload) = watch::channel(Upload::Sending);
+            let chunks = stream::iter(chunks.into_iter().map(Ok)).chain(body);
+            // The chunks are passed on as they are, without copying
+            let body_stream = stream::unfold((chunks, Some(progress)), |(mut chunks, progress)| async move {
+                let progress = progress?;
+                match chunks.next().await {
+                    Some(Ok(chunk)) => Some((Ok(chunk), (chunks, Some(progress)))),
+                    Some(Err(e)) => {
+                        progress.send_replace(Upload::Failed);
+                        Some((Err(e), (chunks, None)))
+                    }
+                    None => {
+                        progress.send_replace(Upload::Sent(Instant::now()));
+                        None
+                    }
+                }
+            });
+            return Ok(RequestBody::Streaming(Some(reqwest::Body::wrap_stream(body_stream)), upload));
+        }
+    }
+    // A body that arrived in one chunk is kept as it is
+    match chunks.len() {
+        1 => Ok(RequestBody::Buffered(chunks.remove(0))),
+        _ => Ok(RequestBody::Buffered(chunks.concat().into())),
+    }
+}
+
+/// Whether the request failed because the connection it went out on was closed by the server,
+/// as happens when a kept-alive connection is reused just as the server drops it.
+/// Failing to connect, or to answer in time, is the server's fault instead.
+pub fn is_stale_connection(error: &reqwest::Error) -> bool {
+    if error.is_connect() || error.is_timeout() {
+        return false;
+    }
+    let mut source: Option<&(dyn std::error::Error + 'static)> = Some(error);
+    while let Some(cause) = source {
+        if let Some(io_error) = cause.downcast_ref::<std::io::Error>() {
+            use std::io::ErrorKind;
+            if matches!(io_error.kind(), ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::BrokenPipe | ErrorKind::UnexpectedEof) {
+                return true;
+            }
+        }
+        // hyper's error for a connection closed before any of the response arrived
+        if cause.to_string().contains("connection closed before message completed") {
+            return true;
+        }
+        source = cause.source();
+    }
+    false
+}
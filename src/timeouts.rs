@@ -0,0 +1,113 @@
+//! Timeout overrides for particular endpoints and models.
+//!
+//! Image generation and vision models fed huge images may need minutes before the first token,
+//! while embeddings should fail fast. A `--timeout-rule` matches the request path and/or the requested model
+//! and overrides `--timeout` and `--first-byte-timeout` for the requests it matches. The first matching rule wins.
+
+use crate::inventory;
+
+/// Format on the command line should be:  path=PATTERN[timeout=SECONDS]  or  model=PATTERN[first_byte_timeout=SECONDS]
+/// or  path=PATTERN,model=PATTERN[timeout=SECONDS,first_byte_timeout=SECONDS]
+#[derive(Debug, Clone)]
+pub struct TimeoutRule {
+    path: Option<String>,
+    model: Option<String>,
+    /// Overrides `--timeout`
+    timeout: Option<u32>,
+    /// Overrides `--first-byte-timeout`
+    first_byte_timeout: Option<u32>,
+}
+
+impl std::str::FromStr for TimeoutRule {
+    type Err = String;
+
+    /// We expect the user to provide something like "path=/v1/images/*[timeout=600]"
+    /// or "model=qwen3-vl*[timeout=300,first_byte_timeout=600]"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (matchers, settings) = s.trim().strip_suffix(']')
+            .and_then(|without_bracket| without_bracket.split_once('['))
+            .ok_or_else(|| "Invalid timeout rule format. Use path=PATTERN[timeout=SECONDS] or model=PATTERN[first_byte_timeout=SECONDS]".to_string())?;
+        let mut rule = TimeoutRule { path: None, model: None, timeout: None, first_byte_timeout: None };
+
+        for matcher in matchers.split(',').map(str::trim).filter(|matcher| !matcher.is_empty()) {
+            let (kind, pattern) = matcher.split_once('=')
+                .ok_or_else(|| format!("Invalid timeout rule matcher \"{}\". Use path=PATTERN or model=PATTERN", matcher))?;
+            let pattern = pattern.trim();
+            if pattern.is_empty() {
+                return Err(format!("Empty pattern in timeout rule matcher \"{}\"", matcher));
+            }
+            let slot = match kind.trim() {
+                "path" => &mut rule.path,
+                "model" => &mut rule.model,
+                other => return Err(format!("Unknown timeout rule matcher \"{}\". Supported: path, model", other)),
+            };
+            if slot.replace(pattern.to_string()).is_some() {
+                return Err(format!("Matcher \"{}\" given twice in timeout rule", kind.trim()));
+            }
+        }
+        if rule.path.is_none() && rule.model.is_none() {
+            return Err(format!("Timeout rule \"{}\" matches nothing. Give path=PATTERN and/or model=PATTERN", s));
+        }
+
+        for setting in settings.split(',').map(str::trim).filter(|setting| !setting.is_empty()) {
+            let (key, value) = setting.split_once('=')
+                .ok_or_else(|| format!("Invalid timeout rule setting \"{}\". Use key=value", setting))?;
+            let (key, value) = (key.trim(), value.trim());
+            let seconds: u32 = value.parse()
+                .map_err(|_| format!("Invalid {} \"{}\" in timeout rule. Must be a number of seconds, 0 for none", key, value))?;
+            let slot = match key {
+                "timeout" => &mut rule.timeout,
+                "first_byte_timeout" => &mut rule.first_byte_timeout,
+                _ => return Err(format!("Unknown timeout rule setting \"{}\". Supported: timeout, first_byte_timeout", key)),
+            };
+            if slot.replace(seconds).is_some() {
+                return Err(format!("Setting \"{}\" given twice in timeout rule", key));
+            }
+        }
+        if rule.timeout.is_none() && rule.first_byte_timeout.is_none() {
+            return Err(format!("Timeout rule \"{}\" changes nothing. Give timeout=SECONDS and/or first_byte_timeout=SECONDS", s));
+        }
+
+        Ok(rule)
+    }
+}
+
+impl TimeoutRule {
+    fn matches(&self, path: &str, model: Option<&str>) -> bool {
+        let path_matches = self.path.as_deref().is_none_or(|pattern| wildcard_matches(pattern, path));
+        // `llama3` and `llama3:latest` are the same model
+        let model_matches = self.model.as_deref().is_none_or(|pattern| model.is_some_and(|model| {
+            wildcard_matches(pattern, model) || wildcard_matches(pattern, &inventory::normalize_model_name(model))
+        }));
+        path_matches && model_matches
+    }
+}
+
+/// The (`--timeout`, `--first-byte-timeout`) seconds for a request: what the first matching rule sets, else the defaults
+pub fn resolve(rules: &[TimeoutRule], path: &str, model: Option<&str>, timeout: u32, first_byte_timeout: u32) -> (u32, u32) {
+    match rules.iter().find(|rule| rule.matches(path, model)) {
+        Some(rule) => (rule.timeout.unwrap_or(timeout), rule.first_byte_timeout.unwrap_or(first_byte_timeout)),
+        None => (timeout, first_byte_timeout),
+    }
+}
+
+/// Whether `text` matches `pattern`, where `*` stands for any run of characters
+fn wildcard_matches(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts: Vec<&str> = parts.collect();
+    // Without a `*`, the whole text must match
+    let Some(last) = parts.pop() else {
+        return rest.is_empty();
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
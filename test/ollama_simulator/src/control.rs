@@ -60,6 +60,7 @@ async fn handle_control_request(
         (Method::POST, "/models") => handle_set_models(req, state).await,
         (Method::POST, "/reset") => handle_reset(req, state).await,
         (Method::POST, "/loaded-model") => handle_set_loaded_model(req, state).await,
+        (Method::POST, "/kv-cache-type") => handle_set_kv_cache_type(req, state).await,
         (Method::GET, path) if path.starts_with("/request-count/") => {
             let port_str = &path["/request-count/".len()..];
             handle_request_count(port_str, state).await
@@ -227,6 +228,37 @@ async fn handle_set_loaded_model(
     }
 }
 
+async fn handle_set_kv_cache_type(
+    req: Request<Body>,
+    state: Arc<RwLock<SimulatorState>>,
+) -> Result<Response<Body>, Box<dyn std::error::Error + Send + Sync>> {
+    let body = hyper::body::to_bytes(req.into_body()).await?;
+
+    #[derive(serde::Deserialize)]
+    struct SetKvCacheTypeRequest {
+        port: u16,
+        kv_cache_type: Option<String>,
+    }
+
+    let request: SetKvCacheTypeRequest = serde_json::from_slice(&body)?;
+
+    let mut state = state.write().await;
+
+    if let Some(server) = state.servers.get_mut(&request.port) {
+        server.kv_cache_type = request.kv_cache_type;
+        Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "application/json")
+            .body(Body::from(r#"{"status":"ok"}"#))
+            .unwrap())
+    } else {
+        Ok(Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::from(format!("Server on port {} not found", request.port)))
+            .unwrap())
+    }
+}
+
 async fn handle_reset(
     req: Request<Body>,
     state: Arc<RwLock<SimulatorState>>,
@@ -247,6 +279,10 @@ async fn handle_reset(
         server.installed_models = vec![ModelInfo::default_test_model()];
         server.active_requests.clear();
         server.kv_cache_tokens.clear();  // Clear KV cache on reset
+        server.kv_cache_type = None;
+        server.kv_cache_restarting = false;
+        server.blobs.clear();
+        server.pull_count = 0;
         if request.clear_counters {
             server.request_count = 0;
         }
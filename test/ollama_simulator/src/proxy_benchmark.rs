@@ -0,0 +1,362 @@
+//! Proxy Overhead Benchmark
+//!
+//! Measures what the load balancer adds on top of talking to an Ollama server directly:
+//! the latency of small requests, and the throughput of multi-megabyte request and response bodies.
+//! Every scenario is run against a simulated server, once directly and once through the load balancer.
+//! The simulated server answers right away, so the difference is the cost of the proxy itself.
+//! On Linux, the CPU time the load balancer spent per request is shown as well- unlike latency,
+//! it hardly depends on what else the machine is doing.
+//!
+//! The load balancer listens on port 11434, so the benchmark can't run alongside the test suite.
+//!
+//! # Usage
+//! ```sh
+//! cargo build --release -p ollama_load_balancer
+//! cargo run --release --bin proxy_benchmark
+//! ```
+
+// Only the simulated server is used, none of the control API
+#[allow(dead_code)]
+mod simulator;
+#[allow(dead_code)]
+mod types;
+
+use std::process::{Child, Command, Stdio};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use futures_util::stream::{self, StreamExt};
+use tokio::sync::RwLock;
+use tokio::time::sleep;
+
+use crate::simulator::SimulatorState;
+use crate::types::ServerBehavior;
+
+/// Port of the simulated server, apart from the ones the test suite uses
+const SERVER_PORT: u16 = 11521;
+/// The load balancer always listens on this port
+const LOAD_BALANCER_PORT: u16 = 11434;
+/// The model installed on the simulated server
+const MODEL: &str = "test-model:latest";
+const MIB: usize = 1024 * 1024;
+
+/// Requests to time, the same ones directly and through the load balancer
+struct Scenario {
+    name: String,
+    path: String,
+    body: Bytes,
+    /// What the simulated server answers with
+    response_body: String,
+    requests: usize,
+    /// Requests in flight at once
+    concurrency: usize,
+}
+
+/// The timings of one scenario against one address
+struct Measurement {
+    /// Time of each request, from sending it until the whole response was read
+    latencies: Vec<Duration>,
+    /// From the first request sent until the last response read
+    elapsed: Duration,
+    /// Request and response bytes of all requests
+    bytes: usize,
+    /// CPU time the load balancer used for all requests, when measured
+    cpu: Option<Duration>,
+}
+
+impl Measurement {
+    fn mean(&self) -> Duration {
+        self.latencies.iter().sum::<Duration>() / self.latencies.len() as u32
+    }
+
+    /// `percent` of the requests took at most this long
+    fn percentile(&self, percent: usize) -> Duration {
+        let mut sorted = self.latencies.clone();
+        sorted.sort();
+        sorted[(sorted.len() * percent / 100).min(sorted.len() - 1)]
+    }
+
+    fn requests_per_sec(&self) -> f64 {
+        self.latencies.len() as f64 / self.elapsed.as_secs_f64()
+    }
+
+    fn mib_per_sec(&self) -> f64 {
+        self.bytes as f64 / MIB as f64 / self.elapsed.as_secs_f64()
+    }
+
+    fn cpu_per_request(&self) -> Option<Duration> {
+        self.cpu.map(|cpu| cpu / self.latencies.len() as u32)
+    }
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .map_err(|e| format!("Failed to create tokio runtime: {}", e))?;
+
+    runtime.block_on(async move {
+        run_benchmark().await
+    })
+}
+
+async fn run_benchmark() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    println!("======================================");
+    println!("  Ollama Load Balancer Proxy Benchmark");
+    println!("======================================");
+    println!();
+
+    let load_balancer_path = find_load_balancer()?;
+    println!("Load balancer: {}", load_balancer_path);
+
+    let state = Arc::new(RwLock::new(SimulatorState::new(vec![SERVER_PORT])));
+    let simulator_state = state.clone();
+    let simulator = tokio::spawn(async move {
+        if let Err(e) = simulator::run_ollama_server(SERVER_PORT, simulator_state).await {
+            eprintln!("Ollama server {} error: {}", SERVER_PORT, e);
+        }
+    });
+    wait_for_port(SERVER_PORT).await?;
+
+    let mut load_balancer = start_load_balancer(&load_balancer_path).await?;
+    println!();
+
+    let result = run_scenarios(&state, load_balancer.id()).await;
+
+    let _ = load_balancer.kill();
+    let _ = load_balancer.wait();
+    simulator.abort();
+    result
+}
+
+async fn run_scenarios(state: &Arc<RwLock<SimulatorState>>, load_balancer_pid: u32) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let client = reqwest::Client::new();
+    let direct = format!("http://127.0.0.1:{}", SERVER_PORT);
+    let load_balancer = format!("http://127.0.0.1:{}", LOAD_BALANCER_PORT);
+
+    for scenario in scenarios() {
+        state.write().await.servers.get_mut(&SERVER_PORT).unwrap().behavior = ServerBehavior::Custom {
+            response_body: scenario.response_body.clone(),
+            status_code: 200,
+            content_type: "application/json; charset=utf-8".to_string(),
+            delay_ms: 0,
+        };
+
+        println!("{} ({} requests, {} at a time)", scenario.name, scenario.requests, scenario.concurrency);
+        let direct = measure(&client, &direct, &scenario, None).await
+            .map_err(|e| format!("{} directly: {}", scenario.name, e))?;
+        let proxied = measure(&client, &load_balancer, &scenario, Some(load_balancer_pid)).await
+            .map_err(|e| format!("{} through the load balancer: {}", scenario.name, e))?;
+
+        for (label, measurement) in [("direct", &direct), ("load balancer", &proxied)] {
+            println!(
+                "  {:<14} mean {:>9}  p50 {:>9}  p99 {:>9}  {:>8.0} req/s  {:>8.1} MiB/s",
+                label,
+                format_ms(measurement.mean()),
+                format_ms(measurement.percentile(50)),
+                format_ms(measurement.percentile(99)),
+                measurement.requests_per_sec(),
+                measurement.mib_per_sec(),
+            );
+        }
+        println!(
+            "  {:<14} mean {:>9}  p50 {:>9}  p99 {:>9}  {:>7.1}% req/s  {:>7.1}% MiB/s",
+            "added",
+            format_added(direct.mean(), proxied.mean()),
+            format_added(direct.percentile(50), proxied.percentile(50)),
+            format_added(direct.percentile(99), proxied.percentile(99)),
+            (proxied.requests_per_sec() / direct.requests_per_sec() - 1.0) * 100.0,
+            (proxied.mib_per_sec() / direct.mib_per_sec() - 1.0) * 100.0,
+        );
+        if let Some(cpu) = proxied.cpu_per_request() {
+            println!("  {:<14} {} per request", "CPU", format_ms(cpu));
+        }
+        println!();
+    }
+    Ok(())
+}
+
+fn scenarios() -> Vec<Scenario> {
+    let chat = |content: serde_json::Value| Bytes::from(serde_json::json!({
+        "model": MODEL,
+        "stream": false,
+        "messages": [content],
+    }).to_string());
+    let small_chat = chat(serde_json::json!({ "role": "user", "content": "Why is the sky blue?" }));
+    let reply = |content: String| serde_json::json!({
+        "model": MODEL,
+        "created_at": "2025-01-01T00:00:00Z",
+        "message": { "role": "assistant", "content": content },
+        "done": true,
+        "done_reason": "stop",
+    }).to_string();
+    let small_reply = reply("Because of Rayleigh scattering.".to_string());
+
+    vec![
+        Scenario {
+            name: "Small chat".to_string(),
+            path: "/api/chat".to_string(),
+            body: small_chat.clone(),
+            response_body: small_reply.clone(),
+            requests: 2000,
+            concurrency: 1,
+        },
+        Scenario {
+            name: "Small chat, concurrent".to_string(),
+            path: "/api/chat".to_string(),
+            body: small_chat.clone(),
+            response_body: small_reply.clone(),
+            requests: 4000,
+            concurrency: 8,
+        },
+        Scenario {
+            name: "Chat with an 8 MiB base64 image".to_string(),
+            path: "/api/chat".to_string(),
+            body: chat(serde_json::json!({ "role": "user", "content": "What is in this picture?", "images": [base64_payload(8 * MIB)] })),
+            response_body: small_reply.clone(),
+            requests: 40,
+            concurrency: 1,
+        },
+        Scenario {
+            name: "Chat with a 16 MiB reply".to_string(),
+            path: "/api/chat".to_string(),
+            body: small_chat,
+            response_body: reply(base64_payload(16 * MIB)),
+            requests: 40,
+            concurrency: 1,
+        },
+        Scenario {
+            // Larger than --retry-body-limit, so it's streamed through rather than kept in memory
+            name: "64 MiB blob upload".to_string(),
+            path: format!("/api/blobs/sha256:{}", "0".repeat(64)),
+            body: Bytes::from(vec![0x5a; 64 * MIB]),
+            response_body: String::new(),
+            requests: 10,
+            concurrency: 1,
+        },
+    ]
+}
+
+/// Base64 text of `length` bytes, like an image attached to a chat
+fn base64_payload(length: usize) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    (0..length).map(|index| ALPHABET[index * 7 % ALPHABET.len()] as char).collect()
+}
+
+/// Sends the scenario's requests to `address`, after a few untimed ones to open connections.
+/// The CPU time of the process `pid` is measured along.
+async fn measure(client: &reqwest::Client, address: &str, scenario: &Scenario, pid: Option<u32>) -> Result<Measurement, String> {
+    let url = format!("{}{}", address, scenario.path);
+    let warmup = scenario.concurrency.max(3);
+    run_requests(client, &url, &scenario.body, warmup, scenario.concurrency).await?;
+
+    let cpu_before = pid.and_then(cpu_time);
+    let start = Instant::now();
+    let (latencies, bytes) = run_requests(client, &url, &scenario.body, scenario.requests, scenario.concurrency).await?;
+    let elapsed = start.elapsed();
+    let cpu = cpu_before.zip(pid.and_then(cpu_time)).map(|(before, after)| after.saturating_sub(before));
+    Ok(Measurement { latencies, elapsed, bytes, cpu })
+}
+
+/// CPU time the process used so far, `None` where there's no `/proc`
+fn cpu_time(pid: u32) -> Option<Duration> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // Fields after the command name, which is in parentheses and may contain spaces. utime and stime come 12th and 13th.
+    let fields: Vec<&str> = stat.rsplit_once(')')?.1.split_whitespace().collect();
+    let ticks = fields.get(11)?.parse::<u64>().ok()? + fields.get(12)?.parse::<u64>().ok()?;
+    // In clock ticks, which are 1/100 second on Linux
+    Some(Duration::from_millis(ticks * 10))
+}
+
+/// Sends `count` requests, `concurrency` at a time. Returns each request's time and the bytes sent and received.
+async fn run_requests(client: &reqwest::Client, url: &str, body: &Bytes, count: usize, concurrency: usize) -> Result<(Vec<Duration>, usize), String> {
+    let results: Vec<Result<(Duration, usize), String>> = stream::iter(0..count)
+        .map(|_| async move {
+            let start = Instant::now();
+            let response = client.post(url)
+                .header("Content-Type", "application/json")
+                .body(body.clone())
+                .send()
+                .await
+                .map_err(|e| format!("request failed: {}", e))?;
+            let status = response.status();
+            let response_body = response.bytes().await.map_err(|e| format!("reading the response failed: {}", e))?;
+            if !status.is_success() {
+                return Err(format!("status {}: {}", status, String::from_utf8_lossy(&response_body)));
+            }
+            Ok((start.elapsed(), body.len() + response_body.len()))
+        })
+        .buffer_unordered(concurrency)
+        .collect()
+        .await;
+
+    let mut latencies = Vec::with_capacity(count);
+    let mut bytes = 0;
+    for result in results {
+        let (latency, length) = result?;
+        latencies.push(latency);
+        bytes += length;
+    }
+    Ok((latencies, bytes))
+}
+
+fn format_ms(duration: Duration) -> String {
+    format!("{:.3} ms", duration.as_secs_f64() * 1000.0)
+}
+
+fn format_added(direct: Duration, proxied: Duration) -> String {
+    format!("{:+.3} ms", (proxied.as_secs_f64() - direct.as_secs_f64()) * 1000.0)
+}
+
+fn find_load_balancer() -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let possible_lb_paths = vec![
+        "../../../target/release/ollama_load_balancer",
+        "../../target/release/ollama_load_balancer",
+        "./target/release/ollama_load_balancer",
+        "../../../target/debug/ollama_load_balancer",
+        "../../target/debug/ollama_load_balancer",
+        "./target/debug/ollama_load_balancer",
+    ];
+
+    for path in possible_lb_paths {
+        let full_path = std::path::Path::new(path);
+        if full_path.exists() {
+            if path.contains("/debug/") {
+                println!("Warning: benchmarking a debug build, run 'cargo build --release' for meaningful numbers");
+            }
+            return Ok(full_path.canonicalize()?.to_string_lossy().to_string());
+        }
+    }
+    Err("Load balancer executable not found. Run 'cargo build --release' first.".into())
+}
+
+async fn wait_for_port(port: u16) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let start = Instant::now();
+    while tokio::net::TcpStream::connect(("127.0.0.1", port)).await.is_err() {
+        if start.elapsed() > Duration::from_secs(5) {
+            return Err(format!("Timeout waiting for port {}", port).into());
+        }
+        sleep(Duration::from_millis(50)).await;
+    }
+    Ok(())
+}
+
+async fn start_load_balancer(load_balancer_path: &str) -> Result<Child, Box<dyn std::error::Error + Send + Sync>> {
+    if tokio::net::TcpListener::bind(("127.0.0.1", LOAD_BALANCER_PORT)).await.is_err() {
+        return Err(format!("Port {} is in use, stop the load balancer or test suite using it first", LOAD_BALANCER_PORT).into());
+    }
+
+    // As many slots as the concurrent scenario sends at once, so that nothing waits in line
+    let child = Command::new(load_balancer_path)
+        .arg(format!("--server=http://127.0.0.1:{}=Bench[slots=8]", SERVER_PORT))
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| format!("Failed to start load balancer: {}", e))?;
+
+    wait_for_port(LOAD_BALANCER_PORT).await?;
+    // Give it a moment to fully initialize
+    sleep(Duration::from_millis(200)).await;
+    Ok(child)
+}
@@ -22,6 +22,9 @@ use crate::types::{
     calculate_prompt_eval, PromptEvalResult,
 };
 
+/// How long the simulated llm_server_windows takes to restart Ollama with another KV cache type
+const KV_CACHE_RESTART_MS: u64 = 1000;
+
 /// Global state for all simulated servers
 #[derive(Debug)]
 pub struct SimulatorState {
@@ -111,6 +114,10 @@ async fn handle_ollama_request(
         (Method::GET, "/api/version") | (Method::HEAD, "/api/version") => {
             handle_version(behavior).await
         }
+        // CORS preflight, for any path
+        (Method::OPTIONS, _) => {
+            handle_cors_preflight(behavior).await
+        }
         // List models
         (Method::GET, "/api/tags") | (Method::HEAD, "/api/tags") => {
             handle_tags(state, port, behavior).await
@@ -159,6 +166,37 @@ async fn handle_ollama_request(
         (Method::POST, "/v1/messages") => {
             handle_v1_messages(req, state, port, behavior).await
         }
+        // Model management
+        (Method::POST, "/api/pull") => {
+            handle_pull(req, state, port).await
+        }
+        (Method::DELETE, "/api/delete") => {
+            handle_delete(req, state, port).await
+        }
+        (Method::POST, "/api/copy") => {
+            handle_copy(req, state, port).await
+        }
+        (Method::POST, "/api/create") => {
+            handle_create(req, state, port).await
+        }
+        (Method::HEAD, path) if path.starts_with("/api/blobs/") => {
+            handle_blob_head(path.strip_prefix("/api/blobs/").unwrap_or(""), state, port).await
+        }
+        (Method::POST, path) if path.starts_with("/api/blobs/") => {
+            let digest = path.strip_prefix("/api/blobs/").unwrap_or("").to_string();
+            handle_blob_upload(req, digest, state, port).await
+        }
+        // llm_server_windows control API, served on the Ollama port of the simulated server
+        (Method::GET, "/health") => {
+            handle_llm_server_health(state, port).await
+        }
+        (Method::POST, "/set-kv-cache") => {
+            handle_llm_server_set_kv_cache(req, state, port).await
+        }
+        // Reports the request as it arrived, for testing the load balancer's proxying
+        (_, "/echo") => {
+            handle_echo(req).await
+        }
         // Catch all
         _ => {
             Ok(Response::builder()
@@ -170,6 +208,348 @@ async fn handle_ollama_request(
     }
 }
 
+/// Delay between the progress lines of a simulated pull or create
+const MODEL_PROGRESS_INTERVAL_MS: u64 = 50;
+
+/// `llama3` and `llama3:latest` are the same model
+fn with_tag(model: &str) -> String {
+    if model.contains(':') {
+        model.to_string()
+    } else {
+        format!("{}:latest", model)
+    }
+}
+
+fn json_response(status: StatusCode, body: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json; charset=utf-8")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+async fn read_json(req: Request<Body>) -> Result<serde_json::Value, Response<Body>> {
+    let body = hyper::body::to_bytes(req.into_body()).await.unwrap_or_default();
+    serde_json::from_slice(&body)
+        .map_err(|e| json_response(StatusCode::BAD_REQUEST, serde_json::json!({ "error": format!("Invalid request: {}", e) }).to_string()))
+}
+
+/// Streams the progress lines one by one, or only the last one with `"stream": false`
+fn progress_response(lines: Vec<serde_json::Value>, stream_progress: bool) -> Response<Body> {
+    if !stream_progress {
+        let last = lines.last().cloned().unwrap_or_default();
+        let status = if last.get("error").is_some() { StatusCode::INTERNAL_SERVER_ERROR } else { StatusCode::OK };
+        return json_response(status, last.to_string());
+    }
+    let body_stream = stream::iter(lines).then(|line| async move {
+        sleep(Duration::from_millis(MODEL_PROGRESS_INTERVAL_MS)).await;
+        Ok::<_, Infallible>(format!("{}\n", line))
+    });
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/x-ndjson")
+        .body(Body::wrap_stream(body_stream))
+        .unwrap()
+}
+
+/// Simulates `ollama pull`. Models whose name starts with "missing" don't exist in the registry.
+async fn handle_pull(
+    req: Request<Body>,
+    state: Arc<RwLock<SimulatorState>>,
+    port: u16,
+) -> Result<Response<Body>, Infallible> {
+    let json = match read_json(req).await {
+        Ok(json) => json,
+        Err(response) => return Ok(response),
+    };
+    let Some(model) = json.get("model").or_else(|| json.get("name")).and_then(|m| m.as_str()).map(with_tag) else {
+        return Ok(json_response(StatusCode::BAD_REQUEST, r#"{"error":"model is required"}"#.to_string()));
+    };
+    let stream_progress = json.get("stream").and_then(|s| s.as_bool()).unwrap_or(true);
+    if let Some(server) = state.write().await.servers.get_mut(&port) {
+        server.pull_count += 1;
+    }
+
+    if model.starts_with("missing") {
+        let lines = vec![
+            serde_json::json!({ "status": "pulling manifest" }),
+            serde_json::json!({ "error": "pull model manifest: file does not exist" }),
+        ];
+        return Ok(progress_response(lines, stream_progress));
+    }
+
+    {
+        let mut state_guard = state.write().await;
+        if let Some(server) = state_guard.servers.get_mut(&port) {
+            if !server.installed_models.iter().any(|m| m.name == model) {
+                server.installed_models.push(ModelInfo { name: model.clone(), ..ModelInfo::default_test_model() });
+            }
+        }
+    }
+    let digest = ModelInfo::default_test_model().digest;
+    let lines = vec![
+        serde_json::json!({ "status": "pulling manifest" }),
+        serde_json::json!({ "status": format!("pulling {}", &digest[..12]), "digest": format!("sha256:{}", digest), "total": 4_000_000_000u64, "completed": 4_000_000_000u64 }),
+        serde_json::json!({ "status": "verifying sha256 digest" }),
+        serde_json::json!({ "status": "writing manifest" }),
+        serde_json::json!({ "status": "success" }),
+    ];
+    Ok(progress_response(lines, stream_progress))
+}
+
+async fn handle_delete(
+    req: Request<Body>,
+    state: Arc<RwLock<SimulatorState>>,
+    port: u16,
+) -> Result<Response<Body>, Infallible> {
+    let json = match read_json(req).await {
+        Ok(json) => json,
+        Err(response) => return Ok(response),
+    };
+    let model = json.get("model").or_else(|| json.get("name")).and_then(|m| m.as_str()).map(with_tag).unwrap_or_default();
+
+    let mut state_guard = state.write().await;
+    let Some(server) = state_guard.servers.get_mut(&port) else {
+        return Ok(json_response(StatusCode::INTERNAL_SERVER_ERROR, r#"{"error":"Server not found"}"#.to_string()));
+    };
+    let count_before = server.installed_models.len();
+    server.installed_models.retain(|m| m.name != model);
+    if server.installed_models.len() == count_before {
+        return Ok(json_response(StatusCode::NOT_FOUND, serde_json::json!({ "error": format!("model '{}' not found", model) }).to_string()));
+    }
+    if server.loaded_model.as_deref() == Some(model.as_str()) {
+        server.loaded_model = None;
+    }
+    Ok(Response::builder().status(StatusCode::OK).body(Body::empty()).unwrap())
+}
+
+async fn handle_copy(
+    req: Request<Body>,
+    state: Arc<RwLock<SimulatorState>>,
+    port: u16,
+) -> Result<Response<Body>, Infallible> {
+    let json = match read_json(req).await {
+        Ok(json) => json,
+        Err(response) => return Ok(response),
+    };
+    let source = json.get("source").and_then(|m| m.as_str()).map(with_tag).unwrap_or_default();
+    let Some(destination) = json.get("destination").and_then(|m| m.as_str()).map(with_tag) else {
+        return Ok(json_response(StatusCode::BAD_REQUEST, r#"{"error":"destination is required"}"#.to_string()));
+    };
+
+    let mut state_guard = state.write().await;
+    let Some(server) = state_guard.servers.get_mut(&port) else {
+        return Ok(json_response(StatusCode::INTERNAL_SERVER_ERROR, r#"{"error":"Server not found"}"#.to_string()));
+    };
+    let Some(source_model) = server.installed_models.iter().find(|m| m.name == source).cloned() else {
+        return Ok(json_response(StatusCode::NOT_FOUND, serde_json::json!({ "error": format!("model '{}' not found", source) }).to_string()));
+    };
+    server.installed_models.retain(|m| m.name != destination);
+    server.installed_models.push(ModelInfo { name: destination, ..source_model });
+    Ok(Response::builder().status(StatusCode::OK).body(Body::empty()).unwrap())
+}
+
+/// Simulates `ollama create` from an installed model
+/// Simulates `ollama create`, either `from` an installed model or from uploaded blobs given in `files`
+async fn handle_create(
+    req: Request<Body>,
+    state: Arc<RwLock<SimulatorState>>,
+    port: u16,
+) -> Result<Response<Body>, Infallible> {
+    let json = match read_json(req).await {
+        Ok(json) => json,
+        Err(response) => return Ok(response),
+    };
+    let Some(model) = json.get("model").or_else(|| json.get("name")).and_then(|m| m.as_str()).map(with_tag) else {
+        return Ok(json_response(StatusCode::BAD_REQUEST, r#"{"error":"model is required"}"#.to_string()));
+    };
+    let from = json.get("from").and_then(|m| m.as_str()).map(with_tag).unwrap_or_default();
+    let files: Vec<String> = json.get("files").and_then(|files| files.as_object())
+        .map(|files| files.values().filter_map(|digest| digest.as_str()).map(String::from).collect())
+        .unwrap_or_default();
+    let stream_progress = json.get("stream").and_then(|s| s.as_bool()).unwrap_or(true);
+
+    let created: Result<(), String> = {
+        let mut state_guard = state.write().await;
+        match state_guard.servers.get_mut(&port) {
+            Some(server) if !files.is_empty() => match files.iter().find(|digest| !server.blobs.contains(digest)) {
+                Some(missing) => Err(format!("blob {} not found", missing)),
+                None => {
+                    server.installed_models.retain(|m| m.name != model);
+                    server.installed_models.push(ModelInfo { name: model.clone(), ..ModelInfo::default_test_model() });
+                    Ok(())
+                }
+            },
+            Some(server) => match server.installed_models.iter().find(|m| m.name == from).cloned() {
+                Some(base) => {
+                    server.installed_models.retain(|m| m.name != model);
+                    server.installed_models.push(ModelInfo { name: model.clone(), ..base });
+                    Ok(())
+                }
+                None => Err(format!("model '{}' not found", from)),
+            },
+            None => Err("Server not found".to_string()),
+        }
+    };
+
+    let lines = match created {
+        Ok(()) => vec![
+            serde_json::json!({ "status": "using existing layer" }),
+            serde_json::json!({ "status": "writing manifest" }),
+            serde_json::json!({ "status": "success" }),
+        ],
+        Err(error) => vec![serde_json::json!({ "error": error })],
+    };
+    Ok(progress_response(lines, stream_progress))
+}
+
+/// Whether the server has a blob, like `ollama create` asks before uploading it
+async fn handle_blob_head(
+    digest: &str,
+    state: Arc<RwLock<SimulatorState>>,
+    port: u16,
+) -> Result<Response<Body>, Infallible> {
+    let state_guard = state.read().await;
+    let has_blob = state_guard.servers.get(&port).is_some_and(|server| server.blobs.iter().any(|blob| blob == digest));
+    let status = if has_blob { StatusCode::OK } else { StatusCode::NOT_FOUND };
+    Ok(Response::builder().status(status).body(Body::empty()).unwrap())
+}
+
+async fn handle_blob_upload(
+    req: Request<Body>,
+    digest: String,
+    state: Arc<RwLock<SimulatorState>>,
+    port: u16,
+) -> Result<Response<Body>, Infallible> {
+    // The content isn't checked against the digest
+    let _ = hyper::body::to_bytes(req.into_body()).await;
+    let mut state_guard = state.write().await;
+    if let Some(server) = state_guard.servers.get_mut(&port) {
+        if !server.blobs.contains(&digest) {
+            server.blobs.push(digest);
+        }
+    }
+    Ok(Response::builder().status(StatusCode::CREATED).body(Body::empty()).unwrap())
+}
+
+/// Answers with the method, URI and headers the request arrived with (values as lossy UTF-8),
+/// and the length and checksum of its body.
+/// The response carries hop-by-hop headers and a header value that isn't UTF-8.
+async fn handle_echo(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let (parts, body) = req.into_parts();
+    let mut headers: HashMap<String, Vec<String>> = HashMap::new();
+    for (name, value) in &parts.headers {
+        headers.entry(name.as_str().to_string()).or_default().push(String::from_utf8_lossy(value.as_bytes()).into_owned());
+    }
+    let body = hyper::body::to_bytes(body).await.unwrap_or_default();
+    let json = serde_json::json!({
+        "method": parts.method.as_str(),
+        "uri": parts.uri.to_string(),
+        "headers": headers,
+        "body_length": body.len(),
+        "body_checksum": body_checksum(&body),
+    });
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .header("Keep-Alive", "timeout=5")
+        .header("Connection", "X-Private")
+        .header("X-Private", "for the load balancer only")
+        .header("X-Binary", &[0xff, 0x41][..])
+        .body(Body::from(json.to_string()))
+        .unwrap())
+}
+
+/// FNV-1a hash of a request body, to tell whether it arrived unchanged
+pub fn body_checksum(body: &[u8]) -> u64 {
+    body.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+async fn handle_llm_server_health(
+    state: Arc<RwLock<SimulatorState>>,
+    port: u16,
+) -> Result<Response<Body>, Infallible> {
+    let state = state.read().await;
+    if state.servers.get(&port).is_some_and(|s| s.kv_cache_restarting) {
+        return Ok(Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .header("Content-Type", "application/json")
+            .body(Body::from(r#"{"status":"restarting"}"#))
+            .unwrap());
+    }
+    match state.servers.get(&port).and_then(|s| s.kv_cache_type.clone()) {
+        Some(kv_cache_type) => {
+            let json = serde_json::json!({"status": "healthy", "kv_cache_type": kv_cache_type});
+            Ok(Response::builder()
+                .status(StatusCode::OK)
+                .header("Content-Type", "application/json")
+                .body(Body::from(json.to_string()))
+                .unwrap())
+        }
+        None => Ok(Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .header("Content-Type", "application/json")
+            .body(Body::from(r#"{"error":"not found"}"#))
+            .unwrap()),
+    }
+}
+
+/// Simulates llm_server_windows restarting Ollama with another KV cache type:
+/// answers 202 right away, `/health` is unavailable during the restart and then reports the new type.
+async fn handle_llm_server_set_kv_cache(
+    req: Request<Body>,
+    state: Arc<RwLock<SimulatorState>>,
+    port: u16,
+) -> Result<Response<Body>, Infallible> {
+    let body = hyper::body::to_bytes(req.into_body()).await.unwrap_or_default();
+    let kv_cache_type = serde_json::from_slice::<serde_json::Value>(&body)
+        .ok()
+        .and_then(|json| json.get("type").and_then(|t| t.as_str()).map(String::from));
+    let Some(kv_cache_type) = kv_cache_type.filter(|t| t == "q8_0" || t == "q16") else {
+        return Ok(Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .header("Content-Type", "application/json")
+            .body(Body::from(r#"{"error":"type must be q8_0 or q16"}"#))
+            .unwrap());
+    };
+
+    {
+        let mut state_guard = state.write().await;
+        match state_guard.servers.get_mut(&port) {
+            Some(server) if server.kv_cache_type.is_some() => {
+                server.kv_cache_restarting = true;
+                // Restarting Ollama unloads everything
+                server.loaded_model = None;
+                server.kv_cache_tokens.clear();
+            }
+            _ => {
+                return Ok(Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .header("Content-Type", "application/json")
+                    .body(Body::from(r#"{"error":"not found"}"#))
+                    .unwrap());
+            }
+        }
+    }
+
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(KV_CACHE_RESTART_MS)).await;
+        let mut state_guard = state.write().await;
+        if let Some(server) = state_guard.servers.get_mut(&port) {
+            if server.kv_cache_restarting {
+                server.kv_cache_restarting = false;
+                server.kv_cache_type = Some(kv_cache_type);
+            }
+        }
+    });
+
+    Ok(Response::builder()
+        .status(StatusCode::ACCEPTED)
+        .header("Content-Type", "application/json")
+        .body(Body::from(r#"{"status":"restarting"}"#))
+        .unwrap())
+}
+
 async fn handle_root(behavior: ServerBehavior) -> Result<Response<Body>, Infallible> {
     if let ServerBehavior::Hang = behavior {
         // Hang indefinitely
@@ -185,6 +565,23 @@ async fn handle_root(behavior: ServerBehavior) -> Result<Response<Body>, Infalli
         .unwrap())
 }
 
+/// Ollama allows browsers from any origin by default
+async fn handle_cors_preflight(behavior: ServerBehavior) -> Result<Response<Body>, Infallible> {
+    if let ServerBehavior::Hang = behavior {
+        loop {
+            sleep(Duration::from_secs(3600)).await;
+        }
+    }
+
+    Ok(Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Access-Control-Allow-Methods", "GET, POST, PUT, PATCH, DELETE, HEAD, OPTIONS")
+        .header("Access-Control-Allow-Headers", "Authorization, Content-Type, User-Agent, Accept, X-Requested-With")
+        .body(Body::empty())
+        .unwrap())
+}
+
 async fn handle_version(behavior: ServerBehavior) -> Result<Response<Body>, Infallible> {
     if let ServerBehavior::Hang = behavior {
         loop {
@@ -846,6 +1243,9 @@ async fn handle_v1_chat_completions(
         stream: Option<bool>,
         #[serde(default)]
         max_tokens: Option<usize>,
+        /// Thinking models stream their reasoning first when asked for it
+        #[serde(default)]
+        reasoning_effort: Option<String>,
     }
 
     let chat_req: V1ChatRequest = match serde_json::from_slice(&body) {
@@ -873,7 +1273,9 @@ async fn handle_v1_chat_completions(
     match behavior {
         ServerBehavior::Normal { tokens_per_sec, load_delay_ms, .. } => {
             if stream {
-                let chunks = generate_v1_streaming_chunks(&model_name, num_tokens, tokens_per_sec, load_delay_ms);
+                let reasoning = chat_req.reasoning_effort.map(|_| Ok(generate_v1_reasoning_chunk(&model_name)));
+                let chunks = stream::iter(reasoning)
+                    .chain(generate_v1_streaming_chunks(&model_name, num_tokens, tokens_per_sec, load_delay_ms));
                 return Ok(Response::builder()
                     .status(StatusCode::OK)
                     .header("Content-Type", "text/event-stream")
@@ -1610,6 +2012,23 @@ fn generate_v1_streaming_chunks(
     )
 }
 
+/// An OpenAI-compatible streaming chunk carrying reasoning, the way Ollama streams a thinking model's thoughts
+fn generate_v1_reasoning_chunk(model: &str) -> bytes::Bytes {
+    let chunk = serde_json::json!({
+        "id": format!("chatcmpl-{}", uuid::Uuid::new_v4().to_string().chars().take(8).collect::<String>()),
+        "object": "chat.completion.chunk",
+        "created": Utc::now().timestamp(),
+        "model": model,
+        "system_fingerprint": "fp_ollama",
+        "choices": [{
+            "index": 0,
+            "delta": {"role": "assistant", "content": "", "reasoning": "The user wants a greeting."},
+            "finish_reason": null
+        }]
+    });
+    bytes::Bytes::from(format!("data: {}\n\n", chunk))
+}
+
 /// Generate OpenAI-compatible non-streaming response
 fn generate_v1_non_streaming_response(model: &str, num_tokens: usize) -> serde_json::Value {
     serde_json::json!({
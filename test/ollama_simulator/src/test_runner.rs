@@ -53,7 +53,7 @@ use tokio::sync::RwLock;
 use tokio::time::sleep;
 
 use crate::simulator::SimulatorState;
-use crate::types::{ServerBehavior, TestResult};
+use crate::types::{ModelInfo, ServerBehavior, TestResult};
 
 /// Configuration for the test run
 struct TestConfig {
@@ -164,6 +164,93 @@ async fn run_tests() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     // Test 15: Graceful TCP shutdown (FIN)
     results.push(test_tcp_graceful_shutdown(&config).await);
 
+    // Test 16: Model-aware routing
+    results.push(test_model_aware_routing(&config, state.clone()).await);
+
+    // Test 17: /api/tags answered by the load balancer
+    results.push(test_aggregated_tags(&config, state.clone()).await);
+
+    // Test 18: /v1/models answered by the load balancer
+    results.push(test_aggregated_v1_models(&config, state.clone()).await);
+
+    // Test 19: /api/ps answered by the load balancer
+    results.push(test_aggregated_ps(&config, state.clone()).await);
+
+    // Test 20: Prefer servers that already have the model loaded
+    results.push(test_hot_model_preference(&config, state.clone()).await);
+
+    // Test 21: Capability and speed annotations
+    results.push(test_capability_and_speed(&config, state.clone()).await);
+
+    // Test 22: Conversation affinity
+    results.push(test_conversation_affinity(&config, state.clone()).await);
+
+    // Test 23: Conversation affinity across OpenAI, Anthropic and Ollama APIs
+    results.push(test_cross_api_affinity(&config, state.clone()).await);
+
+    // Test 24: Prompt-prefix affinity for raw completions
+    results.push(test_prompt_prefix_affinity(&config, state.clone()).await);
+
+    // Test 25: KV cache type aware routing
+    results.push(test_kv_cache_type_routing(&config, state.clone()).await);
+
+    // Test 26: Automatic KV cache reconfiguration
+    results.push(test_kv_cache_reconfiguration(&config, state.clone()).await);
+
+    // Test 27: Per-server concurrency slots
+    results.push(test_concurrency_slots(&config, state.clone()).await);
+
+    // Test 28: Waiting queue when all servers are busy
+    results.push(test_waiting_queue(&config, state.clone()).await);
+
+    // Test 29: Priority classes for waiting requests
+    results.push(test_priority_classes(&config, state.clone()).await);
+
+    // Test 30: Fair sharing of servers between clients
+    results.push(test_fair_sharing(&config, state.clone()).await);
+
+    // Test 31: Transparent retry on another server
+    results.push(test_transparent_retry(&config, state.clone()).await);
+
+    // Test 32: Separate first byte and between-chunk timeouts
+    results.push(test_separate_timeouts(&config, state.clone()).await);
+
+    // Test 33: Timeout overrides per endpoint and model
+    results.push(test_timeout_rules(&config, state.clone()).await);
+
+    // Test 34: Metadata requests don't need a free server
+    results.push(test_metadata_requests(&config, state.clone()).await);
+
+    // Test 35: Model management on several servers at once
+    results.push(test_model_management_fanout(&config, state.clone()).await);
+
+    // Test 36: Declarative model placement and reconciliation
+    results.push(test_model_placement(&config, state.clone()).await);
+
+    // Test 37: Blob uploads and the create that uses them stay on one server, uploads take no slot
+    results.push(test_blob_upload_pinning(&config, state.clone()).await);
+
+    // Test 38: Query strings, hop-by-hop and forwarding headers, non-UTF-8 header values
+    results.push(test_proxy_header_fidelity(&config).await);
+
+    // Test 39: A kept-alive connection closed by the server doesn't count against it
+    results.push(test_stale_keep_alive_connection(&config, state.clone()).await);
+
+    // Test 40: Multi-megabyte request and response bodies arrive byte for byte, oversized inference bodies get 413
+    results.push(test_large_body_passthrough(&config).await);
+
+    // Test 41: The wait for the first byte starts once a slowly streamed body is uploaded
+    results.push(test_slow_streamed_upload(&config).await);
+
+    // Test 42: Conversation affinity ranks above the capability tier and a hot model
+    results.push(test_affinity_beats_hot_server(&config, state.clone()).await);
+
+    // Test 43: A request holds out a while for a busy server of the right KV cache type before another one is restarted
+    results.push(test_reconfigure_wait(&config, state.clone()).await);
+
+    // Test 44: A client's blobs stay on one server, a create from blobs on several servers gets 409
+    results.push(test_blob_session_pinning(&config, state.clone()).await);
+
     let total_duration = test_start.elapsed();
 
     // Print results
@@ -367,6 +454,16 @@ async fn wait_for_simulator_ready(config: &TestConfig) -> Result<(), Box<dyn std
 }
 
 async fn start_load_balancer(config: &TestConfig) -> Result<Child, Box<dyn std::error::Error + Send + Sync>> {
+    start_load_balancer_with(config, &[], &[]).await
+}
+
+/// Like `start_load_balancer`, but `annotations[i]` (e.g. `"[capability=10]"`) is appended
+/// to the i-th server's name, and `extra_args` are passed to the load balancer as-is.
+async fn start_load_balancer_with(
+    config: &TestConfig,
+    annotations: &[&str],
+    extra_args: &[&str],
+) -> Result<Child, Box<dyn std::error::Error + Send + Sync>> {
     // First, ensure the port is free
     let port_check_start = Instant::now();
     while port_check_start.elapsed() < Duration::from_secs(3) {
@@ -379,13 +476,17 @@ async fn start_load_balancer(config: &TestConfig) -> Result<Child, Box<dyn std::
         }
     }
 
-    let mut args = vec![
-        format!("--timeout={}", config.load_balancer_timeout),
-    ];
+    // Short timeouts, unless the test sets its own
+    let mut args: Vec<String> = ["--timeout", "--first-byte-timeout"].iter()
+        .filter(|flag| !extra_args.iter().any(|arg| arg.starts_with(&format!("{}=", flag))))
+        .map(|flag| format!("{}={}", flag, config.load_balancer_timeout))
+        .collect();
 
-    for port in &config.server_ports {
-        args.push(format!("--server=http://127.0.0.1:{}=Server{}", port, port));
+    for (index, port) in config.server_ports.iter().enumerate() {
+        let annotation = annotations.get(index).copied().unwrap_or_default();
+        args.push(format!("--server=http://127.0.0.1:{}=Server{}{}", port, port, annotation));
     }
+    args.extend(extra_args.iter().map(|arg| arg.to_string()));
 
     let child = Command::new(&config.load_balancer_path)
         .args(&args)
@@ -481,6 +582,72 @@ async fn set_all_servers_behavior(
     Ok(())
 }
 
+async fn set_server_models(
+    config: &TestConfig,
+    port: u16,
+    model_names: &[&str],
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let models: Vec<ModelInfo> = model_names.iter().map(|name| ModelInfo {
+        name: name.to_string(),
+        ..ModelInfo::default_test_model()
+    }).collect();
+    let client = reqwest::Client::new();
+    client.post(&format!("http://127.0.0.1:{}/models", config.control_port))
+        .json(&serde_json::json!({
+            "port": port,
+            "models": models
+        }))
+        .send()
+        .await?;
+    Ok(())
+}
+
+/// Sets the model a simulated server reports as loaded in `/api/ps` (`None` for nothing loaded)
+async fn set_loaded_model(
+    config: &TestConfig,
+    port: u16,
+    model: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let client = reqwest::Client::new();
+    client.post(&format!("http://127.0.0.1:{}/loaded-model", config.control_port))
+        .json(&serde_json::json!({
+            "port": port,
+            "model": model
+        }))
+        .send()
+        .await?;
+    Ok(())
+}
+
+/// Sets the KV cache type the simulated llm_server_windows of a server reports (`None` for no llm_server_windows)
+async fn set_kv_cache_type(
+    config: &TestConfig,
+    port: u16,
+    kv_cache_type: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let client = reqwest::Client::new();
+    client.post(&format!("http://127.0.0.1:{}/kv-cache-type", config.control_port))
+        .json(&serde_json::json!({
+            "port": port,
+            "kv_cache_type": kv_cache_type
+        }))
+        .send()
+        .await?;
+    Ok(())
+}
+
+/// Server annotations pointing the load balancer at the simulated llm_server_windows `/health`,
+/// which the simulator serves on each server's own port
+fn control_port_annotations(config: &TestConfig) -> Vec<String> {
+    config.server_ports.iter().map(|port| format!("[control_port={}]", port)).collect()
+}
+
+/// The load balancer polls `/api/tags` and `/api/ps` in the background right after starting.
+/// Give that first poll a moment to complete before relying on the inventory.
+async fn wait_for_inventory_poll() {
+    sleep(Duration::from_millis(500)).await;
+}
+
 // ============================================================================
 // TEST IMPLEMENTATIONS
 // ============================================================================
@@ -620,7 +787,7 @@ async fn test_server_unreachable(
             .timeout(Duration::from_secs(15))
             .build()?;
 
-        // First request goes to hanging server, should timeout and try another
+        // First request goes to hanging server, should timeout without being retried on another
         // Note: Load balancer has short timeout (5s), so this should complete
         let response = client.post(&format!("http://127.0.0.1:{}/api/chat", config.load_balancer_port))
             .json(&serde_json::json!({
@@ -631,7 +798,7 @@ async fn test_server_unreachable(
             .send()
             .await?;
 
-        // The load balancer should return an error for the first server (Bad Gateway)
+        // The load balancer should return an error for the first server (Gateway Timeout)
         let status1 = response.status();
 
         // Make another request - it should go to a working server (second server)
@@ -648,15 +815,12 @@ async fn test_server_unreachable(
 
         stop_load_balancer(lb).await;
 
-        // First request should fail (502 Bad Gateway from timeout)
+        // First request should fail (504 Gateway Timeout from --first-byte-timeout)
         // Second request should succeed (going to a working server)
-        if status1 == reqwest::StatusCode::BAD_GATEWAY && status2.is_success() {
+        if status1 == reqwest::StatusCode::GATEWAY_TIMEOUT && status2.is_success() {
             Ok::<_, Box<dyn std::error::Error + Send + Sync>>(())
-        } else if status2.is_success() {
-            // Even if first didn't fail as expected, second should succeed
-            Ok(())
         } else {
-            Err(format!("Expected first=502 second=200, got first={} second={}", status1, status2).into())
+            Err(format!("Expected first=504 second=200, got first={} second={}", status1, status2).into())
         }
     }.await;
 
@@ -832,7 +996,8 @@ async fn test_all_servers_busy(
             num_tokens: 100,
         }).await?;
 
-        let lb = start_load_balancer(config).await?;
+        // Without a waiting line (see the waiting queue test for requests that wait)
+        let lb = start_load_balancer_with(config, &[], &["--max-queue-length=0"]).await?;
 
         let client = reqwest::Client::builder()
             .timeout(Duration::from_secs(30))
@@ -1516,6 +1681,7 @@ async fn test_tcp_radio_silence(config: &TestConfig) -> TestResult {
         // Start load balancer pointing ONLY to the freeze_server
         let lb_args = vec![
             format!("--timeout={}", 2), // 2 second timeout for faster test
+            format!("--first-byte-timeout={}", 2),
             format!("--server=http://127.0.0.1:{}=FreezeServer", freeze_port),
         ];
 
@@ -1829,3 +1995,2813 @@ async fn test_tcp_graceful_shutdown(config: &TestConfig) -> TestResult {
         duration_ms: start.elapsed().as_millis() as u64,
     }
 }
+
+// ============================================================================
+// MODEL-AWARE ROUTING TESTS
+// ============================================================================
+
+/// Test 16: Inference requests only go to servers that have the model installed
+///
+/// Each server gets a different inventory. A request for a model that only the
+/// last server has must be served by that server even though the others are free,
+/// and a request for a model nobody has must be rejected with 404 without reaching
+/// any server.
+async fn test_model_aware_routing(
+    config: &TestConfig,
+    state: Arc<RwLock<SimulatorState>>,
+) -> TestResult {
+    let name = "Model-aware routing".to_string();
+    let start = Instant::now();
+
+    let result: Result<(), Box<dyn std::error::Error + Send + Sync>> = async {
+        reset_simulator(config).await?;
+
+        set_server_models(config, config.server_ports[0], &["alpha:latest"]).await?;
+        set_server_models(config, config.server_ports[1], &["alpha:latest"]).await?;
+        set_server_models(config, config.server_ports[2], &["beta:7b"]).await?;
+
+        let lb = start_load_balancer(config).await?;
+        wait_for_inventory_poll().await;
+
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()?;
+
+        // Only the third server has beta:7b
+        let response = client.post(&format!("http://127.0.0.1:{}/api/chat", config.load_balancer_port))
+            .json(&serde_json::json!({
+                "model": "beta:7b",
+                "messages": [{"role": "user", "content": "Hello"}],
+                "stream": false
+            }))
+            .send()
+            .await?;
+        let beta_status = response.status();
+
+        // A tagless name means :latest, just like in Ollama
+        let response = client.post(&format!("http://127.0.0.1:{}/v1/chat/completions", config.load_balancer_port))
+            .json(&serde_json::json!({
+                "model": "alpha",
+                "messages": [{"role": "user", "content": "Hello"}]
+            }))
+            .send()
+            .await?;
+        let alpha_status = response.status();
+
+        // No server has this model
+        let response = client.post(&format!("http://127.0.0.1:{}/api/generate", config.load_balancer_port))
+            .json(&serde_json::json!({
+                "model": "missing-model:latest",
+                "prompt": "Hello",
+                "stream": false
+            }))
+            .send()
+            .await?;
+        let missing_status = response.status();
+        let missing_body: serde_json::Value = response.json().await?;
+
+        stop_load_balancer(lb).await;
+
+        let loaded: Vec<Option<String>> = {
+            let state = state.read().await;
+            config.server_ports.iter()
+                .map(|port| state.servers.get(port).and_then(|s| s.loaded_model.clone()))
+                .collect()
+        };
+
+        if !beta_status.is_success() {
+            return Err(format!("Request for beta:7b failed with {}", beta_status).into());
+        }
+        if loaded[2].as_deref() != Some("beta:7b") {
+            return Err(format!("beta:7b should have been served by the third server, loaded models: {:?}", loaded).into());
+        }
+        if !alpha_status.is_success() {
+            return Err(format!("Request for alpha failed with {}", alpha_status).into());
+        }
+        if missing_status != reqwest::StatusCode::NOT_FOUND {
+            return Err(format!("Expected 404 for unknown model, got {}", missing_status).into());
+        }
+        if !missing_body["error"].as_str().unwrap_or("").contains("missing-model:latest") {
+            return Err(format!("Expected Ollama-style error for unknown model, got {}", missing_body).into());
+        }
+        if loaded.iter().any(|m| m.as_deref() == Some("missing-model:latest")) {
+            return Err("Request for unknown model reached a server".into());
+        }
+        Ok(())
+    }.await;
+
+    let error_message = match &result {
+        Ok(()) => String::new(),
+        Err(e) => e.to_string(),
+    };
+    TestResult {
+        name,
+        passed: result.is_ok(),
+        message: error_message,
+        duration_ms: start.elapsed().as_millis() as u64,
+    }
+}
+
+/// Starts `count` slow streaming chat requests for `model` and waits until they occupy servers.
+/// Abort the returned handles to release the servers.
+async fn occupy_servers(
+    config: &TestConfig,
+    model: &str,
+    count: usize,
+) -> Vec<tokio::task::JoinHandle<Result<reqwest::Response, reqwest::Error>>> {
+    let client = reqwest::Client::new();
+    let url = format!("http://127.0.0.1:{}/api/chat", config.load_balancer_port);
+    let handles = (0..count).map(|_| {
+        let client = client.clone();
+        let url = url.clone();
+        let model = model.to_string();
+        tokio::spawn(async move {
+            client.post(&url)
+                .json(&serde_json::json!({
+                    "model": model,
+                    "messages": [{"role": "user", "content": "Slow request"}],
+                    "stream": true
+                }))
+                .send()
+                .await
+        })
+    }).collect();
+    sleep(Duration::from_millis(200)).await;
+    handles
+}
+
+/// Test 17: `GET /api/tags` returns the union of all servers' models, even while every server is busy
+async fn test_aggregated_tags(
+    config: &TestConfig,
+    _state: Arc<RwLock<SimulatorState>>,
+) -> TestResult {
+    let name = "Aggregated /api/tags".to_string();
+    let start = Instant::now();
+
+    let result: Result<(), Box<dyn std::error::Error + Send + Sync>> = async {
+        reset_simulator(config).await?;
+
+        set_server_models(config, config.server_ports[0], &["test-model:latest", "alpha:latest"]).await?;
+        set_server_models(config, config.server_ports[1], &["test-model:latest", "alpha:latest"]).await?;
+        set_server_models(config, config.server_ports[2], &["test-model:latest", "beta:latest"]).await?;
+        set_all_servers_behavior(config, &ServerBehavior::Slow {
+            tokens_per_sec: 1.0,
+            num_tokens: 100,
+        }).await?;
+
+        let lb = start_load_balancer(config).await?;
+        wait_for_inventory_poll().await;
+
+        let busy_handles = occupy_servers(config, "test-model:latest", config.server_ports.len()).await;
+
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()?;
+        let response = client.get(&format!("http://127.0.0.1:{}/api/tags", config.load_balancer_port))
+            .send()
+            .await?;
+        let status = response.status();
+        let body: serde_json::Value = response.json().await?;
+
+        for handle in busy_handles {
+            handle.abort();
+        }
+        stop_load_balancer(lb).await;
+
+        if !status.is_success() {
+            return Err(format!("Expected /api/tags to succeed while all servers are busy, got {}", status).into());
+        }
+        let mut names: Vec<&str> = body["models"].as_array()
+            .ok_or("'models' is not an array")?
+            .iter()
+            .filter_map(|m| m["name"].as_str())
+            .collect();
+        names.sort();
+        if names != vec!["alpha:latest", "beta:latest", "test-model:latest"] {
+            return Err(format!("Expected each model exactly once, got {:?}", names).into());
+        }
+        Ok(())
+    }.await;
+
+    let error_message = match &result {
+        Ok(()) => String::new(),
+        Err(e) => e.to_string(),
+    };
+    TestResult {
+        name,
+        passed: result.is_ok(),
+        message: error_message,
+        duration_ms: start.elapsed().as_millis() as u64,
+    }
+}
+
+/// Test 18: `GET /v1/models` and `GET /v1/models/{id}` are synthesized from the cluster inventory
+async fn test_aggregated_v1_models(
+    config: &TestConfig,
+    _state: Arc<RwLock<SimulatorState>>,
+) -> TestResult {
+    let name = "Aggregated /v1/models".to_string();
+    let start = Instant::now();
+
+    let result: Result<(), Box<dyn std::error::Error + Send + Sync>> = async {
+        reset_simulator(config).await?;
+
+        set_server_models(config, config.server_ports[0], &["alpha:latest"]).await?;
+        set_server_models(config, config.server_ports[1], &["alpha:latest"]).await?;
+        set_server_models(config, config.server_ports[2], &["beta:7b"]).await?;
+
+        let lb = start_load_balancer(config).await?;
+        wait_for_inventory_poll().await;
+
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()?;
+        let base = format!("http://127.0.0.1:{}", config.load_balancer_port);
+
+        let list: serde_json::Value = client.get(&format!("{}/v1/models", base)).send().await?.json().await?;
+        let alpha_status = client.get(&format!("{}/v1/models/alpha", base)).send().await?.status();
+        let beta: serde_json::Value = client.get(&format!("{}/v1/models/beta%3A7b", base)).send().await?.json().await?;
+        let missing = client.get(&format!("{}/v1/models/missing-model", base)).send().await?;
+        let missing_status = missing.status();
+        let missing_body: serde_json::Value = missing.json().await?;
+
+        stop_load_balancer(lb).await;
+
+        if list["object"] != "list" {
+            return Err(format!("Expected an OpenAI list object, got {}", list).into());
+        }
+        let data = list["data"].as_array().ok_or("'data' is not an array")?;
+        let alpha_entry = data.iter().find(|m| m["id"] == "alpha:latest")
+            .ok_or_else(|| format!("alpha:latest missing from {}", list))?;
+        let expected_owners = format!("Server{}, Server{}", config.server_ports[0], config.server_ports[1]);
+        if alpha_entry["owned_by"] != expected_owners.as_str() {
+            return Err(format!("Expected alpha:latest owned_by '{}', got {}", expected_owners, alpha_entry["owned_by"]).into());
+        }
+        if data.len() != 2 {
+            return Err(format!("Expected 2 models, got {}", list).into());
+        }
+        if !alpha_status.is_success() {
+            return Err(format!("Expected /v1/models/alpha to resolve to alpha:latest, got {}", alpha_status).into());
+        }
+        if beta["id"] != "beta:7b" || beta["owned_by"] != format!("Server{}", config.server_ports[2]).as_str() {
+            return Err(format!("Unexpected /v1/models/beta:7b response: {}", beta).into());
+        }
+        if missing_status != reqwest::StatusCode::NOT_FOUND || missing_body["error"]["code"] != "model_not_found" {
+            return Err(format!("Expected OpenAI-style 404, got {} {}", missing_status, missing_body).into());
+        }
+        Ok(())
+    }.await;
+
+    let error_message = match &result {
+        Ok(()) => String::new(),
+        Err(e) => e.to_string(),
+    };
+    TestResult {
+        name,
+        passed: result.is_ok(),
+        message: error_message,
+        duration_ms: start.elapsed().as_millis() as u64,
+    }
+}
+
+/// Test 19: `GET /api/ps` merges the loaded models of all servers, each tagged with its server name
+async fn test_aggregated_ps(
+    config: &TestConfig,
+    _state: Arc<RwLock<SimulatorState>>,
+) -> TestResult {
+    let name = "Aggregated /api/ps".to_string();
+    let start = Instant::now();
+
+    let result: Result<(), Box<dyn std::error::Error + Send + Sync>> = async {
+        reset_simulator(config).await?;
+
+        set_server_models(config, config.server_ports[0], &["alpha:latest"]).await?;
+        set_server_models(config, config.server_ports[1], &["alpha:latest"]).await?;
+        set_server_models(config, config.server_ports[2], &["beta:7b"]).await?;
+        set_loaded_model(config, config.server_ports[0], Some("alpha:latest")).await?;
+        set_loaded_model(config, config.server_ports[1], None).await?;
+        set_loaded_model(config, config.server_ports[2], Some("beta:7b")).await?;
+
+        let lb = start_load_balancer(config).await?;
+        wait_for_inventory_poll().await;
+
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()?;
+        let ps: serde_json::Value = client
+            .get(&format!("http://127.0.0.1:{}/api/ps", config.load_balancer_port))
+            .send()
+            .await?
+            .json()
+            .await?;
+
+        stop_load_balancer(lb).await;
+
+        let models = ps["models"].as_array().ok_or_else(|| format!("'models' is not an array: {}", ps))?;
+        let loaded: Vec<(String, String)> = models.iter()
+            .map(|m| (m["name"].as_str().unwrap_or_default().to_string(), m["server"].as_str().unwrap_or_default().to_string()))
+            .collect();
+        let expected = vec![
+            ("alpha:latest".to_string(), format!("Server{}", config.server_ports[0])),
+            ("beta:7b".to_string(), format!("Server{}", config.server_ports[2])),
+        ];
+        if loaded != expected {
+            return Err(format!("Expected loaded models {:?}, got {:?}", expected, loaded).into());
+        }
+        if models.iter().any(|m| m.get("size_vram").is_none()) {
+            return Err(format!("Expected entries to keep the server's fields, got {}", ps).into());
+        }
+        Ok(())
+    }.await;
+
+    let error_message = match &result {
+        Ok(()) => String::new(),
+        Err(e) => e.to_string(),
+    };
+    TestResult {
+        name,
+        passed: result.is_ok(),
+        message: error_message,
+        duration_ms: start.elapsed().as_millis() as u64,
+    }
+}
+
+/// Test 20: Among free reliable servers, the one that already has the model loaded is chosen over CLI order
+async fn test_hot_model_preference(
+    config: &TestConfig,
+    state: Arc<RwLock<SimulatorState>>,
+) -> TestResult {
+    let name = "Hot model preference".to_string();
+    let start = Instant::now();
+
+    let result: Result<(), Box<dyn std::error::Error + Send + Sync>> = async {
+        reset_simulator(config).await?;
+
+        for port in &config.server_ports {
+            set_server_models(config, *port, &["alpha:latest", "beta:7b"]).await?;
+        }
+        set_loaded_model(config, config.server_ports[0], Some("beta:7b")).await?;
+        set_loaded_model(config, config.server_ports[1], Some("beta:7b")).await?;
+        set_loaded_model(config, config.server_ports[2], Some("alpha:latest")).await?;
+
+        let lb = start_load_balancer(config).await?;
+        wait_for_inventory_poll().await;
+
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()?;
+
+        for _ in 0..2 {
+            let response = client.post(&format!("http://127.0.0.1:{}/api/chat", config.load_balancer_port))
+                .json(&serde_json::json!({
+                    "model": "alpha:latest",
+                    "messages": [{"role": "user", "content": "Hello"}],
+                    "stream": false
+                }))
+                .send()
+                .await?;
+            if !response.status().is_success() {
+                return Err(format!("Request for alpha failed with {}", response.status()).into());
+            }
+        }
+
+        stop_load_balancer(lb).await;
+
+        let loaded: Vec<Option<String>> = {
+            let state = state.read().await;
+            config.server_ports.iter()
+                .map(|port| state.servers.get(port).and_then(|s| s.loaded_model.clone()))
+                .collect()
+        };
+        let expected = vec![Some("beta:7b".to_string()), Some("beta:7b".to_string()), Some("alpha:latest".to_string())];
+        if loaded != expected {
+            return Err(format!("alpha should only have been served by the server that had it loaded, loaded models: {:?}", loaded).into());
+        }
+        Ok(())
+    }.await;
+
+    let error_message = match &result {
+        Ok(()) => String::new(),
+        Err(e) => e.to_string(),
+    };
+    TestResult {
+        name,
+        passed: result.is_ok(),
+        message: error_message,
+        duration_ms: start.elapsed().as_millis() as u64,
+    }
+}
+
+/// Test 21: `--server URL=Name[capability=..,speed=..]`- the lowest capability tier that has the model wins,
+/// then the fastest server. Invalid annotations are rejected at startup.
+async fn test_capability_and_speed(
+    config: &TestConfig,
+    state: Arc<RwLock<SimulatorState>>,
+) -> TestResult {
+    let name = "Capability and speed annotations".to_string();
+    let start = Instant::now();
+
+    let result: Result<(), Box<dyn std::error::Error + Send + Sync>> = async {
+        // Invalid annotations must make the load balancer exit instead of silently ignoring them
+        for invalid in ["[capability=101]", "[color=red]", "[speed=-1]", "[speed=5,speed=6]"] {
+            let status = Command::new(&config.load_balancer_path)
+                .arg(format!("--server=http://127.0.0.1:{}=Server{}", config.server_ports[0], invalid))
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status()?;
+            if status.success() {
+                return Err(format!("Load balancer accepted invalid annotation {}", invalid).into());
+            }
+        }
+
+        reset_simulator(config).await?;
+
+        set_server_models(config, config.server_ports[0], &["alpha:latest", "beta:7b"]).await?;
+        set_server_models(config, config.server_ports[1], &["alpha:latest"]).await?;
+        set_server_models(config, config.server_ports[2], &["alpha:latest"]).await?;
+
+        let lb = start_load_balancer_with(
+            config,
+            &["[capability=80,speed=100]", "[capability=10]", "[capability=10, speed=50]"],
+            &[],
+        ).await?;
+        wait_for_inventory_poll().await;
+
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()?;
+
+        let mut loaded_after_each = Vec::new();
+        for model in ["alpha:latest", "beta:7b"] {
+            let response = client.post(&format!("http://127.0.0.1:{}/api/chat", config.load_balancer_port))
+                .json(&serde_json::json!({
+                    "model": model,
+                    "messages": [{"role": "user", "content": "Hello"}],
+                    "stream": false
+                }))
+                .send()
+                .await?;
+            if !response.status().is_success() {
+                return Err(format!("Request for {} failed with {}", model, response.status()).into());
+            }
+            let state = state.read().await;
+            let loaded: Vec<Option<String>> = config.server_ports.iter()
+                .map(|port| state.servers.get(port).and_then(|s| s.loaded_model.clone()))
+                .collect();
+            loaded_after_each.push(loaded);
+        }
+
+        stop_load_balancer(lb).await;
+
+        // alpha: capability 10 tier over capability 80, then speed 50 over speed 0
+        if loaded_after_each[0] != vec![None, None, Some("alpha:latest".to_string())] {
+            return Err(format!("alpha should have been served by the fastest low-capability server, loaded models: {:?}", loaded_after_each[0]).into());
+        }
+        // beta: only the high capability server has it
+        if loaded_after_each[1][0].as_deref() != Some("beta:7b") {
+            return Err(format!("beta:7b should have been served by the only server that has it, loaded models: {:?}", loaded_after_each[1]).into());
+        }
+        Ok(())
+    }.await;
+
+    let error_message = match &result {
+        Ok(()) => String::new(),
+        Err(e) => e.to_string(),
+    };
+    TestResult {
+        name,
+        passed: result.is_ok(),
+        message: error_message,
+        duration_ms: start.elapsed().as_millis() as u64,
+    }
+}
+
+/// Test 22: The next turn of a conversation goes back to the server that has its beginning in the KV cache,
+/// even when a server earlier in CLI order is free.
+async fn test_conversation_affinity(
+    config: &TestConfig,
+    state: Arc<RwLock<SimulatorState>>,
+) -> TestResult {
+    let name = "Conversation affinity".to_string();
+    let start = Instant::now();
+
+    let result: Result<(), Box<dyn std::error::Error + Send + Sync>> = async {
+        reset_simulator(config).await?;
+
+        // Make sure no inventory poll request lands in between and skews the request counts
+        let lb = start_load_balancer_with(config, &[], &["--poll-interval=600"]).await?;
+        wait_for_inventory_poll().await;
+
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()?;
+        let url = format!("http://127.0.0.1:{}/api/chat", config.load_balancer_port);
+        let request_counts = || async {
+            let state = state.read().await;
+            config.server_ports.iter()
+                .map(|port| state.servers.get(port).map(|s| s.request_count).unwrap_or_default())
+                .collect::<Vec<u64>>()
+        };
+
+        // Keep the first server busy so that the first turn lands on the second server
+        set_server_behavior(config, config.server_ports[0], &ServerBehavior::Slow {
+            tokens_per_sec: 1.0,
+            num_tokens: 100,
+        }).await?;
+        let busy_handles = occupy_servers(config, "test-model:latest", 1).await;
+        set_server_behavior(config, config.server_ports[0], &ServerBehavior::default()).await?;
+
+        let mut messages = vec![
+            serde_json::json!({"role": "system", "content": "You are a coding agent"}),
+            serde_json::json!({"role": "user", "content": "List the files"}),
+        ];
+        let before_first_turn = request_counts().await;
+        let first_turn: serde_json::Value = client.post(&url)
+            .json(&serde_json::json!({"model": "test-model:latest", "messages": messages, "stream": false}))
+            .send()
+            .await?
+            .json()
+            .await?;
+        let after_first_turn = request_counts().await;
+
+        for handle in busy_handles {
+            handle.abort();
+        }
+        sleep(Duration::from_millis(300)).await;
+
+        // Second turn extends the conversation with the reply and a new user message
+        messages.push(first_turn["message"].clone());
+        messages.push(serde_json::json!({"role": "user", "content": "Now read main.rs"}));
+        let response = client.post(&url)
+            .json(&serde_json::json!({"model": "test-model:latest", "messages": messages, "stream": false}))
+            .send()
+            .await?;
+        let second_status = response.status();
+        let after_second_turn = request_counts().await;
+
+        // An unrelated conversation has no affinity, so CLI order decides
+        let response = client.post(&url)
+            .json(&serde_json::json!({
+                "model": "test-model:latest",
+                "messages": [
+                    {"role": "system", "content": "You are a different agent"},
+                    {"role": "user", "content": "List the files"},
+                    {"role": "assistant", "content": "main.rs"},
+                    {"role": "user", "content": "Now read main.rs"}
+                ],
+                "stream": false
+            }))
+            .send()
+            .await?;
+        let unrelated_status = response.status();
+        let after_unrelated = request_counts().await;
+
+        stop_load_balancer(lb).await;
+
+        if first_turn["message"]["role"] != "assistant" {
+            return Err(format!("Unexpected first turn response: {}", first_turn).into());
+        }
+        if after_first_turn[1] != before_first_turn[1] + 1 {
+            return Err(format!("First turn should have been served by the second server, request counts {:?} -> {:?}", before_first_turn, after_first_turn).into());
+        }
+        if !second_status.is_success() || after_second_turn[1] != after_first_turn[1] + 1 {
+            return Err(format!("Second turn should have returned to the second server ({}), request counts {:?} -> {:?}", second_status, after_first_turn, after_second_turn).into());
+        }
+        if !unrelated_status.is_success() || after_unrelated[0] != after_second_turn[0] + 1 {
+            return Err(format!("Unrelated conversation should have been served by the first server ({}), request counts {:?} -> {:?}", unrelated_status, after_second_turn, after_unrelated).into());
+        }
+        Ok(())
+    }.await;
+
+    let error_message = match &result {
+        Ok(()) => String::new(),
+        Err(e) => e.to_string(),
+    };
+    TestResult {
+        name,
+        passed: result.is_ok(),
+        message: error_message,
+        duration_ms: start.elapsed().as_millis() as u64,
+    }
+}
+
+/// Test 23: A conversation started through the OpenAI API (streamed over SSE, reasoning first), continued through
+/// the Anthropic API and then through the native Ollama API keeps returning to the server that has it cached.
+async fn test_cross_api_affinity(
+    config: &TestConfig,
+    state: Arc<RwLock<SimulatorState>>,
+) -> TestResult {
+    let name = "Cross-API conversation affinity".to_string();
+    let start = Instant::now();
+
+    let result: Result<(), Box<dyn std::error::Error + Send + Sync>> = async {
+        reset_simulator(config).await?;
+
+        let lb = start_load_balancer_with(config, &[], &["--poll-interval=600"]).await?;
+        wait_for_inventory_poll().await;
+
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()?;
+        let base = format!("http://127.0.0.1:{}", config.load_balancer_port);
+        let request_counts = || async {
+            let state = state.read().await;
+            config.server_ports.iter()
+                .map(|port| state.servers.get(port).map(|s| s.request_count).unwrap_or_default())
+                .collect::<Vec<u64>>()
+        };
+
+        // Keep the first server busy so that the first turn lands on the second server
+        set_server_behavior(config, config.server_ports[0], &ServerBehavior::Slow {
+            tokens_per_sec: 1.0,
+            num_tokens: 100,
+        }).await?;
+        let busy_handles = occupy_servers(config, "test-model:latest", 1).await;
+        set_server_behavior(config, config.server_ports[0], &ServerBehavior::default()).await?;
+
+        // Turn 1: OpenAI, streamed, with reasoning that the next turns don't send back
+        let before = request_counts().await;
+        let sse = client.post(&format!("{}/v1/chat/completions", base))
+            .json(&serde_json::json!({
+                "model": "test-model:latest",
+                "messages": [
+                    {"role": "system", "content": "You are a coding agent"},
+                    {"role": "user", "content": "List the files"}
+                ],
+                "max_tokens": 8,
+                "reasoning_effort": "low",
+                "stream": true
+            }))
+            .send()
+            .await?
+            .text()
+            .await?;
+        let first_reply: String = sse.lines()
+            .filter_map(|line| line.strip_prefix("data: "))
+            .filter_map(|data| serde_json::from_str::<serde_json::Value>(data).ok())
+            .filter_map(|chunk| chunk["choices"][0]["delta"]["content"].as_str().map(str::to_string))
+            .collect();
+        let after_first = request_counts().await;
+
+        for handle in busy_handles {
+            handle.abort();
+        }
+        sleep(Duration::from_millis(300)).await;
+
+        // Turn 2: Anthropic, with the system prompt as a separate field and content blocks
+        let second: serde_json::Value = client.post(&format!("{}/v1/messages", base))
+            .json(&serde_json::json!({
+                "model": "test-model:latest",
+                "max_tokens": 20,
+                "system": "You are a coding agent",
+                "messages": [
+                    {"role": "user", "content": "List the files"},
+                    {"role": "assistant", "content": [{"type": "text", "text": first_reply}]},
+                    {"role": "user", "content": [{"type": "text", "text": "Now read main.rs"}]}
+                ]
+            }))
+            .send()
+            .await?
+            .json()
+            .await?;
+        let second_reply = second["content"][0]["text"].as_str().unwrap_or_default().to_string();
+        let after_second = request_counts().await;
+
+        // Turn 3: native Ollama
+        let response = client.post(&format!("{}/api/chat", base))
+            .json(&serde_json::json!({
+                "model": "test-model:latest",
+                "messages": [
+                    {"role": "system", "content": "You are a coding agent"},
+                    {"role": "user", "content": "List the files"},
+                    {"role": "assistant", "content": first_reply},
+                    {"role": "user", "content": "Now read main.rs"},
+                    {"role": "assistant", "content": second_reply},
+                    {"role": "user", "content": "Fix the bug"}
+                ],
+                "stream": false
+            }))
+            .send()
+            .await?;
+        let third_status = response.status();
+        let after_third = request_counts().await;
+
+        stop_load_balancer(lb).await;
+
+        if first_reply.is_empty() || second_reply.is_empty() {
+            return Err(format!("Expected replies, got {:?} and {}", first_reply, second).into());
+        }
+        if !sse.contains(r#""reasoning":"#) {
+            return Err(format!("Expected the first turn to stream reasoning, got {}", sse).into());
+        }
+        if after_first[1] != before[1] + 1 {
+            return Err(format!("First turn should have been served by the second server, request counts {:?} -> {:?}", before, after_first).into());
+        }
+        if after_second[1] != after_first[1] + 1 {
+            return Err(format!("Anthropic turn should have returned to the second server, request counts {:?} -> {:?}", after_first, after_second).into());
+        }
+        if !third_status.is_success() || after_third[1] != after_second[1] + 1 {
+            return Err(format!("Ollama turn should have returned to the second server ({}), request counts {:?} -> {:?}", third_status, after_second, after_third).into());
+        }
+        Ok(())
+    }.await;
+
+    let error_message = match &result {
+        Ok(()) => String::new(),
+        Err(e) => e.to_string(),
+    };
+    TestResult {
+        name,
+        passed: result.is_ok(),
+        message: error_message,
+        duration_ms: start.elapsed().as_millis() as u64,
+    }
+}
+
+/// Test 24: A `/api/generate` prompt that shares a long beginning with a server's last prompt goes to that server,
+/// a prompt sharing less than `--min-shared-prefix` characters doesn't.
+async fn test_prompt_prefix_affinity(
+    config: &TestConfig,
+    state: Arc<RwLock<SimulatorState>>,
+) -> TestResult {
+    let name = "Prompt-prefix affinity".to_string();
+    let start = Instant::now();
+
+    let result: Result<(), Box<dyn std::error::Error + Send + Sync>> = async {
+        reset_simulator(config).await?;
+
+        let lb = start_load_balancer_with(config, &[], &["--poll-interval=600", "--min-shared-prefix=100"]).await?;
+        wait_for_inventory_poll().await;
+
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()?;
+        let url = format!("http://127.0.0.1:{}/api/generate", config.load_balancer_port);
+        let request_counts = || async {
+            let state = state.read().await;
+            config.server_ports.iter()
+                .map(|port| state.servers.get(port).map(|s| s.request_count).unwrap_or_default())
+                .collect::<Vec<u64>>()
+        };
+        let generate = |prompt: String| {
+            let request = client.post(&url)
+                .json(&serde_json::json!({"model": "test-model:latest", "prompt": prompt, "raw": true, "stream": false}));
+            async move { request.send().await?.error_for_status() }
+        };
+
+        // Keep the first server busy so that the first prompt lands on the second server
+        set_server_behavior(config, config.server_ports[0], &ServerBehavior::Slow {
+            tokens_per_sec: 1.0,
+            num_tokens: 100,
+        }).await?;
+        let busy_handles = occupy_servers(config, "test-model:latest", 1).await;
+        set_server_behavior(config, config.server_ports[0], &ServerBehavior::default()).await?;
+
+        let file_start = "fn main() {\n    let servers = load_servers();\n".repeat(5);
+        let before = request_counts().await;
+        generate(format!("{}    // cursor at line 11", file_start)).await?;
+        let after_first = request_counts().await;
+
+        for handle in busy_handles {
+            handle.abort();
+        }
+        sleep(Duration::from_millis(300)).await;
+
+        // Same file, cursor moved- shares well over 100 characters
+        generate(format!("{}    println!(\"cursor moved\");", file_start)).await?;
+        let after_second = request_counts().await;
+
+        // Shares only "fn main() {" with the cached prompt
+        generate("fn main() {\n    unrelated();\n}".to_string()).await?;
+        let after_short = request_counts().await;
+
+        stop_load_balancer(lb).await;
+
+        if after_first[1] != before[1] + 1 {
+            return Err(format!("First prompt should have been served by the second server, request counts {:?} -> {:?}", before, after_first).into());
+        }
+        if after_second[1] != after_first[1] + 1 {
+            return Err(format!("Prompt with a shared prefix should have returned to the second server, request counts {:?} -> {:?}", after_first, after_second).into());
+        }
+        if after_short[0] != after_second[0] + 1 {
+            return Err(format!("Prompt sharing too little should have been served by the first server, request counts {:?} -> {:?}", after_second, after_short).into());
+        }
+        Ok(())
+    }.await;
+
+    let error_message = match &result {
+        Ok(()) => String::new(),
+        Err(e) => e.to_string(),
+    };
+    TestResult {
+        name,
+        passed: result.is_ok(),
+        message: error_message,
+        duration_ms: start.elapsed().as_millis() as u64,
+    }
+}
+
+/// Test 25: Models given with `--kv-q8` go to servers running q8_0 KV cache, other models to q16 servers.
+/// A server whose llm_server_windows doesn't answer is assumed to run q8_0.
+async fn test_kv_cache_type_routing(
+    config: &TestConfig,
+    state: Arc<RwLock<SimulatorState>>,
+) -> TestResult {
+    let name = "KV cache type aware routing".to_string();
+    let start = Instant::now();
+
+    let result: Result<(), Box<dyn std::error::Error + Send + Sync>> = async {
+        reset_simulator(config).await?;
+
+        for port in &config.server_ports {
+            set_server_models(config, *port, &["test-model:latest", "q8-model:latest"]).await?;
+        }
+        set_kv_cache_type(config, config.server_ports[0], Some("q16")).await?;
+        set_kv_cache_type(config, config.server_ports[1], Some("q16")).await?;
+        set_kv_cache_type(config, config.server_ports[2], None).await?;
+
+        let annotations = control_port_annotations(config);
+        let annotations: Vec<&str> = annotations.iter().map(String::as_str).collect();
+        let lb = start_load_balancer_with(config, &annotations, &["--poll-interval=600", "--kv-q8=q8-model"]).await?;
+        wait_for_inventory_poll().await;
+
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()?;
+        let request_counts = || async {
+            let state = state.read().await;
+            config.server_ports.iter()
+                .map(|port| state.servers.get(port).map(|s| s.request_count).unwrap_or_default())
+                .collect::<Vec<u64>>()
+        };
+        let chat = |model: &str| {
+            let request = client.post(&format!("http://127.0.0.1:{}/api/chat", config.load_balancer_port))
+                .json(&serde_json::json!({
+                    "model": model,
+                    "messages": [{"role": "user", "content": "Hello"}],
+                    "stream": false
+                }));
+            async move { request.send().await?.error_for_status() }
+        };
+
+        let before = request_counts().await;
+        chat("q8-model:latest").await?;
+        let after_q8 = request_counts().await;
+        chat("test-model:latest").await?;
+        let after_q16 = request_counts().await;
+
+        stop_load_balancer(lb).await;
+
+        if after_q8[2] != before[2] + 1 {
+            return Err(format!("q8-model should have been served by the server assumed to run q8_0, request counts {:?} -> {:?}", before, after_q8).into());
+        }
+        if after_q16[0] != after_q8[0] + 1 {
+            return Err(format!("test-model should have been served by the first q16 server, request counts {:?} -> {:?}", after_q8, after_q16).into());
+        }
+        Ok(())
+    }.await;
+
+    let error_message = match &result {
+        Ok(()) => String::new(),
+        Err(e) => e.to_string(),
+    };
+    TestResult {
+        name,
+        passed: result.is_ok(),
+        message: error_message,
+        duration_ms: start.elapsed().as_millis() as u64,
+    }
+}
+
+/// Test 26: With only q8_0 servers free, concurrent requests for a q16 model each get their own server switched
+/// through `POST /set-kv-cache`. A q16 server of a higher capability tier is used rather than restarting a q8_0 one.
+async fn test_kv_cache_reconfiguration(
+    config: &TestConfig,
+    state: Arc<RwLock<SimulatorState>>,
+) -> TestResult {
+    let name = "Automatic KV cache reconfiguration".to_string();
+    let start = Instant::now();
+
+    let result: Result<(), Box<dyn std::error::Error + Send + Sync>> = async {
+        reset_simulator(config).await?;
+
+        // test-model wants q16, but only servers running q8_0 (or assumed to) have it
+        set_kv_cache_type(config, config.server_ports[0], Some("q8_0")).await?;
+        set_kv_cache_type(config, config.server_ports[1], Some("q8_0")).await?;
+        set_kv_cache_type(config, config.server_ports[2], None).await?;
+
+        let annotations = control_port_annotations(config);
+        let annotations: Vec<&str> = annotations.iter().map(String::as_str).collect();
+        let lb = start_load_balancer_with(config, &annotations, &["--poll-interval=600"]).await?;
+        wait_for_inventory_poll().await;
+
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(30))
+            .build()?;
+        let request_counts = || async {
+            let state = state.read().await;
+            config.server_ports.iter()
+                .map(|port| state.servers.get(port).map(|s| s.request_count).unwrap_or_default())
+                .collect::<Vec<u64>>()
+        };
+        let chat = || {
+            let request = client.post(&format!("http://127.0.0.1:{}/api/chat", config.load_balancer_port))
+                .json(&serde_json::json!({
+                    "model": "test-model:latest",
+                    "messages": [{"role": "user", "content": "Hello"}],
+                    "stream": false
+                }));
+            async move { request.send().await?.error_for_status() }
+        };
+
+        // Two requests at once must each get their own server switched, never the same one
+        let before = request_counts().await;
+        let (first, second) = tokio::join!(chat(), chat());
+        first?;
+        second?;
+        let after = request_counts().await;
+
+        let kv_cache_types: Vec<Option<String>> = {
+            let state = state.read().await;
+            config.server_ports.iter()
+                .map(|port| state.servers.get(port).and_then(|s| s.kv_cache_type.clone()))
+                .collect()
+        };
+
+        // Now that server 0 runs q16, it serves the model right away
+        let switched_at = Instant::now();
+        chat().await?;
+        let third_duration = switched_at.elapsed();
+
+        stop_load_balancer(lb).await;
+
+        // A q16 server of a higher capability tier beats restarting a q8_0 one of the lowest tier
+        set_kv_cache_type(config, config.server_ports[0], Some("q8_0")).await?;
+        set_kv_cache_type(config, config.server_ports[1], Some("q16")).await?;
+        let annotations = [
+            format!("[control_port={}]", config.server_ports[0]),
+            format!("[control_port={},capability=10]", config.server_ports[1]),
+            format!("[control_port={},capability=10]", config.server_ports[2]),
+        ];
+        let annotations: Vec<&str> = annotations.iter().map(String::as_str).collect();
+        let lb = start_load_balancer_with(config, &annotations, &["--poll-interval=600"]).await?;
+        wait_for_inventory_poll().await;
+        let before_tiers = request_counts().await;
+        chat().await?;
+        let after_tiers = request_counts().await;
+        let lowest_tier_kv_cache_type = state.read().await.servers.get(&config.server_ports[0]).and_then(|s| s.kv_cache_type.clone());
+        stop_load_balancer(lb).await;
+
+        if kv_cache_types[0].as_deref() != Some("q16") || kv_cache_types[1].as_deref() != Some("q16") {
+            return Err(format!("Both servers with llm_server_windows should have been switched to q16, KV cache types {:?}", kv_cache_types).into());
+        }
+        if after[2] != before[2] {
+            return Err(format!("The server without llm_server_windows can't be switched and shouldn't have been used, request counts {:?} -> {:?}", before, after).into());
+        }
+        if third_duration >= Duration::from_millis(900) {
+            return Err(format!("A server already switched to q16 shouldn't be restarted again, request took {:?}", third_duration).into());
+        }
+        if lowest_tier_kv_cache_type.as_deref() != Some("q8_0") || after_tiers[1] != before_tiers[1] + 1 {
+            return Err(format!("The q16 server of the higher tier should have served test-model without a restart, lowest tier KV cache {:?}, request counts {:?} -> {:?}", lowest_tier_kv_cache_type, before_tiers, after_tiers).into());
+        }
+        Ok(())
+    }.await;
+
+    let error_message = match &result {
+        Ok(()) => String::new(),
+        Err(e) => e.to_string(),
+    };
+    TestResult {
+        name,
+        passed: result.is_ok(),
+        message: error_message,
+        duration_ms: start.elapsed().as_millis() as u64,
+    }
+}
+
+/// Test 27: A server annotated `[slots=2]` takes two requests at once, and with every slot busy the next request gets 503
+async fn test_concurrency_slots(
+    config: &TestConfig,
+    state: Arc<RwLock<SimulatorState>>,
+) -> TestResult {
+    let name = "Per-server concurrency slots".to_string();
+    let start = Instant::now();
+
+    let result: Result<(), Box<dyn std::error::Error + Send + Sync>> = async {
+        for invalid in ["[slots=0]", "[slots=two]"] {
+            let status = Command::new(&config.load_balancer_path)
+                .arg(format!("--server=http://127.0.0.1:{}=Server{}", config.server_ports[0], invalid))
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status()?;
+            if status.success() {
+                return Err(format!("Load balancer accepted invalid annotation {}", invalid).into());
+            }
+        }
+
+        reset_simulator(config).await?;
+        set_all_servers_behavior(config, &ServerBehavior::Slow {
+            tokens_per_sec: 1.0,
+            num_tokens: 100,
+        }).await?;
+
+        let lb = start_load_balancer_with(config, &["[slots=2]"], &["--poll-interval=600", "--max-queue-length=0"]).await?;
+        wait_for_inventory_poll().await;
+
+        let request_counts = || async {
+            let state = state.read().await;
+            config.server_ports.iter()
+                .map(|port| state.servers.get(port).map(|s| s.request_count).unwrap_or_default())
+                .collect::<Vec<u64>>()
+        };
+
+        // 4 slots in total: 2 on the first server, 1 on each of the others
+        let before = request_counts().await;
+        let handles = occupy_servers(config, "test-model:latest", 4).await;
+        // On a busy machine the requests may take a moment longer to reach the servers
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while request_counts().await.iter().sum::<u64>() < before.iter().sum::<u64>() + 4 && Instant::now() < deadline {
+            sleep(Duration::from_millis(50)).await;
+        }
+        let after = request_counts().await;
+
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()?;
+        let response = client.post(&format!("http://127.0.0.1:{}/api/chat", config.load_balancer_port))
+            .json(&serde_json::json!({
+                "model": "test-model:latest",
+                "messages": [{"role": "user", "content": "Should be rejected"}],
+                "stream": false
+            }))
+            .send()
+            .await?;
+        let status = response.status();
+
+        for handle in handles {
+            handle.abort();
+        }
+        stop_load_balancer(lb).await;
+
+        let deltas: Vec<u64> = after.iter().zip(&before).map(|(after, before)| after - before).collect();
+        if deltas != [2, 1, 1] {
+            return Err(format!("Expected 2 requests on the server with 2 slots and 1 on each other server, got {:?}", deltas).into());
+        }
+        if status != reqwest::StatusCode::SERVICE_UNAVAILABLE {
+            return Err(format!("Expected 503 once every slot is busy, got {}", status).into());
+        }
+        Ok(())
+    }.await;
+
+    let error_message = match &result {
+        Ok(()) => String::new(),
+        Err(e) => e.to_string(),
+    };
+    TestResult {
+        name,
+        passed: result.is_ok(),
+        message: error_message,
+        duration_ms: start.elapsed().as_millis() as u64,
+    }
+}
+
+/// Test 28: Requests wait in line for a busy server- a client that disconnects leaves the line,
+/// a full line rejects right away, and waiting longer than `--max-queue-wait` gets 503.
+async fn test_waiting_queue(
+    config: &TestConfig,
+    _state: Arc<RwLock<SimulatorState>>,
+) -> TestResult {
+    let name = "Waiting queue when all servers are busy".to_string();
+    let start = Instant::now();
+
+    let result: Result<(), Box<dyn std::error::Error + Send + Sync>> = async {
+        reset_simulator(config).await?;
+        set_all_servers_behavior(config, &ServerBehavior::Slow {
+            tokens_per_sec: 1.0,
+            num_tokens: 100,
+        }).await?;
+
+        let lb = start_load_balancer_with(config, &[], &["--poll-interval=600", "--max-queue-length=1", "--max-queue-wait=2"]).await?;
+        wait_for_inventory_poll().await;
+
+        let mut handles = occupy_servers(config, "test-model:latest", 3).await;
+        // Requests that get a server from now on are answered right away
+        set_all_servers_behavior(config, &ServerBehavior::default()).await?;
+
+        let url = format!("http://127.0.0.1:{}/api/chat", config.load_balancer_port);
+        let chat = |timeout: Duration| {
+            let request = reqwest::Client::builder()
+                .timeout(timeout)
+                .build()
+                .map(|client| client.post(&url)
+                    .json(&serde_json::json!({
+                        "model": "test-model:latest",
+                        "messages": [{"role": "user", "content": "Waiting"}],
+                        "stream": false
+                    })));
+            async move { request?.send().await }
+        };
+
+        // A client that gives up while waiting must leave the line, or it would keep the only place taken
+        let _ = chat(Duration::from_millis(300)).await;
+
+        let waiting = tokio::spawn(chat(Duration::from_secs(10)));
+        sleep(Duration::from_millis(300)).await;
+
+        // The line holds one request, the next one is turned away right away
+        let full_started = Instant::now();
+        let full = chat(Duration::from_secs(10)).await?.status();
+        let full_duration = full_started.elapsed();
+
+        // Free a server, the waiting request gets it
+        handles.remove(0).abort();
+        let waited = waiting.await??.status();
+
+        // Nothing frees up in time for this one
+        set_all_servers_behavior(config, &ServerBehavior::Slow {
+            tokens_per_sec: 1.0,
+            num_tokens: 100,
+        }).await?;
+        handles.extend(occupy_servers(config, "test-model:latest", 1).await);
+        let timed_out_started = Instant::now();
+        let timed_out = chat(Duration::from_secs(10)).await?.status();
+        let timed_out_duration = timed_out_started.elapsed();
+
+        for handle in handles {
+            handle.abort();
+        }
+        stop_load_balancer(lb).await;
+
+        if full != reqwest::StatusCode::SERVICE_UNAVAILABLE || full_duration >= Duration::from_secs(1) {
+            return Err(format!("Expected an immediate 503 with the waiting line full, got {} after {:?}", full, full_duration).into());
+        }
+        if waited != reqwest::StatusCode::OK {
+            return Err(format!("Expected the waiting request to be served once a server was freed, got {}", waited).into());
+        }
+        if timed_out != reqwest::StatusCode::SERVICE_UNAVAILABLE || timed_out_duration < Duration::from_millis(1900) {
+            return Err(format!("Expected 503 after waiting 2 seconds, got {} after {:?}", timed_out, timed_out_duration).into());
+        }
+        Ok(())
+    }.await;
+
+    let error_message = match &result {
+        Ok(()) => String::new(),
+        Err(e) => e.to_string(),
+    };
+    TestResult {
+        name,
+        passed: result.is_ok(),
+        message: error_message,
+        duration_ms: start.elapsed().as_millis() as u64,
+    }
+}
+
+/// Test 29: Waiting requests are served high, normal, then low priority, and a low request that waited
+/// long enough overtakes a fresh normal one (`--priority-aging`). Invalid rules are rejected at startup.
+async fn test_priority_classes(
+    config: &TestConfig,
+    _state: Arc<RwLock<SimulatorState>>,
+) -> TestResult {
+    let name = "Priority classes for waiting requests".to_string();
+    let start = Instant::now();
+
+    let result: Result<(), Box<dyn std::error::Error + Send + Sync>> = async {
+        for invalid in ["urgent:ip=127.0.0.1", "low:ip=127.0.0.1/33", "low:color=red", "low"] {
+            let status = Command::new(&config.load_balancer_path)
+                .arg(format!("--server=http://127.0.0.1:{}=Server", config.server_ports[0]))
+                .arg(format!("--priority={}", invalid))
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status()?;
+            if status.success() {
+                return Err(format!("Load balancer accepted invalid priority rule {}", invalid).into());
+            }
+        }
+
+        reset_simulator(config).await?;
+        let slow = ServerBehavior::Slow {
+            tokens_per_sec: 1.0,
+            num_tokens: 100,
+        };
+
+        let lb = start_load_balancer_with(config, &[], &[
+            "--poll-interval=600",
+            "--max-queue-wait=15",
+            "--priority=low:user-agent=batch-job",
+            "--priority-aging=2",
+        ]).await?;
+        wait_for_inventory_poll().await;
+
+        let url = format!("http://127.0.0.1:{}/api/chat", config.load_balancer_port);
+        // Resolves to when the request was answered
+        let spawn_chat = |headers: &[(&'static str, &'static str)]| {
+            let mut request = reqwest::Client::new().post(&url)
+                .timeout(Duration::from_secs(20))
+                .json(&serde_json::json!({
+                    "model": "test-model:latest",
+                    "messages": [{"role": "user", "content": "Waiting"}],
+                    "stream": false
+                }));
+            for (name, value) in headers {
+                request = request.header(*name, *value);
+            }
+            tokio::spawn(async move {
+                let response = request.send().await?.error_for_status()?;
+                Ok::<_, reqwest::Error>((Instant::now(), response))
+            })
+        };
+
+        // Low, then normal, then high priority requests wait for the three busy servers
+        set_all_servers_behavior(config, &slow).await?;
+        let mut handles = occupy_servers(config, "test-model:latest", 3).await;
+        set_all_servers_behavior(config, &ServerBehavior::default()).await?;
+        let low = spawn_chat(&[("User-Agent", "batch-job/1.0")]);
+        sleep(Duration::from_millis(100)).await;
+        let normal = spawn_chat(&[]);
+        sleep(Duration::from_millis(100)).await;
+        let high = spawn_chat(&[("X-LB-Priority", "high")]);
+        sleep(Duration::from_millis(200)).await;
+        for handle in handles.drain(..) {
+            handle.abort();
+            sleep(Duration::from_millis(300)).await;
+        }
+        let (low_done, _) = low.await??;
+        let (normal_done, _) = normal.await??;
+        let (high_done, _) = high.await??;
+
+        // A low priority request that waited long enough overtakes a normal one that just arrived
+        set_all_servers_behavior(config, &slow).await?;
+        let mut handles = occupy_servers(config, "test-model:latest", 3).await;
+        set_all_servers_behavior(config, &ServerBehavior::default()).await?;
+        let aged_low = spawn_chat(&[("User-Agent", "batch-job/1.0")]);
+        sleep(Duration::from_millis(4500)).await;
+        let fresh_normal = spawn_chat(&[]);
+        sleep(Duration::from_millis(200)).await;
+        for handle in handles.drain(..) {
+            handle.abort();
+            sleep(Duration::from_millis(300)).await;
+        }
+        let (aged_low_done, _) = aged_low.await??;
+        let (fresh_normal_done, _) = fresh_normal.await??;
+
+        stop_load_balancer(lb).await;
+
+        if !(high_done < normal_done && normal_done < low_done) {
+            return Err("Expected the high priority request to be served first, then normal, then low".into());
+        }
+        if aged_low_done > fresh_normal_done {
+            return Err("Expected the low priority request that waited 4.5 seconds to overtake the normal one that just arrived".into());
+        }
+        Ok(())
+    }.await;
+
+    let error_message = match &result {
+        Ok(()) => String::new(),
+        Err(e) => e.to_string(),
+    };
+    TestResult {
+        name,
+        passed: result.is_ok(),
+        message: error_message,
+        duration_ms: start.elapsed().as_millis() as u64,
+    }
+}
+
+/// Test 30: With `--fairness=api-key --max-per-client=2`, one client can't take every server,
+/// and freed servers alternate between the clients that have requests waiting.
+async fn test_fair_sharing(
+    config: &TestConfig,
+    _state: Arc<RwLock<SimulatorState>>,
+) -> TestResult {
+    let name = "Fair sharing between clients".to_string();
+    let start = Instant::now();
+
+    let result: Result<(), Box<dyn std::error::Error + Send + Sync>> = async {
+        reset_simulator(config).await?;
+        set_all_servers_behavior(config, &ServerBehavior::Slow {
+            tokens_per_sec: 1.0,
+            num_tokens: 100,
+        }).await?;
+
+        let lb = start_load_balancer_with(config, &[], &[
+            "--poll-interval=600",
+            "--fairness=api-key",
+            "--max-per-client=2",
+        ]).await?;
+        wait_for_inventory_poll().await;
+
+        let url = format!("http://127.0.0.1:{}/api/chat", config.load_balancer_port);
+        // Finishes once a server started streaming the reply, and keeps that server busy until awaited and dropped
+        let stream_chat = |api_key: &str| {
+            let request = reqwest::Client::new().post(&url)
+                .bearer_auth(api_key)
+                .json(&serde_json::json!({
+                    "model": "test-model:latest",
+                    "messages": [{"role": "user", "content": "Slow request"}],
+                    "stream": true
+                }));
+            tokio::spawn(async move { request.send().await })
+        };
+        let settle = || sleep(Duration::from_millis(300));
+
+        // Client A gets 2 servers, its other 2 requests wait although a server is free
+        let a1 = stream_chat("client-a");
+        sleep(Duration::from_millis(100)).await;
+        let a2 = stream_chat("client-a");
+        sleep(Duration::from_millis(100)).await;
+        let a3 = stream_chat("client-a");
+        sleep(Duration::from_millis(100)).await;
+        let a4 = stream_chat("client-a");
+        settle().await;
+        let capped = a1.is_finished() && a2.is_finished() && !a3.is_finished() && !a4.is_finished();
+
+        // Client B gets the free server right away
+        let b1 = stream_chat("client-b");
+        settle().await;
+        let b1_served = b1.is_finished();
+        let b2 = stream_chat("client-b");
+        settle().await;
+
+        // Waiting: a3, a4, b2. Freed servers alternate between the clients instead of going to a4 before b2
+        drop(a1.await??);
+        settle().await;
+        let first_turn = (a3.is_finished(), a4.is_finished(), b2.is_finished());
+        drop(a2.await??);
+        settle().await;
+        let second_turn = (a3.is_finished(), a4.is_finished(), b2.is_finished());
+
+        for handle in [a3, a4, b1, b2] {
+            handle.abort();
+        }
+        stop_load_balancer(lb).await;
+
+        if !capped {
+            return Err("Expected client A to get only 2 servers at once while its other requests wait".into());
+        }
+        if !b1_served {
+            return Err("Expected client B to get the free server while client A's requests wait".into());
+        }
+        if first_turn != (true, false, false) {
+            return Err(format!("Expected client A's oldest waiting request to get the first freed server, (a3, a4, b2) served: {:?}", first_turn).into());
+        }
+        if second_turn != (true, false, true) {
+            return Err(format!("Expected client B to get the second freed server, (a3, a4, b2) served: {:?}", second_turn).into());
+        }
+        Ok(())
+    }.await;
+
+    let error_message = match &result {
+        Ok(()) => String::new(),
+        Err(e) => e.to_string(),
+    };
+    TestResult {
+        name,
+        passed: result.is_ok(),
+        message: error_message,
+        duration_ms: start.elapsed().as_millis() as u64,
+    }
+}
+
+/// Test 31: A request whose first choice can't be reached is served by the next best server,
+/// unless its body is beyond `--retry-body-limit` or `--max-attempts=1`.
+async fn test_transparent_retry(
+    config: &TestConfig,
+    state: Arc<RwLock<SimulatorState>>,
+) -> TestResult {
+    let name = "Transparent retry on another server".to_string();
+    let start = Instant::now();
+
+    let result: Result<(), Box<dyn std::error::Error + Send + Sync>> = async {
+        reset_simulator(config).await?;
+
+        // Nothing listens on this port, so connecting fails right away. Its lower capability makes it the first choice.
+        let dead_server = "--server=http://127.0.0.1:11599=Dead";
+        let simulated = ["[capability=10]"; 3];
+        let chat = serde_json::json!({
+            "model": "test-model:latest",
+            "messages": [{"role": "user", "content": "Hello"}],
+            "stream": false
+        });
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()?;
+        let chat_url = format!("http://127.0.0.1:{}/api/chat", config.load_balancer_port);
+        let request_counts = || async {
+            let state = state.read().await;
+            config.server_ports.iter()
+                .map(|port| state.servers.get(port).map(|s| s.request_count).unwrap_or_default())
+                .collect::<Vec<u64>>()
+        };
+
+        // The request goes on to the next best server
+        let lb = start_load_balancer_with(config, &simulated, &[dead_server, "--poll-interval=600"]).await?;
+        wait_for_inventory_poll().await;
+        let before = request_counts().await;
+        let retried_status = client.post(&chat_url).json(&chat).send().await?.status();
+        let after = request_counts().await;
+        stop_load_balancer(lb).await;
+        let served: Vec<u64> = after.iter().zip(&before).map(|(after, before)| after - before).collect();
+
+        // A body too large to keep in memory is only sent once
+        let lb = start_load_balancer_with(config, &simulated, &[dead_server, "--poll-interval=600", "--retry-body-limit=10"]).await?;
+        let streamed_status = client.post(format!("http://127.0.0.1:{}/v1/images/generations", config.load_balancer_port))
+            .json(&serde_json::json!({"model": "test-model:latest", "prompt": "A lighthouse"}))
+            .send()
+            .await?
+            .status();
+        stop_load_balancer(lb).await;
+
+        // Without retries, the client gets the error
+        let lb = start_load_balancer_with(config, &simulated, &[dead_server, "--poll-interval=600", "--max-attempts=1"]).await?;
+        let single_attempt_status = client.post(&chat_url).json(&chat).send().await?.status();
+        stop_load_balancer(lb).await;
+
+        if !retried_status.is_success() || served != vec![1, 0, 0] {
+            return Err(format!("Expected the request to be retried on the first simulated server, got status {} and requests per server {:?}", retried_status, served).into());
+        }
+        if streamed_status != reqwest::StatusCode::BAD_GATEWAY {
+            return Err(format!("Expected 502 for a body over --retry-body-limit, got {}", streamed_status).into());
+        }
+        if single_attempt_status != reqwest::StatusCode::BAD_GATEWAY {
+            return Err(format!("Expected 502 with --max-attempts=1, got {}", single_attempt_status).into());
+        }
+        Ok(())
+    }.await;
+
+    let error_message = match &result {
+        Ok(()) => String::new(),
+        Err(e) => e.to_string(),
+    };
+    TestResult {
+        name,
+        passed: result.is_ok(),
+        message: error_message,
+        duration_ms: start.elapsed().as_millis() as u64,
+    }
+}
+
+/// Test 32: `--first-byte-timeout` limits the wait for the first token, `--timeout` only the silence between tokens.
+/// A response that doesn't start in time gets 504 instead of being retried on another server.
+async fn test_separate_timeouts(
+    config: &TestConfig,
+    _state: Arc<RwLock<SimulatorState>>,
+) -> TestResult {
+    let name = "Separate first byte and between-chunk timeouts".to_string();
+    let start = Instant::now();
+
+    let result: Result<(), Box<dyn std::error::Error + Send + Sync>> = async {
+        reset_simulator(config).await?;
+
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(15))
+            .build()?;
+        let url = format!("http://127.0.0.1:{}/api/chat", config.load_balancer_port);
+        // Whether the whole streamed reply arrived
+        let stream_chat = || async {
+            let response = client.post(&url)
+                .json(&serde_json::json!({
+                    "model": "test-model:latest",
+                    "messages": [{"role": "user", "content": "Hello"}],
+                    "stream": true
+                }))
+                .send()
+                .await?;
+            Ok::<bool, reqwest::Error>(response.status().is_success() && response.bytes().await.is_ok())
+        };
+        // 2.5 seconds before the first token (model loading), then tokens come quickly
+        let slow_start = ServerBehavior::Normal {
+            tokens_per_sec: 100.0,
+            prompt_eval_tokens_per_sec: 2900.0,
+            num_tokens: 5,
+            load_delay_ms: 2500,
+        };
+        set_all_servers_behavior(config, &slow_start).await?;
+
+        // A long wait for the first token is fine even with a short silence limit
+        let lb = start_load_balancer_with(config, &[], &["--timeout=1", "--first-byte-timeout=10"]).await?;
+        let long_first_byte_completed = stream_chat().await?;
+        stop_load_balancer(lb).await;
+
+        // ...but not beyond the first byte timeout
+        let lb = start_load_balancer_with(config, &[], &["--timeout=10", "--first-byte-timeout=1"]).await?;
+        let first_byte_started = Instant::now();
+        let first_byte_timeout_completed = stream_chat().await?;
+        let first_byte_elapsed = first_byte_started.elapsed();
+        // A non-streamed reply sends no headers until it's done. It's not retried on another server, which would start over.
+        let headers_started = Instant::now();
+        let headers_status = client.post(&url)
+            .json(&serde_json::json!({
+                "model": "test-model:latest",
+                "messages": [{"role": "user", "content": "Hello"}],
+                "stream": false
+            }))
+            .send()
+            .await?
+            .status();
+        let headers_elapsed = headers_started.elapsed();
+        stop_load_balancer(lb).await;
+
+        // 2.5 seconds between tokens is too long a silence mid-response
+        set_all_servers_behavior(config, &ServerBehavior::Slow {
+            tokens_per_sec: 0.4,
+            num_tokens: 5,
+        }).await?;
+        let lb = start_load_balancer_with(config, &[], &["--timeout=1", "--first-byte-timeout=10"]).await?;
+        let silence_started = Instant::now();
+        let silence_completed = stream_chat().await?;
+        let silence_elapsed = silence_started.elapsed();
+        stop_load_balancer(lb).await;
+
+        if !long_first_byte_completed {
+            return Err("Expected a reply whose first token took 2.5 seconds to complete with --timeout=1 --first-byte-timeout=10".into());
+        }
+        if first_byte_timeout_completed || first_byte_elapsed > Duration::from_millis(2000) {
+            return Err(format!("Expected the reply to be cut off after --first-byte-timeout=1, completed: {}, took {:?}", first_byte_timeout_completed, first_byte_elapsed).into());
+        }
+        if headers_status != reqwest::StatusCode::GATEWAY_TIMEOUT || headers_elapsed > Duration::from_millis(2000) {
+            return Err(format!("Expected 504 after --first-byte-timeout=1 without retrying on other servers, got {} after {:?}", headers_status, headers_elapsed).into());
+        }
+        if silence_completed || silence_elapsed > Duration::from_millis(4500) {
+            return Err(format!("Expected the reply to be cut off 1 second after the first token, completed: {}, took {:?}", silence_completed, silence_elapsed).into());
+        }
+        Ok(())
+    }.await;
+
+    let error_message = match &result {
+        Ok(()) => String::new(),
+        Err(e) => e.to_string(),
+    };
+    TestResult {
+        name,
+        passed: result.is_ok(),
+        message: error_message,
+        duration_ms: start.elapsed().as_millis() as u64,
+    }
+}
+
+/// Test 33: `--timeout-rule` overrides the timeouts for the paths and models it matches, invalid rules are rejected at startup
+async fn test_timeout_rules(
+    config: &TestConfig,
+    _state: Arc<RwLock<SimulatorState>>,
+) -> TestResult {
+    let name = "Timeout overrides per endpoint and model".to_string();
+    let start = Instant::now();
+
+    let result: Result<(), Box<dyn std::error::Error + Send + Sync>> = async {
+        for invalid in ["path=/api/chat", "[timeout=5]", "path=/api/chat[timeout=soon]", "path=/api/chat[speed=5]", "host=x[timeout=5]"] {
+            let status = Command::new(&config.load_balancer_path)
+                .arg(format!("--server=http://127.0.0.1:{}=Server", config.server_ports[0]))
+                .arg(format!("--timeout-rule={}", invalid))
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status()?;
+            if status.success() {
+                return Err(format!("Load balancer accepted invalid timeout rule {}", invalid).into());
+            }
+        }
+
+        reset_simulator(config).await?;
+        // 2.5 seconds before the first token, then tokens come quickly
+        set_all_servers_behavior(config, &ServerBehavior::Normal {
+            tokens_per_sec: 100.0,
+            prompt_eval_tokens_per_sec: 2900.0,
+            num_tokens: 5,
+            load_delay_ms: 2500,
+        }).await?;
+
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(15))
+            .build()?;
+        // Whether the whole streamed reply arrived
+        let stream = |path: &'static str, body: serde_json::Value| {
+            let client = client.clone();
+            let url = format!("http://127.0.0.1:{}{}", config.load_balancer_port, path);
+            async move {
+                let response = client.post(&url).json(&body).send().await?;
+                Ok::<bool, reqwest::Error>(response.status().is_success() && response.bytes().await.is_ok())
+            }
+        };
+        let chat = || stream("/api/chat", serde_json::json!({
+            "model": "test-model:latest",
+            "messages": [{"role": "user", "content": "Hello"}],
+            "stream": true
+        }));
+        let generate = || stream("/api/generate", serde_json::json!({
+            "model": "test-model:latest",
+            "prompt": "Hello",
+            "stream": true
+        }));
+
+        // The rule for the endpoint gives chat time for its first token, generate keeps the 1 second limit
+        let lb = start_load_balancer_with(config, &[], &[
+            "--first-byte-timeout=1",
+            "--timeout-rule=path=/api/ch*[first_byte_timeout=10]",
+        ]).await?;
+        let chat_by_path = chat().await?;
+        let generate_by_path = generate().await?;
+        stop_load_balancer(lb).await;
+
+        // The rule for the model applies to any endpoint
+        let lb = start_load_balancer_with(config, &[], &[
+            "--first-byte-timeout=1",
+            "--timeout-rule=model=test-*[first_byte_timeout=10]",
+        ]).await?;
+        let chat_by_model = chat().await?;
+        let generate_by_model = generate().await?;
+        stop_load_balancer(lb).await;
+
+        if !chat_by_path || generate_by_path {
+            return Err(format!("Expected only chat to get the longer first byte timeout of its path, completed (chat, generate): {:?}", (chat_by_path, generate_by_path)).into());
+        }
+        if !chat_by_model || !generate_by_model {
+            return Err(format!("Expected both endpoints to get the longer first byte timeout of the model, completed (chat, generate): {:?}", (chat_by_model, generate_by_model)).into());
+        }
+        Ok(())
+    }.await;
+
+    let error_message = match &result {
+        Ok(()) => String::new(),
+        Err(e) => e.to_string(),
+    };
+    TestResult {
+        name,
+        passed: result.is_ok(),
+        message: error_message,
+        duration_ms: start.elapsed().as_millis() as u64,
+    }
+}
+
+/// Test 34: Metadata requests and CORS preflights are answered while every server is busy, without taking a slot
+async fn test_metadata_requests(
+    config: &TestConfig,
+    state: Arc<RwLock<SimulatorState>>,
+) -> TestResult {
+    let name = "Metadata requests without a free server".to_string();
+    let start = Instant::now();
+
+    let result: Result<(), Box<dyn std::error::Error + Send + Sync>> = async {
+        reset_simulator(config).await?;
+        set_server_models(config, config.server_ports[2], &["test-model:latest", "beta:latest"]).await?;
+        set_all_servers_behavior(config, &ServerBehavior::Slow {
+            tokens_per_sec: 1.0,
+            num_tokens: 100,
+        }).await?;
+
+        // Without a waiting line, anything that needs a slot gets 503 right away
+        let lb = start_load_balancer_with(config, &[], &["--poll-interval=600", "--max-queue-length=0"]).await?;
+        wait_for_inventory_poll().await;
+        let handles = occupy_servers(config, "test-model:latest", 3).await;
+        set_all_servers_behavior(config, &ServerBehavior::default()).await?;
+
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(5))
+            .build()?;
+        let base = format!("http://127.0.0.1:{}", config.load_balancer_port);
+        let request_counts = || async {
+            let state = state.read().await;
+            config.server_ports.iter()
+                .map(|port| state.servers.get(port).map(|s| s.request_count).unwrap_or_default())
+                .collect::<Vec<u64>>()
+        };
+
+        let root = client.get(format!("{}/", base)).send().await?.status();
+        let head_root = client.head(format!("{}/", base)).send().await?.status();
+        let version = client.get(format!("{}/api/version", base)).send().await?.status();
+        let preflight = client.request(reqwest::Method::OPTIONS, format!("{}/api/chat", base))
+            .header("Origin", "http://localhost:3000")
+            .header("Access-Control-Request-Method", "POST")
+            .send()
+            .await?;
+        let preflight_status = preflight.status();
+        let preflight_allows = preflight.headers().contains_key("access-control-allow-origin");
+        // Only the third server has this model
+        let before_show = request_counts().await;
+        let show = client.post(format!("{}/api/show", base))
+            .json(&serde_json::json!({"model": "beta:latest"}))
+            .send()
+            .await?
+            .status();
+        let after_show = request_counts().await;
+        let chat = client.post(format!("{}/api/chat", base))
+            .json(&serde_json::json!({
+                "model": "test-model:latest",
+                "messages": [{"role": "user", "content": "Hello"}],
+                "stream": false
+            }))
+            .send()
+            .await?
+            .status();
+
+        for handle in handles {
+            handle.abort();
+        }
+        stop_load_balancer(lb).await;
+
+        if !root.is_success() || !head_root.is_success() || !version.is_success() {
+            return Err(format!("Expected GET /, HEAD / and GET /api/version to succeed while all servers are busy, got {}, {}, {}", root, head_root, version).into());
+        }
+        if !preflight_status.is_success() || !preflight_allows {
+            return Err(format!("Expected the CORS preflight to be answered by a server while all are busy, got {}", preflight_status).into());
+        }
+        let show_served: Vec<u64> = after_show.iter().zip(&before_show).map(|(after, before)| after - before).collect();
+        if !show.is_success() || show_served != vec![0, 0, 1] {
+            return Err(format!("Expected POST /api/show to go to the server with the model, got {} and requests per server {:?}", show, show_served).into());
+        }
+        if chat != reqwest::StatusCode::SERVICE_UNAVAILABLE {
+            return Err(format!("Expected chat to still need a free server, got {}", chat).into());
+        }
+        Ok(())
+    }.await;
+
+    let error_message = match &result {
+        Ok(()) => String::new(),
+        Err(e) => e.to_string(),
+    };
+    TestResult {
+        name,
+        passed: result.is_ok(),
+        message: error_message,
+        duration_ms: start.elapsed().as_millis() as u64,
+    }
+}
+
+/// Installed model names of every simulated server, in port order
+async fn installed_models(config: &TestConfig, state: &Arc<RwLock<SimulatorState>>) -> Vec<Vec<String>> {
+    let state = state.read().await;
+    config.server_ports.iter()
+        .map(|port| state.servers.get(port)
+            .map(|s| s.installed_models.iter().map(|m| m.name.clone()).collect())
+            .unwrap_or_default())
+        .collect()
+}
+
+/// Test 35: `X-LB-Servers` runs pulls and deletes on several servers with a tagged, summarized response,
+/// and `--admin-key` guards it.
+async fn test_model_management_fanout(
+    config: &TestConfig,
+    state: Arc<RwLock<SimulatorState>>,
+) -> TestResult {
+    let name = "Model management on several servers".to_string();
+    let start = Instant::now();
+
+    let result: Result<(), Box<dyn std::error::Error + Send + Sync>> = async {
+        reset_simulator(config).await?;
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()?;
+        let base = format!("http://127.0.0.1:{}", config.load_balancer_port);
+        let has_model = |models: &Vec<String>, model: &str| models.iter().any(|m| m == model);
+        let summary_of = |body: &str| -> Option<serde_json::Value> {
+            let last: serde_json::Value = serde_json::from_str(body.lines().last()?).ok()?;
+            (last["status"] == "summary").then_some(last)
+        };
+        let successes = |summary: &serde_json::Value| summary["servers"].as_array()
+            .map(|servers| servers.iter().filter(|s| s["success"] == true).count())
+            .unwrap_or_default();
+
+        let lb = start_load_balancer_with(config, &[], &["--poll-interval=600"]).await?;
+
+        // Without the header, a pull goes to a single server like any other request
+        let single = client.post(format!("{}/api/pull", base))
+            .json(&serde_json::json!({"model": "solo"}))
+            .send()
+            .await?;
+        let single_status = single.status();
+        let single_body = single.text().await?;
+        let single_installed = installed_models(config, &state).await.iter().filter(|models| has_model(models, "solo:latest")).count();
+
+        let all = client.post(format!("{}/api/pull", base))
+            .header("X-LB-Servers", "all")
+            .json(&serde_json::json!({"model": "llama3"}))
+            .send()
+            .await?;
+        let all_status = all.status();
+        let all_body = all.text().await?;
+        let all_installed = installed_models(config, &state).await.iter().filter(|models| has_model(models, "llama3:latest")).count();
+
+        let delete = client.delete(format!("{}/api/delete", base))
+            .header("X-LB-Servers", format!("Server{}, Server{}", config.server_ports[0], config.server_ports[2]))
+            .json(&serde_json::json!({"model": "llama3"}))
+            .send()
+            .await?;
+        let delete_body = delete.text().await?;
+        let after_delete: Vec<bool> = installed_models(config, &state).await.iter().map(|models| has_model(models, "llama3:latest")).collect();
+
+        let unknown = client.post(format!("{}/api/pull", base))
+            .header("X-LB-Servers", "Nonexistent")
+            .json(&serde_json::json!({"model": "llama3"}))
+            .send()
+            .await?
+            .status();
+
+        let missing = client.post(format!("{}/api/pull", base))
+            .header("X-LB-Servers", "all")
+            .json(&serde_json::json!({"model": "missing-model"}))
+            .send()
+            .await?
+            .text()
+            .await?;
+
+        stop_load_balancer(lb).await;
+
+        if !single_status.is_success() || summary_of(&single_body).is_some() || single_installed != 1 {
+            return Err(format!("Expected a pull without the header to run on one server, got {} on {} servers", single_status, single_installed).into());
+        }
+        let all_lines: Vec<serde_json::Value> = all_body.lines().filter_map(|line| serde_json::from_str(line).ok()).collect();
+        let untagged = all_lines.iter().filter(|line| line["status"] != "summary" && !line["server"].is_string()).count();
+        let all_summary = summary_of(&all_body).ok_or("Expected the pull on all servers to end with a summary")?;
+        if !all_status.is_success() || untagged > 0 || successes(&all_summary) != 3 || all_installed != 3 {
+            return Err(format!("Expected the pull to succeed on all 3 servers with tagged lines, got {}, {} untagged lines, summary {}, installed on {}", all_status, untagged, all_summary, all_installed).into());
+        }
+        let delete_summary = summary_of(&delete_body).ok_or("Expected the delete to end with a summary")?;
+        if successes(&delete_summary) != 2 || after_delete != vec![false, true, false] {
+            return Err(format!("Expected the delete to affect only the first and third servers, got summary {} and installed {:?}", delete_summary, after_delete).into());
+        }
+        if unknown != reqwest::StatusCode::BAD_REQUEST {
+            return Err(format!("Expected an unknown server name to be rejected with 400, got {}", unknown).into());
+        }
+        let missing_summary = summary_of(&missing).ok_or("Expected the failed pull to end with a summary")?;
+        if successes(&missing_summary) != 0 || !missing_summary["servers"][0]["error"].is_string() {
+            return Err(format!("Expected a pull of a nonexistent model to fail on every server, got summary {}", missing_summary).into());
+        }
+
+        // With an admin key, only admins may run on several servers
+        let lb = start_load_balancer_with(config, &[], &["--poll-interval=600", "--admin-key=secret"]).await?;
+        let forbidden = client.post(format!("{}/api/pull", base))
+            .header("X-LB-Servers", "all")
+            .json(&serde_json::json!({"model": "gemma"}))
+            .send()
+            .await?
+            .status();
+        let admin = client.post(format!("{}/api/pull", base))
+            .bearer_auth("secret")
+            .json(&serde_json::json!({"model": "gemma"}))
+            .send()
+            .await?
+            .text()
+            .await?;
+        stop_load_balancer(lb).await;
+
+        if forbidden != reqwest::StatusCode::FORBIDDEN {
+            return Err(format!("Expected the header without the admin key to be rejected with 403, got {}", forbidden).into());
+        }
+        let admin_summary = summary_of(&admin).ok_or("Expected the admin's pull to run on all servers")?;
+        if successes(&admin_summary) != 3 {
+            return Err(format!("Expected the admin's pull to succeed on all 3 servers, got summary {}", admin_summary).into());
+        }
+        Ok(())
+    }.await;
+
+    let error_message = match &result {
+        Ok(()) => String::new(),
+        Err(e) => e.to_string(),
+    };
+    TestResult {
+        name,
+        passed: result.is_ok(),
+        message: error_message,
+        duration_ms: start.elapsed().as_millis() as u64,
+    }
+}
+
+/// Runs the load balancer's `reconcile` subcommand to completion, returning whether it succeeded and its output
+async fn run_reconcile(
+    config: &TestConfig,
+    annotations: &[&str],
+    args: &[&str],
+) -> Result<(bool, String), Box<dyn std::error::Error + Send + Sync>> {
+    let mut all_args: Vec<String> = config.server_ports.iter().enumerate()
+        .map(|(index, port)| format!("--server=http://127.0.0.1:{}=Server{}{}", port, port, annotations.get(index).copied().unwrap_or_default()))
+        .collect();
+    all_args.extend(args.iter().map(|arg| arg.to_string()));
+    let load_balancer_path = config.load_balancer_path.clone();
+    let output = tokio::task::spawn_blocking(move || {
+        Command::new(load_balancer_path).args(&all_args).output()
+    }).await??;
+    let text = format!("{}{}", String::from_utf8_lossy(&output.stdout), String::from_utf8_lossy(&output.stderr));
+    Ok((output.status.success(), text))
+}
+
+/// Test 36: `--placement` with the `reconcile` subcommand and `--fix-drift`, and a failing pull backed off instead of retried every poll
+async fn test_model_placement(
+    config: &TestConfig,
+    state: Arc<RwLock<SimulatorState>>,
+) -> TestResult {
+    let name = "Declarative model placement".to_string();
+    let start = Instant::now();
+
+    let result: Result<(), Box<dyn std::error::Error + Send + Sync>> = async {
+        reset_simulator(config).await?;
+        let ports = &config.server_ports;
+        set_server_models(config, ports[0], &["test-model:latest", "old:latest"]).await?;
+        set_server_models(config, ports[1], &["test-model:latest"]).await?;
+        set_server_models(config, ports[2], &["test-model:latest", "old:latest"]).await?;
+        let sorted_models = || async {
+            installed_models(config, &state).await.into_iter()
+                .map(|mut models| {
+                    models.sort();
+                    models
+                })
+                .collect::<Vec<_>>()
+        };
+        let annotations = ["", "[group=gpu]", "[group=gpu]"];
+        let first_server = format!("test-model=Server{}", ports[0]);
+        let placements = [
+            "--placement", "llama3=all", "--placement", "phi3=gpu", "--placement", "old=none", "--placement", &first_server,
+        ];
+        let dry_run = [&placements[..], &["reconcile", "--dry-run"]].concat();
+        let apply = [&placements[..], &["reconcile"]].concat();
+        let (dry_run_ok, dry_run_output) = run_reconcile(config, &annotations, &dry_run).await?;
+        let after_dry_run = sorted_models().await;
+        let (apply_ok, apply_output) = run_reconcile(config, &annotations, &apply).await?;
+        let after_apply = sorted_models().await;
+        let (again_ok, again_output) = run_reconcile(config, &annotations, &dry_run).await?;
+
+        let unchanged = vec![
+            vec!["old:latest".to_string(), "test-model:latest".to_string()],
+            vec!["test-model:latest".to_string()],
+            vec!["old:latest".to_string(), "test-model:latest".to_string()],
+        ];
+        if !dry_run_ok || !dry_run_output.contains("9 step(s)") || after_dry_run != unchanged {
+            return Err(format!("Expected the dry run to plan 9 steps and change nothing, got models {:?} and output:\n{}", after_dry_run, dry_run_output).into());
+        }
+        let placed = vec![
+            vec!["llama3:latest".to_string(), "test-model:latest".to_string()],
+            vec!["llama3:latest".to_string(), "phi3:latest".to_string()],
+            vec!["llama3:latest".to_string(), "phi3:latest".to_string()],
+        ];
+        if !apply_ok || after_apply != placed {
+            return Err(format!("Expected reconcile to place the models, got models {:?} and output:\n{}", after_apply, apply_output).into());
+        }
+        if !again_ok || !again_output.contains("nothing to do") {
+            return Err(format!("Expected nothing left to do after reconciling, got output:\n{}", again_output).into());
+        }
+
+        let (failed_ok, _) = run_reconcile(config, &annotations, &["--placement", "llama3=cpu", "reconcile"]).await?;
+        if failed_ok {
+            return Err("Expected a placement naming an unknown group to be rejected".into());
+        }
+
+        // The load balancer reports drift, and only fixes it with --fix-drift
+        set_server_models(config, ports[1], &["test-model:latest"]).await?;
+        let lb = start_load_balancer_with(config, &annotations, &["--poll-interval=1", "--placement=phi3=gpu"]).await?;
+        sleep(Duration::from_millis(2500)).await;
+        let reported_only = sorted_models().await;
+        stop_load_balancer(lb).await;
+        if reported_only[1] != vec!["test-model:latest".to_string()] {
+            return Err(format!("Expected drift to only be reported without --fix-drift, got models {:?}", reported_only).into());
+        }
+
+        let lb = start_load_balancer_with(config, &annotations, &["--poll-interval=1", "--placement=phi3=gpu", "--fix-drift"]).await?;
+        let fix_start = Instant::now();
+        let mut fixed = sorted_models().await;
+        while fixed[1] != vec!["phi3:latest".to_string(), "test-model:latest".to_string()] && fix_start.elapsed() < Duration::from_secs(5) {
+            sleep(Duration::from_millis(100)).await;
+            fixed = sorted_models().await;
+        }
+        stop_load_balancer(lb).await;
+        if fixed[1] != vec!["phi3:latest".to_string(), "test-model:latest".to_string()] || fixed[0] != placed[0] {
+            return Err(format!("Expected --fix-drift to pull phi3 on the second server only, got models {:?}", fixed).into());
+        }
+
+        // A pull that fails isn't retried after every poll
+        let pull_count = || async { state.read().await.servers.get(&ports[0]).map(|s| s.pull_count).unwrap_or_default() };
+        let missing = format!("--placement=missing-model=Server{}", ports[0]);
+        let pulls_before = pull_count().await;
+        let lb = start_load_balancer_with(config, &annotations, &["--poll-interval=1", &missing, "--fix-drift"]).await?;
+        sleep(Duration::from_millis(3500)).await;
+        stop_load_balancer(lb).await;
+        let pulls = pull_count().await - pulls_before;
+        if pulls != 1 {
+            return Err(format!("Expected the failing pull to be tried once and then backed off, it was tried {} times", pulls).into());
+        }
+        Ok(())
+    }.await;
+
+    let error_message = match &result {
+        Ok(()) => String::new(),
+        Err(e) => e.to_string(),
+    };
+    TestResult {
+        name,
+        passed: result.is_ok(),
+        message: error_message,
+        duration_ms: start.elapsed().as_millis() as u64,
+    }
+}
+
+/// Test 37: `ollama create`'s blob check, upload and create all reach the same server. The upload streams
+/// slower than `--first-byte-timeout` while every server is busy- it takes no slot and isn't timed like inference.
+async fn test_blob_upload_pinning(
+    config: &TestConfig,
+    state: Arc<RwLock<SimulatorState>>,
+) -> TestResult {
+    let name = "Blob uploads pinned to one server".to_string();
+    let start = Instant::now();
+
+    let result: Result<(), Box<dyn std::error::Error + Send + Sync>> = async {
+        reset_simulator(config).await?;
+        set_all_servers_behavior(config, &ServerBehavior::Slow {
+            tokens_per_sec: 5.0,
+            num_tokens: 25,
+        }).await?;
+        let lb = start_load_balancer_with(config, &[], &["--poll-interval=600", "--first-byte-timeout=1", "--retry-body-limit=1024"]).await?;
+        wait_for_inventory_poll().await;
+
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(15))
+            .build()?;
+        let base = format!("http://127.0.0.1:{}", config.load_balancer_port);
+        let digest = format!("sha256:{}", "ab12".repeat(16));
+
+        // Like `ollama create`: is the blob there, upload it, then create from it
+        let head = client.head(format!("{}/api/blobs/{}", base, digest)).send().await?.status();
+        // 30 KiB over 3 seconds, while every server generates for 5 seconds
+        let handles = occupy_servers(config, "test-model:latest", 3).await;
+        let upload_started = Instant::now();
+        let upload = client.post(format!("{}/api/blobs/{}", base, digest))
+            .body(slow_body(30, Duration::from_millis(100)))
+            .send()
+            .await?
+            .status();
+        let upload_duration = upload_started.elapsed();
+        drop(handles);
+        sleep(Duration::from_millis(500)).await;
+        // The server that has the blob is busy now, while the others are free
+        let handles = occupy_servers(config, "test-model:latest", 1).await;
+        let create = client.post(format!("{}/api/create", base))
+            .json(&serde_json::json!({
+                "model": "custom",
+                "files": {"model.gguf": digest},
+                "stream": false
+            }))
+            .send()
+            .await?;
+        let create_status = create.status();
+        let create_body = create.text().await?;
+
+        for handle in handles {
+            handle.abort();
+        }
+        stop_load_balancer(lb).await;
+
+        let (blobs, created): (Vec<usize>, Vec<bool>) = {
+            let state = state.read().await;
+            config.server_ports.iter()
+                .map(|port| state.servers.get(port)
+                    .map(|s| (s.blobs.len(), s.installed_models.iter().any(|m| m.name == "custom:latest")))
+                    .unwrap_or_default())
+                .unzip()
+        };
+        if head != reqwest::StatusCode::NOT_FOUND || upload != reqwest::StatusCode::CREATED {
+            return Err(format!("Expected the blob check to get 404 and the upload 201, got {} and {}", head, upload).into());
+        }
+        if upload_duration >= Duration::from_millis(4500) {
+            return Err(format!("Expected the upload not to wait for a busy server's slot, it took {:?}", upload_duration).into());
+        }
+        if blobs != vec![1, 0, 0] {
+            return Err(format!("Expected the blob on the first server only, got blobs per server {:?}", blobs).into());
+        }
+        if !create_status.is_success() || create_body.contains("error") || created != vec![true, false, false] {
+            return Err(format!("Expected the create to wait for the server with the blob, got {} {} and created per server {:?}", create_status, create_body, created).into());
+        }
+        Ok(())
+    }.await;
+
+    let error_message = match &result {
+        Ok(()) => String::new(),
+        Err(e) => e.to_string(),
+    };
+    TestResult {
+        name,
+        passed: result.is_ok(),
+        message: error_message,
+        duration_ms: start.elapsed().as_millis() as u64,
+    }
+}
+
+/// Test 38: Query strings, `Host`, forwarding headers, hop-by-hop headers and non-UTF-8 header values are proxied like a proper proxy would
+async fn test_proxy_header_fidelity(config: &TestConfig) -> TestResult {
+    let name = "Proxy header fidelity".to_string();
+    let start = Instant::now();
+
+    let result: Result<(), Box<dyn std::error::Error + Send + Sync>> = async {
+        reset_simulator(config).await?;
+        let lb = start_load_balancer(config).await?;
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(5))
+            .build()?;
+        let base = format!("http://127.0.0.1:{}", config.load_balancer_port);
+
+        let forwarded = client.get(format!("{}/echo?model=x&verbose=true", base))
+            .header("X-Forwarded-For", "10.1.2.3")
+            .header("X-LB-Priority", "high")
+            .header("Connection", "keep-alive, X-Hop")
+            .header("X-Hop", "1")
+            .header("Proxy-Authorization", "Basic c2VjcmV0")
+            .header("X-Custom", "kept")
+            .header("X-Binary-Req", &[0xff, 0x42][..])
+            .send()
+            .await?;
+        let response_binary = forwarded.headers().get("x-binary").map(|value| value.as_bytes().to_vec());
+        let response_hop_by_hop: Vec<String> = ["keep-alive", "x-private"].iter()
+            .filter(|name| forwarded.headers().contains_key(**name))
+            .map(|name| name.to_string())
+            .collect();
+        let echo: serde_json::Value = forwarded.json().await?;
+        let direct: serde_json::Value = client.get(format!("{}/echo", base)).send().await?.json().await?;
+
+        stop_load_balancer(lb).await;
+
+        let header = |echo: &serde_json::Value, name: &str| echo["headers"][name][0].as_str().map(String::from);
+        if echo["uri"] != "/echo?model=x&verbose=true" {
+            return Err(format!("Expected the query string to reach the server, got URI {}", echo["uri"]).into());
+        }
+        let host = format!("127.0.0.1:{}", config.server_ports[0]);
+        if header(&echo, "host").as_deref() != Some(host.as_str()) {
+            return Err(format!("Expected Host to be the server's address {}, got {:?}", host, header(&echo, "host")).into());
+        }
+        if header(&echo, "x-forwarded-for").as_deref() != Some("10.1.2.3, 127.0.0.1") || header(&direct, "x-forwarded-for").as_deref() != Some("127.0.0.1") {
+            return Err(format!("Expected the client's address appended to X-Forwarded-For, got {:?} and {:?}", header(&echo, "x-forwarded-for"), header(&direct, "x-forwarded-for")).into());
+        }
+        if header(&echo, "x-forwarded-proto").as_deref() != Some("http") {
+            return Err(format!("Expected X-Forwarded-Proto: http, got {:?}", header(&echo, "x-forwarded-proto")).into());
+        }
+        let leaked: Vec<&str> = ["x-lb-priority", "x-hop", "proxy-authorization"].into_iter()
+            .filter(|name| header(&echo, name).is_some())
+            .collect();
+        if !leaked.is_empty() {
+            return Err(format!("Expected hop-by-hop and load balancer headers to be dropped, the server got {:?}", leaked).into());
+        }
+        if header(&echo, "x-custom").as_deref() != Some("kept") || header(&echo, "x-binary-req").is_none() {
+            return Err(format!("Expected end-to-end headers to reach the server, got headers {}", echo["headers"]).into());
+        }
+        if response_binary != Some(vec![0xff, 0x41]) {
+            return Err(format!("Expected the non-UTF-8 response header to pass through byte for byte, got {:?}", response_binary).into());
+        }
+        if !response_hop_by_hop.is_empty() {
+            return Err(format!("Expected the server's hop-by-hop headers to be dropped, the client got {:?}", response_hop_by_hop).into());
+        }
+        Ok(())
+    }.await;
+
+    let error_message = match &result {
+        Ok(()) => String::new(),
+        Err(e) => e.to_string(),
+    };
+    TestResult {
+        name,
+        passed: result.is_ok(),
+        message: error_message,
+        duration_ms: start.elapsed().as_millis() as u64,
+    }
+}
+
+/// Port of the server that drops kept-alive connections, outside the simulator's range
+const STALE_SERVER_PORT: u16 = 11598;
+
+/// A bare HTTP server that answers the first request on each connection and keeps the connection alive,
+/// then closes it without a response when the next request arrives on it- like a server that dropped
+/// an idle connection just as the load balancer reused it. Returns how many requests were answered and dropped.
+async fn start_stale_keep_alive_server() -> Result<(tokio::task::JoinHandle<()>, Arc<std::sync::atomic::AtomicU64>, Arc<std::sync::atomic::AtomicU64>), Box<dyn std::error::Error + Send + Sync>> {
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = tokio::net::TcpListener::bind(("127.0.0.1", STALE_SERVER_PORT)).await?;
+    let answered = Arc::new(AtomicU64::new(0));
+    let dropped = Arc::new(AtomicU64::new(0));
+    let (answered_count, dropped_count) = (answered.clone(), dropped.clone());
+    let handle = tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let (answered, dropped) = (answered_count.clone(), dropped_count.clone());
+            tokio::spawn(async move {
+                let mut buffer = Vec::new();
+                let mut chunk = [0u8; 4096];
+                let mut requests_on_connection = 0;
+                loop {
+                    // Read the request head, and its body if it has one
+                    let head_end = loop {
+                        if let Some(position) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+                            break position + 4;
+                        }
+                        match stream.read(&mut chunk).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(read) => buffer.extend_from_slice(&chunk[..read]),
+                        }
+                    };
+                    let head = String::from_utf8_lossy(&buffer[..head_end]).to_ascii_lowercase();
+                    let body_length: usize = head.lines()
+                        .find_map(|line| line.strip_prefix("content-length:"))
+                        .and_then(|length| length.trim().parse().ok())
+                        .unwrap_or(0);
+                    while buffer.len() < head_end + body_length {
+                        match stream.read(&mut chunk).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(read) => buffer.extend_from_slice(&chunk[..read]),
+                        }
+                    }
+                    buffer.drain(..head_end + body_length);
+
+                    requests_on_connection += 1;
+                    if requests_on_connection > 1 {
+                        dropped.fetch_add(1, Ordering::SeqCst);
+                        return;
+                    }
+                    let body = r#"{"models":[]}"#;
+                    let response = format!("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+                    if stream.write_all(response.as_bytes()).await.is_err() {
+                        return;
+                    }
+                    answered.fetch_add(1, Ordering::SeqCst);
+                }
+            });
+        }
+    });
+    Ok((handle, answered, dropped))
+}
+
+/// Test 39: A kept-alive connection the server closes as it's reused is retried on a new one, without demoting the server
+async fn test_stale_keep_alive_connection(
+    config: &TestConfig,
+    state: Arc<RwLock<SimulatorState>>,
+) -> TestResult {
+    let name = "Stale kept-alive connection retried".to_string();
+    let start = Instant::now();
+
+    let result: Result<(), Box<dyn std::error::Error + Send + Sync>> = async {
+        use std::sync::atomic::Ordering;
+
+        reset_simulator(config).await?;
+        let (stale_server, answered, dropped) = start_stale_keep_alive_server().await?;
+        // The simulated servers are only there to be chosen if the stale server gets demoted
+        let stale = format!("--server=http://127.0.0.1:{}=Stale", STALE_SERVER_PORT);
+        let lb = start_load_balancer_with(config, &["[capability=10]", "[capability=10]", "[capability=10]"], &["--poll-interval=600", &stale]).await;
+        let lb = match lb {
+            Ok(lb) => lb,
+            Err(e) => {
+                stale_server.abort();
+                return Err(e);
+            }
+        };
+        let simulator_requests = || async {
+            let state = state.read().await;
+            config.server_ports.iter().map(|port| state.servers.get(port).map(|s| s.request_count).unwrap_or_default()).sum::<u64>()
+        };
+        wait_for_inventory_poll().await;
+        let answered_by_poll = answered.load(Ordering::SeqCst);
+        let polled_simulator_requests = simulator_requests().await;
+
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(5))
+            .build()?;
+        let mut statuses = Vec::new();
+        for _ in 0..3 {
+            statuses.push(client.get(format!("http://127.0.0.1:{}/echo", config.load_balancer_port)).send().await?.status());
+        }
+        stop_load_balancer(lb).await;
+        stale_server.abort();
+
+        let simulator_requests = simulator_requests().await - polled_simulator_requests;
+        let answered = answered.load(Ordering::SeqCst) - answered_by_poll;
+        let dropped = dropped.load(Ordering::SeqCst);
+        if statuses.iter().any(|status| !status.is_success()) {
+            return Err(format!("Expected all requests to succeed, got {:?}", statuses).into());
+        }
+        if dropped == 0 {
+            return Err("Expected the load balancer to reuse kept-alive connections, but every request came on a new one".into());
+        }
+        if answered != 3 || simulator_requests != 0 {
+            return Err(format!("Expected every request to be answered by the server that dropped connections ({} dropped), got {} answered there and {} by other servers", dropped, answered, simulator_requests).into());
+        }
+        Ok(())
+    }.await;
+
+    let error_message = match &result {
+        Ok(()) => String::new(),
+        Err(e) => e.to_string(),
+    };
+    TestResult {
+        name,
+        passed: result.is_ok(),
+        message: error_message,
+        duration_ms: start.elapsed().as_millis() as u64,
+    }
+}
+
+/// Test 40: Multi-megabyte request and response bodies arrive byte for byte, and an inference body beyond
+/// `--inference-body-limit` gets 413.
+async fn test_large_body_passthrough(config: &TestConfig) -> TestResult {
+    let name = "Large bodies passed through intact".to_string();
+    let start = Instant::now();
+
+    let result: Result<(), Box<dyn std::error::Error + Send + Sync>> = async {
+        reset_simulator(config).await?;
+        // A non-streamed reply of several megabytes, which the load balancer also reads for conversation affinity
+        let content: String = (0..4 * 1024 * 1024).map(|index| (b'a' + (index % 26) as u8) as char).collect();
+        let reply = serde_json::json!({
+            "model": "test-model:latest",
+            "created_at": "2025-01-01T00:00:00Z",
+            "message": { "role": "assistant", "content": content },
+            "done": true,
+            "done_reason": "stop",
+        }).to_string();
+        set_all_servers_behavior(config, &ServerBehavior::Custom {
+            response_body: reply.clone(),
+            status_code: 200,
+            content_type: "application/json; charset=utf-8".to_string(),
+            delay_ms: 0,
+        }).await?;
+        let lb = start_load_balancer_with(config, &[], &["--inference-body-limit=8388608"]).await?;
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(30))
+            .build()?;
+        let base = format!("http://127.0.0.1:{}", config.load_balancer_port);
+
+        // 1 MiB is kept in memory for retries, 20 MiB is beyond --retry-body-limit and streamed through in chunks
+        let mut mismatches = Vec::new();
+        for (length, chunked) in [(1024 * 1024, false), (20 * 1024 * 1024, true)] {
+            let body: Vec<u8> = (0..length).map(|index| (index % 251) as u8).collect();
+            let checksum = simulator::body_checksum(&body);
+            let request_body = if chunked {
+                let chunks: Vec<Result<Vec<u8>, std::io::Error>> = body.chunks(64 * 1024).map(|chunk| Ok(chunk.to_vec())).collect();
+                reqwest::Body::wrap_stream(futures_util::stream::iter(chunks))
+            } else {
+                reqwest::Body::from(body)
+            };
+            let echo: serde_json::Value = client.post(format!("{}/echo", base))
+                .body(request_body)
+                .send()
+                .await?
+                .json()
+                .await?;
+            if echo["body_length"] != length || echo["body_checksum"] != checksum {
+                mismatches.push(format!("{} byte body arrived as {} bytes with checksum {} instead of {}", length, echo["body_length"], echo["body_checksum"], checksum));
+            }
+        }
+
+        let chat = serde_json::json!({
+            "model": "test-model:latest",
+            "stream": false,
+            "messages": [{ "role": "user", "content": "Recite the alphabet" }],
+        });
+        let received = client.post(format!("{}/api/chat", base))
+            .header("Content-Type", "application/json")
+            .body(chat.to_string())
+            .send()
+            .await?
+            .bytes()
+            .await?;
+
+        // An inference body beyond --inference-body-limit is rejected, whether its size is announced or not
+        let oversized = serde_json::json!({
+            "model": "test-model:latest",
+            "messages": [{ "role": "user", "content": "Describe", "images": ["A".repeat(9 * 1024 * 1024)] }],
+        }).to_string().into_bytes();
+        let mut oversized_statuses = Vec::new();
+        for chunked in [false, true] {
+            let request_body = if chunked {
+                let chunks: Vec<Result<Vec<u8>, std::io::Error>> = oversized.chunks(64 * 1024).map(|chunk| Ok(chunk.to_vec())).collect();
+                reqwest::Body::wrap_stream(futures_util::stream::iter(chunks))
+            } else {
+                reqwest::Body::from(oversized.clone())
+            };
+            let status = client.post(format!("{}/api/chat", base))
+                .header("Content-Type", "application/json")
+                .body(request_body)
+                .send()
+                .await
+                .map(|response| response.status().as_u16());
+            oversized_statuses.push(status.map_err(|e| e.to_string()));
+        }
+
+        stop_load_balancer(lb).await;
+
+        if !mismatches.is_empty() {
+            return Err(format!("Expected request bodies to reach the server unchanged: {}", mismatches.join("; ")).into());
+        }
+        if received != reply.as_bytes() {
+            return Err(format!("Expected the {} byte reply unchanged, the client got {} bytes", reply.len(), received.len()).into());
+        }
+        if oversized_statuses.iter().any(|status| status != &Ok(413)) {
+            return Err(format!("Expected 413 for inference bodies beyond --inference-body-limit, got {:?}", oversized_statuses).into());
+        }
+        Ok(())
+    }.await;
+
+    let error_message = match &result {
+        Ok(()) => String::new(),
+        Err(e) => e.to_string(),
+    };
+    TestResult {
+        name,
+        passed: result.is_ok(),
+        message: error_message,
+        duration_ms: start.elapsed().as_millis() as u64,
+    }
+}
+
+/// A request body of `chunks` KiB, of which the client sends one KiB every `interval`
+fn slow_body(chunks: usize, interval: Duration) -> reqwest::Body {
+    let body_stream = futures_util::stream::unfold(0, move |sent| async move {
+        if sent == chunks {
+            return None;
+        }
+        sleep(interval).await;
+        Some((Ok::<_, std::io::Error>(vec![b'x'; 1024]), sent + 1))
+    });
+    reqwest::Body::wrap_stream(body_stream)
+}
+
+/// Test 41: A body streamed through (beyond `--retry-body-limit`) that the client uploads slower than
+/// `--first-byte-timeout` still gets its response, and the server isn't marked Unreliable for it.
+/// The wait for the first byte starts once the upload is done, so a server that stays silent after it still times out.
+async fn test_slow_streamed_upload(config: &TestConfig) -> TestResult {
+    let name = "Slow streamed upload".to_string();
+    let start = Instant::now();
+
+    let result: Result<(), Box<dyn std::error::Error + Send + Sync>> = async {
+        reset_simulator(config).await?;
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(30))
+            .build()?;
+        let base = format!("http://127.0.0.1:{}", config.load_balancer_port);
+        let echo_host = |echo: &serde_json::Value| echo["headers"]["host"][0].as_str().unwrap_or_default().to_string();
+
+        // 30 KiB over 3 seconds against a 1 second first byte timeout
+        let lb = start_load_balancer_with(config, &[], &["--poll-interval=600", "--first-byte-timeout=1", "--retry-body-limit=1024"]).await?;
+        let slow: serde_json::Value = client.post(format!("{}/echo", base))
+            .body(slow_body(30, Duration::from_millis(100)))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let next: serde_json::Value = client.get(format!("{}/echo", base))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        stop_load_balancer(lb).await;
+
+        let first_server = format!("127.0.0.1:{}", config.server_ports[0]);
+        if slow["body_length"] != 30 * 1024 || echo_host(&slow) != first_server {
+            return Err(format!("Expected the slow upload to reach {} whole, got {} bytes on {}", first_server, slow["body_length"], echo_host(&slow)).into());
+        }
+        if echo_host(&next) != first_server {
+            return Err(format!("The first server should still be Reliable after the slow upload, the next request went to {}", echo_host(&next)).into());
+        }
+
+        // A server that reads the body but never answers. Its lower capability makes it the first choice.
+        let silent_port = 11597;
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", silent_port)).await?;
+        let silent = tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    use tokio::io::AsyncReadExt;
+                    let mut chunk = [0u8; 4096];
+                    while matches!(stream.read(&mut chunk).await, Ok(read) if read > 0) {}
+                });
+            }
+        });
+        let silent_server = format!("--server=http://127.0.0.1:{}=Silent", silent_port);
+        let lb = start_load_balancer_with(
+            config,
+            &["[capability=10]", "[capability=10]", "[capability=10]"],
+            &["--poll-interval=600", "--first-byte-timeout=1", "--retry-body-limit=1024", &silent_server],
+        ).await;
+        let lb = match lb {
+            Ok(lb) => lb,
+            Err(e) => {
+                silent.abort();
+                return Err(e);
+            }
+        };
+        let sent_at = Instant::now();
+        let status = client.post(format!("{}/echo", base))
+            .body(slow_body(20, Duration::from_millis(100)))
+            .send()
+            .await
+            .map(|response| response.status().as_u16());
+        let waited = sent_at.elapsed();
+        stop_load_balancer(lb).await;
+        silent.abort();
+
+        if status.as_ref().ok() != Some(&504) {
+            return Err(format!("Expected 504 from the silent server, got {:?}", status).into());
+        }
+        if waited < Duration::from_millis(2800) || waited > Duration::from_secs(5) {
+            return Err(format!("Expected the 1 second wait for the first byte to start after the 2 second upload, the client got its error after {:?}", waited).into());
+        }
+        Ok(())
+    }.await;
+
+    let error_message = match &result {
+        Ok(()) => String::new(),
+        Err(e) => e.to_string(),
+    };
+    TestResult {
+        name,
+        passed: result.is_ok(),
+        message: error_message,
+        duration_ms: start.elapsed().as_millis() as u64,
+    }
+}
+
+/// Test 42: The next turn of a conversation returns to the server that has it cached, even though that server
+/// is of a higher capability tier and another server is polled with the model hot.
+async fn test_affinity_beats_hot_server(
+    config: &TestConfig,
+    state: Arc<RwLock<SimulatorState>>,
+) -> TestResult {
+    let name = "Conversation affinity beats a hot server".to_string();
+    let start = Instant::now();
+
+    let result: Result<(), Box<dyn std::error::Error + Send + Sync>> = async {
+        reset_simulator(config).await?;
+
+        // The first server is polled with the model hot, and no poll later on changes that
+        set_loaded_model(config, config.server_ports[0], Some("test-model:latest")).await?;
+        let lb = start_load_balancer_with(config, &["", "[capability=10]", ""], &["--poll-interval=600"]).await?;
+        wait_for_inventory_poll().await;
+
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()?;
+        let url = format!("http://127.0.0.1:{}/api/chat", config.load_balancer_port);
+        let request_counts = || async {
+            let state = state.read().await;
+            config.server_ports.iter()
+                .map(|port| state.servers.get(port).map(|s| s.request_count).unwrap_or_default())
+                .collect::<Vec<u64>>()
+        };
+
+        // Keep both servers of the lowest tier busy so that the first turn lands on the second server
+        set_all_servers_behavior(config, &ServerBehavior::Slow {
+            tokens_per_sec: 1.0,
+            num_tokens: 100,
+        }).await?;
+        let busy_handles = occupy_servers(config, "test-model:latest", 2).await;
+        set_all_servers_behavior(config, &ServerBehavior::default()).await?;
+
+        let mut messages = vec![
+            serde_json::json!({"role": "system", "content": "You are a coding agent"}),
+            serde_json::json!({"role": "user", "content": "List the files"}),
+        ];
+        let before_first_turn = request_counts().await;
+        let first_turn: serde_json::Value = client.post(&url)
+            .json(&serde_json::json!({"model": "test-model:latest", "messages": messages, "stream": false}))
+            .send()
+            .await?
+            .json()
+            .await?;
+        let after_first_turn = request_counts().await;
+
+        for handle in busy_handles {
+            handle.abort();
+        }
+        sleep(Duration::from_millis(300)).await;
+
+        messages.push(first_turn["message"].clone());
+        messages.push(serde_json::json!({"role": "user", "content": "Now read main.rs"}));
+        let response = client.post(&url)
+            .json(&serde_json::json!({"model": "test-model:latest", "messages": messages, "stream": false}))
+            .send()
+            .await?;
+        let second_status = response.status();
+        let after_second_turn = request_counts().await;
+
+        stop_load_balancer(lb).await;
+
+        if after_first_turn[1] != before_first_turn[1] + 1 {
+            return Err(format!("First turn should have been served by the second server, request counts {:?} -> {:?}", before_first_turn, after_first_turn).into());
+        }
+        if !second_status.is_success() || after_second_turn[1] != after_first_turn[1] + 1 {
+            return Err(format!("Second turn should have returned to the second server instead of the hot one ({}), request counts {:?} -> {:?}", second_status, after_first_turn, after_second_turn).into());
+        }
+        Ok(())
+    }.await;
+
+    let error_message = match &result {
+        Ok(()) => String::new(),
+        Err(e) => e.to_string(),
+    };
+    TestResult {
+        name,
+        passed: result.is_ok(),
+        message: error_message,
+        duration_ms: start.elapsed().as_millis() as u64,
+    }
+}
+
+/// Test 43: While the only q16 server is busy, a request for a q16 model waits `--reconfigure-wait` for it
+/// before a free q8_0 server is restarted with q16- and if the q16 server frees up in time, nothing is restarted.
+async fn test_reconfigure_wait(
+    config: &TestConfig,
+    state: Arc<RwLock<SimulatorState>>,
+) -> TestResult {
+    let name = "KV cache reconfiguration waits for a busy server".to_string();
+    let start = Instant::now();
+
+    let result: Result<(), Box<dyn std::error::Error + Send + Sync>> = async {
+        reset_simulator(config).await?;
+
+        set_kv_cache_type(config, config.server_ports[0], Some("q8_0")).await?;
+        set_kv_cache_type(config, config.server_ports[1], Some("q16")).await?;
+        set_kv_cache_type(config, config.server_ports[2], None).await?;
+
+        let annotations = control_port_annotations(config);
+        let annotations: Vec<&str> = annotations.iter().map(String::as_str).collect();
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(30))
+            .build()?;
+        let chat = || {
+            let request = client.post(&format!("http://127.0.0.1:{}/api/chat", config.load_balancer_port))
+                .json(&serde_json::json!({
+                    "model": "test-model:latest",
+                    "messages": [{"role": "user", "content": "Hello"}],
+                    "stream": false
+                }));
+            async move { request.send().await?.error_for_status() }
+        };
+        let kv_cache_state = |port: u16| {
+            let state = state.clone();
+            async move {
+                let state = state.read().await;
+                state.servers.get(&port).map(|s| (s.kv_cache_type.clone(), s.kv_cache_restarting, s.request_count)).unwrap_or_default()
+            }
+        };
+
+        // The q16 server stays busy- the q8_0 server is only restarted once the request waited 2 seconds
+        let lb = start_load_balancer_with(config, &annotations, &["--poll-interval=600", "--reconfigure-wait=2"]).await?;
+        wait_for_inventory_poll().await;
+        set_server_behavior(config, config.server_ports[1], &ServerBehavior::Slow {
+            tokens_per_sec: 1.0,
+            num_tokens: 100,
+        }).await?;
+        let busy_handles = occupy_servers(config, "test-model:latest", 1).await;
+        set_server_behavior(config, config.server_ports[1], &ServerBehavior::default()).await?;
+
+        let sent_at = Instant::now();
+        let waiting = tokio::spawn(chat());
+        sleep(Duration::from_secs(1)).await;
+        let while_waiting = kv_cache_state(config.server_ports[0]).await;
+        let switched = waiting.await?;
+        let switch_duration = sent_at.elapsed();
+        let after_switch = kv_cache_state(config.server_ports[0]).await;
+
+        for handle in busy_handles {
+            handle.abort();
+        }
+        stop_load_balancer(lb).await;
+
+        // The q16 server frees up after a second, well within the wait
+        set_kv_cache_type(config, config.server_ports[0], Some("q8_0")).await?;
+        let lb = start_load_balancer_with(config, &annotations, &["--poll-interval=600", "--reconfigure-wait=5"]).await?;
+        wait_for_inventory_poll().await;
+        let before_busy = kv_cache_state(config.server_ports[1]).await;
+        set_server_behavior(config, config.server_ports[1], &ServerBehavior::Slow {
+            tokens_per_sec: 10.0,
+            num_tokens: 10,
+        }).await?;
+        let busy_handles = occupy_servers(config, "test-model:latest", 1).await;
+        set_server_behavior(config, config.server_ports[1], &ServerBehavior::default()).await?;
+        let freed = chat().await;
+        let after_freed = kv_cache_state(config.server_ports[1]).await;
+        let not_switched = kv_cache_state(config.server_ports[0]).await;
+        for handle in busy_handles {
+            handle.abort();
+        }
+        stop_load_balancer(lb).await;
+
+        if while_waiting.0.as_deref() != Some("q8_0") || while_waiting.1 {
+            return Err(format!("The q8_0 server shouldn't be restarted before the request waited 2 seconds, KV cache state after 1 second {:?}", while_waiting).into());
+        }
+        if let Err(e) = switched {
+            return Err(format!("The request should have been served once the q8_0 server was switched: {}", e).into());
+        }
+        if after_switch.0.as_deref() != Some("q16") || switch_duration < Duration::from_secs(2) {
+            return Err(format!("Expected the q8_0 server to be switched to q16 after the wait, KV cache state {:?} after {:?}", after_switch, switch_duration).into());
+        }
+        if let Err(e) = freed {
+            return Err(format!("The request should have been served by the q16 server once it was free: {}", e).into());
+        }
+        if not_switched.0.as_deref() != Some("q8_0") || after_freed.2 != before_busy.2 + 2 {
+            return Err(format!("The q16 server should have served the request once free, without restarting the q8_0 one, q8_0 server {:?}, q16 request count {} -> {}", not_switched, before_busy.2, after_freed.2).into());
+        }
+        Ok(())
+    }.await;
+
+    let error_message = match &result {
+        Ok(()) => String::new(),
+        Err(e) => e.to_string(),
+    };
+    TestResult {
+        name,
+        passed: result.is_ok(),
+        message: error_message,
+        duration_ms: start.elapsed().as_millis() as u64,
+    }
+}
+
+/// Test 44: A client's second blob follows its first one even though that server got busy in between,
+/// while another client's blob goes to a free server. A create naming blobs of both servers gets `409`,
+/// and uploading the missing blob again brings it next to the others.
+async fn test_blob_session_pinning(
+    config: &TestConfig,
+    state: Arc<RwLock<SimulatorState>>,
+) -> TestResult {
+    let name = "Blobs of one client kept together".to_string();
+    let start = Instant::now();
+
+    let result: Result<(), Box<dyn std::error::Error + Send + Sync>> = async {
+        reset_simulator(config).await?;
+        let lb = start_load_balancer_with(config, &[], &["--poll-interval=600"]).await?;
+        wait_for_inventory_poll().await;
+
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(15))
+            .build()?;
+        // Another machine, as far as the load balancer can tell
+        let other_client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(15))
+            .local_address("127.0.0.2".parse::<std::net::IpAddr>()?)
+            .build()?;
+        let base = format!("http://127.0.0.1:{}", config.load_balancer_port);
+        let [first, second, third] = ["cd34", "ef56", "0789"].map(|hex| format!("sha256:{}", hex.repeat(16)));
+        let upload = |client: &reqwest::Client, digest: &str| {
+            let request = client.post(format!("{}/api/blobs/{}", base, digest)).body("model file");
+            async move { request.send().await.map(|response| response.status()) }
+        };
+        let create = |model: &str, digests: [&str; 2]| {
+            let request = client.post(format!("{}/api/create", base))
+                .json(&serde_json::json!({
+                    "model": model,
+                    "files": {"model.gguf": digests[0], "adapter.gguf": digests[1]},
+                    "stream": false
+                }));
+            async move {
+                let response = request.send().await?;
+                let status = response.status();
+                Ok::<_, reqwest::Error>((status, response.text().await?))
+            }
+        };
+
+        let first_upload = upload(&client, &first).await?;
+        // The first server is busy by the time the second blob comes
+        set_all_servers_behavior(config, &ServerBehavior::Slow {
+            tokens_per_sec: 1.0,
+            num_tokens: 100,
+        }).await?;
+        let handles = occupy_servers(config, "test-model:latest", 1).await;
+        set_all_servers_behavior(config, &ServerBehavior::default()).await?;
+        let second_upload = upload(&client, &second).await?;
+        let other_upload = upload(&other_client, &third).await?;
+        for handle in handles {
+            handle.abort();
+        }
+        sleep(Duration::from_millis(300)).await;
+
+        let together = create("together", [&first, &second]).await?;
+        let spread = create("spread", [&first, &third]).await?;
+        let reupload = upload(&client, &third).await?;
+        let reunited = create("reunited", [&first, &third]).await?;
+
+        stop_load_balancer(lb).await;
+
+        let blobs: Vec<Vec<String>> = {
+            let state = state.read().await;
+            config.server_ports.iter()
+                .map(|port| state.servers.get(port).map(|s| s.blobs.clone()).unwrap_or_default())
+                .collect()
+        };
+        let uploads = [first_upload, second_upload, other_upload, reupload];
+        if uploads.iter().any(|status| *status != reqwest::StatusCode::CREATED) {
+            return Err(format!("Expected every upload to get 201, got {:?}", uploads).into());
+        }
+        if blobs[0] != [first.clone(), second.clone(), third.clone()] || blobs[1] != [third.clone()] || !blobs[2].is_empty() {
+            return Err(format!("Expected the client's blobs on the first server and the other client's on the second, got blobs per server {:?}", blobs).into());
+        }
+        if !together.0.is_success() || together.1.contains("error") {
+            return Err(format!("Expected the create from blobs on one server to succeed, got {} {}", together.0, together.1).into());
+        }
+        if spread.0 != reqwest::StatusCode::CONFLICT || !spread.1.contains("spread over several servers") {
+            return Err(format!("Expected 409 for a create from blobs on two servers, got {} {}", spread.0, spread.1).into());
+        }
+        if !reunited.0.is_success() || reunited.1.contains("error") {
+            return Err(format!("Expected the create to succeed once the blob was uploaded again, got {} {}", reunited.0, reunited.1).into());
+        }
+        Ok(())
+    }.await;
+
+    let error_message = match &result {
+        Ok(()) => String::new(),
+        Err(e) => e.to_string(),
+    };
+    TestResult {
+        name,
+        passed: result.is_ok(),
+        message: error_message,
+        duration_ms: start.elapsed().as_millis() as u64,
+    }
+}
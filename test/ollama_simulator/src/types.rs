@@ -137,6 +137,15 @@ pub struct SimulatedServerState {
     /// KV cache: tokens currently cached (prompt + previous response)
     /// Used for prefix matching - if incoming prompt shares prefix, those tokens are "free"
     pub kv_cache_tokens: Vec<String>,
+    /// KV cache type reported by the simulated llm_server_windows `GET /health` ("q8_0" or "q16").
+    /// None simulates a server without llm_server_windows (`/health` returns 404).
+    pub kv_cache_type: Option<String>,
+    /// Set while a simulated `POST /set-kv-cache` restart is in progress (`/health` returns 503).
+    pub kv_cache_restarting: bool,
+    /// Digests of the blobs uploaded with `POST /api/blobs/:digest`
+    pub blobs: Vec<String>,
+    /// Number of `POST /api/pull` requests received
+    pub pull_count: u64,
 }
 
 impl SimulatedServerState {
@@ -150,6 +159,10 @@ impl SimulatedServerState {
             request_count: 0,
             active_requests: HashMap::new(),
             kv_cache_tokens: Vec::new(),
+            kv_cache_type: None,
+            kv_cache_restarting: false,
+            blobs: Vec::new(),
+            pull_count: 0,
         }
     }
 